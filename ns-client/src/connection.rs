@@ -1,8 +1,10 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{io::Read, sync::Arc};
 use std::{io::Write, net::TcpStream, sync::mpsc::Sender};
 
 use ns_core::errors::{Error, Result};
-use ns_core::models::packets::TcpPacket;
+use ns_core::models::{blob::PartialBlob, packets::TcpPacket};
 
 use crate::models::canvas::CanvasCommand;
 
@@ -20,11 +22,20 @@ impl TcpHandler {
         // Create a channel to send packets to the server
         let (tx, rx) = std::sync::mpsc::channel::<TcpPacket>();
 
+        // Set once the server announces it is shutting down, after which the
+        // canvas stays open but nothing is sent anymore
+        let server_closed = Arc::new(AtomicBool::new(false));
+
         // Spawn a thread to send packets to the server
         let stream_ptr = stream.clone();
+        let writer_closed = server_closed.clone();
         std::thread::spawn(move || -> Result<()> {
             loop {
                 match rx.recv() {
+                    Ok(TcpPacket::Disconnect) if writer_closed.load(Ordering::SeqCst) => {}
+                    Ok(_) if writer_closed.load(Ordering::SeqCst) => {
+                        println!("The server has shut down, the canvas is read-only");
+                    }
                     Ok(packet) => {
                         let packet_bytes = packet.to_bytes()?;
                         stream_ptr.as_ref().write_all(&packet_bytes)?;
@@ -38,8 +49,9 @@ impl TcpHandler {
         });
 
         // Spawn a thread to receive packets from the server
+        let mut downloads = HashMap::new();
         std::thread::spawn(move || loop {
-            let task = || -> Result<()> {
+            let mut task = || -> Result<()> {
                 let packet = read_packet(stream.clone())?;
 
                 match packet {
@@ -53,9 +65,7 @@ impl TcpHandler {
                     }
 
                     TcpPacket::LoadCanvas(entries) => {
-                        for entry in entries {
-                            canvas_sender.send(CanvasCommand::Draw(entry))?;
-                        }
+                        canvas_sender.send(CanvasCommand::Load(entries))?;
                     }
 
                     TcpPacket::Delete(id) => {
@@ -68,11 +78,94 @@ impl TcpHandler {
                             .unwrap();
                     }
 
+                    TcpPacket::TransformResponse(ids, transform) => {
+                        canvas_sender.send(CanvasCommand::Transform(ids, transform))?;
+                    }
+
                     TcpPacket::ClearResponse { ids_to_delete } => {
                         for id in ids_to_delete {
                             canvas_sender.send(CanvasCommand::Delete(id))?;
                         }
                     }
+
+                    TcpPacket::Error(msg) => {
+                        std::io::stdout().flush()?;
+                        println!("Error: {}", msg);
+                    }
+
+                    TcpPacket::HistoryResponse(history) => {
+                        println!(
+                            "Playing back revisions {} to {}",
+                            history.base_revision,
+                            history.latest()
+                        );
+                        canvas_sender.send(CanvasCommand::Playback(history))?;
+                    }
+
+                    TcpPacket::CanvasAtResponse {
+                        revision,
+                        entries,
+                        layers,
+                    } => {
+                        println!("Showing the canvas at revision {}", revision);
+                        canvas_sender.send(CanvasCommand::Preview {
+                            revision,
+                            entries,
+                            layers,
+                        })?;
+                    }
+
+                    TcpPacket::LiveStrokeResponse {
+                        author,
+                        points,
+                        style,
+                    } => {
+                        canvas_sender.send(CanvasCommand::LiveStroke {
+                            author,
+                            points,
+                            style,
+                        })?;
+                    }
+
+                    TcpPacket::LiveStrokeEnd(author) => {
+                        canvas_sender.send(CanvasCommand::EndLiveStroke(author))?;
+                    }
+
+                    TcpPacket::BlobChunk {
+                        id,
+                        size,
+                        offset,
+                        data,
+                    } => {
+                        let download = downloads
+                            .entry(id)
+                            .or_insert_with(|| PartialBlob::new(id, size));
+                        match download.push(offset, &data) {
+                            Ok(None) => {}
+                            Ok(Some(data)) => {
+                                downloads.remove(&id);
+                                canvas_sender.send(CanvasCommand::Blob(id, data))?;
+                            }
+                            Err(e) => {
+                                downloads.remove(&id);
+                                println!("Error: {}", e);
+                            }
+                        }
+                    }
+
+                    TcpPacket::BlobStored(id) => {
+                        println!("Uploaded image {}", id);
+                    }
+
+                    TcpPacket::LayerResponse(layers) => {
+                        canvas_sender.send(CanvasCommand::Layers(layers))?;
+                    }
+
+                    TcpPacket::ServerShutdown(reason) => {
+                        server_closed.store(true, Ordering::SeqCst);
+                        println!("Server shut down: {}", reason);
+                        canvas_sender.send(CanvasCommand::ServerShutdown(reason))?;
+                    }
                     _ => {}
                 }
 
@@ -84,6 +177,11 @@ impl TcpHandler {
                 drop(stream);
                 std::process::exit(1);
             }
+
+            // The server is gone, so there is nothing left to read
+            if server_closed.load(Ordering::SeqCst) {
+                break;
+            }
         });
 
         Ok(tx)
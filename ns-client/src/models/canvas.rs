@@ -1,32 +1,125 @@
-use std::sync::mpsc::{Receiver, Sender};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::mpsc::{Receiver, Sender},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use macroquad::{
     camera::{set_camera, Camera2D},
-    color::LIGHTGRAY,
+    color::{Color, LIGHTGRAY, RED, WHITE},
     input::{
-        is_key_down, is_mouse_button_down, is_quit_requested, mouse_delta_position, prevent_quit,
-        KeyCode,
+        clear_input_queue, get_char_pressed, is_key_down, is_key_pressed, is_mouse_button_down,
+        is_mouse_button_pressed, is_quit_requested, mouse_delta_position, mouse_position,
+        prevent_quit, KeyCode, MouseButton,
     },
-    math::{vec2, Vec2},
-    shapes::{draw_circle, draw_line, draw_rectangle},
+    math::{vec2, vec3, Mat4, Vec2},
+    shapes::{draw_circle, draw_ellipse, draw_line, draw_rectangle, draw_triangle},
     text::draw_text,
+    texture::{draw_texture_ex, DrawTextureParams, Image, Texture2D},
+    time::{get_frame_time, get_time},
     ui::{hash, root_ui, widgets::Window},
-    window::{clear_background, next_frame, screen_height, screen_width},
+    window::{clear_background, get_internal_gl, next_frame, screen_height, screen_width},
 };
+use ns_core::geometry::{distance, simplify, triangulate};
 use ns_core::models::{
-    canvas::{Canvas, CanvasElement, CanvasEntry},
+    blob::BlobId,
+    canvas::{
+        ArrowHead, BatchChange, Canvas, CanvasElement, CanvasEntry, EntryId, Layer, LayerChange,
+        LayerId, Point, Style, Transform, BASE_LAYER,
+    },
+    history::History,
     packets::TcpPacket,
 };
 
-use super::enums::{Filter, Ownership, ToolType};
+use super::{
+    enums::{Filter, ToolType},
+    playback::Playback,
+};
+
+/// How far, in canvas units, the points of a stroke may be moved when it is
+/// simplified before being sent.
+const STROKE_TOLERANCE: f32 = 1.5;
+
+/// How many times per second the points of the stroke being drawn are sent,
+/// the ones added in between being sent together.
+const LIVE_STROKES_PER_SEC: f64 = 20.;
+
+/// How many segments the outlines of circles and ellipses are drawn with.
+const ELLIPSE_SEGMENTS: usize = 48;
+
+/// How many segments each corner of the outline of a rounded rectangle is
+/// drawn with.
+const CORNER_SEGMENTS: usize = 12;
+
+/// How close, in pixels on the screen, the mouse has to be to an element or
+/// handle to pick it.
+const PICK_DISTANCE: f32 = 6.;
+
+/// How large the handles of the selected element are, in pixels on the screen.
+const HANDLE_SIZE: f32 = 10.;
+
+/// The colour selected elements are outlined in.
+const SELECTION_COLOUR: [u8; 4] = [0, 120, 215, 255];
+
+/// What dragging the mouse with the select tool does, from the point of the
+/// canvas it was pressed on to the one it is on now.
+#[derive(Debug, Clone, Copy)]
+enum SelectionDrag {
+    /// Selects every entry within the rectangle between the two points
+    Band(Point, Point),
+    /// Moves every selected entry
+    Move(Point, Point),
+    /// Resizes the selected entry by dragging a corner of its bounds, the
+    /// opposite corner staying where it is. Both are where they would be if
+    /// the entry was not rotated.
+    Resize {
+        id: EntryId,
+        anchor: Point,
+        corner: Point,
+    },
+}
+
+/// A stroke someone else is still drawing, shown until it ends.
+pub struct LiveStroke {
+    pub points: Vec<Point>,
+    pub style: Style,
+}
 
 pub struct ClientCanvas {
     pub nickname: String,
     pub canvas: Canvas,
     pub selected_tool: ToolType,
-    pub selected_colour: [u8; 4],
+    /// The style new elements are drawn with
+    pub style: Style,
+    /// The points of the stroke being drawn with the pen, if any
+    pub pending_stroke: Option<Vec<Point>>,
+    /// How many points of the pending stroke were already sent to the others
+    streamed_points: usize,
+    /// When points of the pending stroke were last sent, in seconds
+    last_streamed: f64,
+    /// Where the shape being dragged out with the mouse starts and currently
+    /// ends on the canvas, if any
+    dragged_shape: Option<(Point, Point)>,
+    /// Where the canvas was clicked with the text tool and what was typed
+    /// there since, until it is sent
+    pending_text: Option<(Point, String)>,
+    /// The entries selected with the mouse or through the prompt
+    pub selection: Vec<EntryId>,
+    /// What the mouse is dragging with the select tool, if anything
+    selection_drag: Option<SelectionDrag>,
+    /// The strokes others are drawing, by username
+    pub live_strokes: HashMap<String, LiveStroke>,
+    /// The content of the images on the canvas by blob, [None] while it is
+    /// being downloaded or if it could not be decoded
+    textures: HashMap<BlobId, Option<Texture2D>>,
     pub user_decided_to_exit: bool,
     pub show_exit_dialog: bool,
+    /// The reason given by the server when it shut down, if it did.
+    pub server_shutdown: Option<String>,
+    /// Shown instead of the live canvas while looking back at its history.
+    pub playback: Option<Playback>,
+    /// The layer new entries are drawn on
+    pub active_layer: LayerId,
     pub canvas_receiver: Receiver<CanvasCommand>,
     pub tcp_packet_sender: Sender<TcpPacket>,
 }
@@ -34,13 +127,52 @@ pub struct ClientCanvas {
 #[derive(Debug, Clone)]
 pub enum CanvasCommand {
     Draw(CanvasEntry),
-    Delete(usize),
-    Overwrite(usize, CanvasEntry),
+    /// Replaces every entry, e.g. when joining or after the server reset the canvas
+    Load(Vec<CanvasEntry>),
+    Delete(EntryId),
+    Overwrite(EntryId, CanvasEntry),
+    /// Moves, scales or rotates entries the way the server did
+    Transform(Vec<EntryId>, Transform),
     List(Filter),
     ChangeTool(ToolType),
-    ChangeColour([u8; 4]),
+    ChangeStyle(Style),
+    /// Replaces the selection, highlighting the entries
+    Select(Vec<EntryId>),
+    /// Selects every entry the filter matches
+    SelectWhere(Filter),
+    /// Asks the server to change every selected entry at once
+    ChangeSelection(BatchChange),
+    /// Asks the server to move, scale or rotate every selected entry at once
+    TransformSelection(Transform),
+    /// Replaces the selected entry with an element, or draws it when there is
+    /// not exactly one
+    DrawOverSelection(CanvasElement),
+    /// Asks the server to give an entry another style, keeping its shape
+    Restyle(EntryId, Style),
+    /// Adds points to the stroke someone else is drawing
+    LiveStroke {
+        author: String,
+        points: Vec<Point>,
+        style: Style,
+    },
+    EndLiveStroke(String),
+    /// The content of a blob, checked against its id
+    Blob(BlobId, Vec<u8>),
     ShowAll,
     ShowMine,
+    ServerShutdown(String),
+    /// Opens the playback of the history of the canvas
+    Playback(History),
+    /// Shows the canvas as it was right after a revision
+    Preview {
+        revision: u64,
+        entries: Vec<CanvasEntry>,
+        layers: Vec<Layer>,
+    },
+    /// Replaces every layer, from the bottom one to the top one
+    Layers(Vec<Layer>),
+    SelectLayer(LayerId),
+    ListLayers,
 }
 
 impl ClientCanvas {
@@ -52,10 +184,25 @@ impl ClientCanvas {
         Self {
             nickname,
             selected_tool: ToolType::Line,
-            selected_colour: [0, 0, 0, 255],
+            style: Style {
+                fill_colour: Some([0, 0, 0, 255]),
+                ..Style::default()
+            },
+            pending_stroke: None,
+            streamed_points: 0,
+            last_streamed: 0.,
+            dragged_shape: None,
+            pending_text: None,
+            selection: Vec::new(),
+            selection_drag: None,
+            live_strokes: HashMap::new(),
+            textures: HashMap::new(),
             canvas: Canvas::new(),
             user_decided_to_exit: false,
             show_exit_dialog: false,
+            server_shutdown: None,
+            playback: None,
+            active_layer: BASE_LAYER,
             canvas_receiver,
             tcp_packet_sender,
         }
@@ -79,45 +226,189 @@ impl ClientCanvas {
 
             CanvasCommand::Draw(entry) => self.canvas.entries.push(entry),
 
+            CanvasCommand::Load(entries) => self.canvas.entries = entries,
+
             CanvasCommand::Overwrite(id, new_entry) => {
-                if self.canvas.update_entry(id, &new_entry.element).is_none() {
-                    println!("Entry with id {} does not exist", id);
+                // Keep whether the entry is shown, which only this client decides
+                match self.canvas.entries.iter_mut().find(|entry| entry.id == id) {
+                    Some(entry) => {
+                        *entry = CanvasEntry {
+                            shown: entry.shown,
+                            ..new_entry
+                        }
+                    }
+                    None => println!("Entry with id {} does not exist", id),
+                }
+            }
+
+            CanvasCommand::Transform(ids, transform) => self
+                .canvas
+                .entries
+                .iter_mut()
+                .filter(|entry| ids.contains(&entry.id))
+                .for_each(|entry| entry.transform(transform)),
+
+            CanvasCommand::List(filter) => self
+                .canvas
+                .entries
+                .iter()
+                .filter(|entry| filter.matches(entry, &self.nickname))
+                .for_each(|entry| println!("{}", entry)),
+
+            CanvasCommand::SelectWhere(filter) => {
+                self.selection = self
+                    .canvas
+                    .entries
+                    .iter()
+                    .filter(|entry| filter.matches(entry, &self.nickname))
+                    .map(|entry| entry.id)
+                    .collect();
+                println!("Selected {} entries", self.selection.len());
+            }
+
+            CanvasCommand::ChangeSelection(change) => {
+                if self.selection.is_empty() {
+                    println!("Nothing is selected");
+                } else {
+                    // Deleted entries are no longer selected
+                    let ids = match change {
+                        BatchChange::Delete => std::mem::take(&mut self.selection),
+                        _ => self.selection.clone(),
+                    };
+                    self.tcp_packet_sender
+                        .send(TcpPacket::BatchRequest(ids, change))
+                        .unwrap();
                 }
             }
 
-            CanvasCommand::List(filter) => {
-                self
-                    .canvas.entries
-                            .iter()
-                            .filter(|entry| match filter.tool_type {
-                                Some(ToolType::Line) => {
-                                    matches!(entry.element, CanvasElement::Line { .. })
-                                }
-                                Some(ToolType::Circle) => {
-                                    matches!(entry.element, CanvasElement::Circle { .. })
-                                }
-                                Some(ToolType::Rectangle) => {
-                                    matches!(entry.element, CanvasElement::Rect { .. })
-                                }
-                                Some(ToolType::Text) => {
-                                    matches!(entry.element, CanvasElement::Text { .. })
-                                }
-                                None => true,
-                            } && match filter.ownership {
-                                Ownership::All => true,
-                                Ownership::Mine => entry.author == self.nickname,
-                            }
-                            ).for_each(|entry| {
-                                println!("{}", entry);
-                            });
+            CanvasCommand::TransformSelection(transform) => {
+                if self.selection.is_empty() {
+                    println!("Nothing is selected");
+                } else {
+                    self.tcp_packet_sender
+                        .send(TcpPacket::TransformRequest(
+                            self.selection.clone(),
+                            transform,
+                        ))
+                        .unwrap();
+                }
+            }
+
+            CanvasCommand::DrawOverSelection(element) => {
+                // A single selected entry is replaced, as if it was edited
+                let packet = match self.selection[..] {
+                    [id] => TcpPacket::UpdateRequest(id, element),
+                    _ => TcpPacket::DrawRequest(element),
+                };
+                self.selection.clear();
+                self.tcp_packet_sender.send(packet).unwrap();
             }
 
             CanvasCommand::Delete(id) => self.canvas.delete_entry(id),
 
             CanvasCommand::ChangeTool(tool) => self.selected_tool = tool,
 
-            CanvasCommand::ChangeColour(colour) => {
-                self.selected_colour = colour;
+            CanvasCommand::Select(ids) => self.selection = ids,
+
+            CanvasCommand::ChangeStyle(style) => self.style = style,
+
+            CanvasCommand::Restyle(id, style) => {
+                match self.canvas.entries.iter().find(|entry| entry.id == id) {
+                    Some(entry) => {
+                        let mut element = entry.element.clone();
+                        element.set_style(style);
+                        self.tcp_packet_sender
+                            .send(TcpPacket::UpdateRequest(id, element))
+                            .unwrap();
+                    }
+                    None => println!("Entry with id {} does not exist", id),
+                }
+            }
+
+            CanvasCommand::LiveStroke {
+                author,
+                points,
+                style,
+            } => {
+                let stroke = self.live_strokes.entry(author).or_insert(LiveStroke {
+                    points: Vec::new(),
+                    style,
+                });
+                stroke.points.extend(points);
+            }
+
+            CanvasCommand::EndLiveStroke(author) => {
+                self.live_strokes.remove(&author);
+            }
+
+            CanvasCommand::Blob(id, data) => {
+                let texture = match Image::from_file_with_format(&data, None) {
+                    Ok(image) => Some(Texture2D::from_image(&image)),
+                    Err(e) => {
+                        println!("Image {} could not be decoded: {}", id, e);
+                        None
+                    }
+                };
+                self.textures.insert(id, texture);
+            }
+
+            CanvasCommand::ServerShutdown(reason) => self.server_shutdown = Some(reason),
+
+            CanvasCommand::Playback(history) => self.playback = Some(Playback::new(history)),
+
+            CanvasCommand::Preview {
+                revision,
+                entries,
+                layers,
+            } => {
+                self.playback = Some(Playback::still(
+                    revision,
+                    Canvas {
+                        entries,
+                        layers,
+                        ..Canvas::default()
+                    },
+                ))
+            }
+
+            CanvasCommand::Layers(layers) => {
+                // The server draws on the layers users create right away
+                let created = layers.iter().find(|layer| {
+                    layer.owner.as_deref() == Some(self.nickname.as_str())
+                        && self.canvas.layer(layer.id).is_none()
+                });
+                if let Some(layer) = created {
+                    self.active_layer = layer.id;
+                }
+                self.canvas.layers = layers;
+
+                // The layer drawn on may be gone, e.g. after the room was reverted
+                if self.canvas.layer(self.active_layer).is_none() {
+                    self.active_layer = BASE_LAYER;
+                }
+            }
+
+            CanvasCommand::SelectLayer(id) => self.active_layer = id,
+
+            CanvasCommand::ListLayers => {
+                for layer in self.canvas.layers.iter().rev() {
+                    println!(
+                        "{}[{}] {}{}{}{}",
+                        if layer.id == self.active_layer {
+                            "* "
+                        } else {
+                            ""
+                        },
+                        layer.id,
+                        layer.name,
+                        match &layer.owner {
+                            Some(owner) => format!(" by [{}]", owner),
+                            None => String::new(),
+                        },
+                        if layer.hidden { ", hidden" } else { "" },
+                        if layer.locked { ", locked" } else { "" },
+                    );
+                }
             }
         }
     }
@@ -125,48 +416,166 @@ impl ClientCanvas {
     /// This function should only be called in the same thread where the canvas
     /// provided by [`macroquad`] is being drawn.
     fn draw_action(&self, entry: &CanvasEntry) {
-        match &entry.element {
-            CanvasElement::Line {
+        draw_rotated(entry, || self.draw_element(&entry.element));
+    }
+
+    /// This function should only be called in the same thread where the canvas
+    /// provided by [`macroquad`] is being drawn.
+    fn draw_element(&self, element: &CanvasElement) {
+        let style = element.style();
+        match element {
+            CanvasElement::Line { x1, y1, x2, y2, .. } => {
+                let ends = [vec2(*x1 as f32, *y1 as f32), vec2(*x2 as f32, *y2 as f32)];
+                draw_outline(&ends, false, style);
+            }
+            CanvasElement::Circle { x, y, radius, .. } => {
+                let (x, y, radius) = (*x as f32, *y as f32, *radius as f32);
+                if let Some(fill) = style.fill_colour {
+                    draw_circle(x, y, radius, fill.into());
+                }
+                draw_outline(&ellipse_points(vec2(x, y), radius, radius), true, style);
+            }
+            CanvasElement::Rect {
+                x,
+                y,
+                width,
+                height,
+                ..
+            } => {
+                let (x, y, width, height) = (*x as f32, *y as f32, *width as f32, *height as f32);
+                if let Some(fill) = style.fill_colour {
+                    draw_rectangle(x, y, width, height, fill.into());
+                }
+                let corners = [
+                    vec2(x, y),
+                    vec2(x + width, y),
+                    vec2(x + width, y + height),
+                    vec2(x, y + height),
+                ];
+                draw_outline(&corners, true, style);
+            }
+            CanvasElement::Text { x, y, text, .. } => {
+                draw_text(
+                    text,
+                    *x as f32,
+                    *y as f32,
+                    style.font_size as f32,
+                    style.stroke_colour.into(),
+                );
+            }
+            CanvasElement::Stroke { points, .. } => {
+                draw_outline(&vertices(points), false, style);
+            }
+            CanvasElement::Ellipse {
+                x,
+                y,
+                radius_x,
+                radius_y,
+                ..
+            } => {
+                let (x, y) = (*x as f32, *y as f32);
+                let (radius_x, radius_y) = (*radius_x as f32, *radius_y as f32);
+                if let Some(fill) = style.fill_colour {
+                    draw_ellipse(x, y, radius_x, radius_y, 0., fill.into());
+                }
+                draw_outline(&ellipse_points(vec2(x, y), radius_x, radius_y), true, style);
+            }
+            CanvasElement::Polygon { points, .. } => {
+                let vertices = vertices(points);
+                if let Some(fill) = style.fill_colour {
+                    for [a, b, c] in triangulate(points) {
+                        draw_triangle(vertices[a], vertices[b], vertices[c], fill.into());
+                    }
+                }
+                draw_outline(&vertices, true, style);
+            }
+            CanvasElement::Arrow {
                 x1,
                 y1,
                 x2,
                 y2,
-                colour,
+                head,
+                ..
             } => {
-                draw_line(
-                    *x1 as f32,
-                    *y1 as f32,
-                    *x2 as f32,
-                    *y2 as f32,
-                    5.,
-                    (*colour).into(),
-                );
+                let (start, end) = (vec2(*x1 as f32, *y1 as f32), vec2(*x2 as f32, *y2 as f32));
+                draw_outline(&[start, end], false, style);
+                match head {
+                    ArrowHead::Open => draw_arrow_head(start, end, false, style),
+                    ArrowHead::Filled => draw_arrow_head(start, end, true, style),
+                    ArrowHead::Double => {
+                        draw_arrow_head(start, end, true, style);
+                        draw_arrow_head(end, start, true, style);
+                    }
+                }
             }
-            CanvasElement::Circle {
+            CanvasElement::RoundedRect {
                 x,
                 y,
+                width,
+                height,
                 radius,
-                colour,
+                ..
             } => {
-                draw_circle(*x as f32, *y as f32, *radius as f32, (*colour).into());
+                let (x, y, width, height) = (*x as f32, *y as f32, *width as f32, *height as f32);
+                let radius = (*radius as f32).min(width / 2.).min(height / 2.);
+                let centres = [
+                    (x + width - radius, y + height - radius),
+                    (x + radius, y + height - radius),
+                    (x + radius, y + radius),
+                    (x + width - radius, y + radius),
+                ];
+
+                if let Some(fill) = style.fill_colour {
+                    draw_rectangle(x + radius, y, width - 2. * radius, height, fill.into());
+                    draw_rectangle(x, y + radius, width, height - 2. * radius, fill.into());
+                    for (cx, cy) in centres {
+                        draw_circle(cx, cy, radius, fill.into());
+                    }
+                }
+
+                // Each corner is a quarter of a circle, going round clockwise
+                // from the bottom right one
+                let outline: Vec<Vec2> = centres
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(corner, (cx, cy))| {
+                        (0..=CORNER_SEGMENTS).map(move |step| {
+                            let angle = (corner as f32 + step as f32 / CORNER_SEGMENTS as f32)
+                                * std::f32::consts::FRAC_PI_2;
+                            vec2(cx + radius * angle.cos(), cy + radius * angle.sin())
+                        })
+                    })
+                    .collect();
+                draw_outline(&outline, true, style);
             }
-            CanvasElement::Rect {
+            CanvasElement::Image {
                 x,
                 y,
                 width,
                 height,
-                colour,
+                blob,
+                ..
             } => {
-                draw_rectangle(
-                    *x as f32,
-                    *y as f32,
-                    *width as f32,
-                    *height as f32,
-                    (*colour).into(),
-                );
-            }
-            CanvasElement::Text { x, y, text, colour } => {
-                draw_text(text, *x as f32, *y as f32, 50., (*colour).into());
+                let (x, y, width, height) = (*x as f32, *y as f32, *width as f32, *height as f32);
+                match self.textures.get(blob) {
+                    Some(Some(texture)) => {
+                        let params = DrawTextureParams {
+                            dest_size: Some(vec2(width, height)),
+                            ..Default::default()
+                        };
+                        draw_texture_ex(texture, x, y, WHITE, params);
+                    }
+                    // Stands in for the image until it is downloaded
+                    _ => draw_rectangle(x, y, width, height, Color::new(0.5, 0.5, 0.5, 0.3)),
+                }
+
+                let corners = [
+                    vec2(x, y),
+                    vec2(x + width, y),
+                    vec2(x + width, y + height),
+                    vec2(x, y + height),
+                ];
+                draw_outline(&corners, true, style);
             }
         }
     }
@@ -190,6 +599,13 @@ impl ClientCanvas {
                 ToolType::Circle => "circle",
                 ToolType::Rectangle => "rect",
                 ToolType::Text => "Aa",
+                ToolType::Pen => "pen",
+                ToolType::Ellipse => "ellipse",
+                ToolType::Polygon => "poly",
+                ToolType::Arrow => "arrow",
+                ToolType::RoundedRect => "rrect",
+                ToolType::Image => "img",
+                ToolType::Select => "select",
             };
 
             // Draw the tool icon
@@ -198,23 +614,99 @@ impl ClientCanvas {
                 20.,
                 screen_height() - 20.,
                 30.,
-                self.selected_colour.into(),
+                self.style.stroke_colour.into(),
             );
 
-            set_camera(&Camera2D {
+            if let Some(reason) = &self.server_shutdown {
+                draw_text(
+                    &format!("Server shut down: {} (read-only)", reason),
+                    20.,
+                    40.,
+                    30.,
+                    RED,
+                );
+            }
+
+            let camera = Camera2D {
                 zoom: Vec2::new(zoom_x, screen_width() / screen_height() * zoom_y),
                 offset: Vec2::new(x_off, y_off),
                 ..Default::default()
-            });
+            };
+            set_camera(&camera);
 
-            // Draw all entries
-            self.canvas
-                .entries
-                .iter()
-                .filter(|entry| entry.shown)
-                .for_each(|entry| self.draw_action(entry));
+            // Draw all entries, or the ones of the revision being played back
+            if let Some(playback) = self.playback.as_mut() {
+                playback.advance(get_frame_time());
+            }
+            self.request_images();
+            match &self.playback {
+                Some(playback) => playback
+                    .frame
+                    .stacked()
+                    .into_iter()
+                    .filter(|entry| {
+                        !playback
+                            .frame
+                            .layer_of(entry)
+                            .is_some_and(|layer| layer.hidden)
+                    })
+                    .for_each(|entry| self.draw_action(entry)),
+                None => self
+                    .visible_entries()
+                    .into_iter()
+                    .for_each(|entry| self.draw_action(entry)),
+            }
+
+            if self.playback.is_none() {
+                self.selection
+                    .retain(|id| self.canvas.get_entry(*id).is_some());
+                self.draw_selection(&camera);
+            }
+
+            // Strokes still being drawn are see-through until they are done
+            if self.playback.is_none() {
+                for stroke in self.live_strokes.values() {
+                    let [r, g, b, a] = stroke.style.stroke_colour;
+                    let style = Style {
+                        stroke_colour: [r, g, b, a / 2],
+                        ..stroke.style.clone()
+                    };
+                    draw_outline(&vertices(&stroke.points), false, &style);
+                }
+            }
+
+            if let Some(points) = &self.pending_stroke {
+                draw_outline(&vertices(points), false, &self.style);
+            }
+
+            if let Some((start, end)) = self.dragged_shape {
+                if let Some(element) = dragged_element(self.selected_tool, start, end, &self.style)
+                {
+                    self.draw_element(&element);
+                }
+            }
+
+            if let Some(((x, y), text)) = &self.pending_text {
+                let caret = if get_time().fract() < 0.5 { "|" } else { "" };
+                draw_text(
+                    &format!("{}{}", text, caret),
+                    *x as f32,
+                    *y as f32,
+                    self.style.font_size as f32,
+                    self.style.stroke_colour.into(),
+                );
+            }
 
-            if is_quit_requested() || is_key_down(KeyCode::Escape) {
+            if self.playback.is_some() {
+                self.draw_playback_window();
+            } else {
+                self.draw_layers_window();
+            }
+
+            // Escape stops typing text before it closes the window
+            if is_quit_requested()
+                || (is_key_pressed(KeyCode::Escape) && self.pending_text.is_none())
+            {
                 self.show_exit_dialog = true;
             }
 
@@ -223,7 +715,15 @@ impl ClientCanvas {
             }
 
             let delta = mouse_delta_position();
-            if is_mouse_button_down(macroquad::input::MouseButton::Left) {
+            if self.draw_with_pen(&camera)
+                || self.drag_shape(&camera)
+                || self.type_text(&camera)
+                || self.select_with_mouse(&camera)
+            {
+                // The mouse is busy drawing rather than panning
+            } else if is_mouse_button_down(MouseButton::Left)
+                || is_mouse_button_down(MouseButton::Right)
+            {
                 x_off -= delta.x;
                 y_off += delta.y;
             }
@@ -254,6 +754,552 @@ impl ClientCanvas {
         }
     }
 
+    /// Asks the server for the content of the images about to be drawn that
+    /// were not downloaded yet.
+    fn request_images(&mut self) {
+        let entries = match &self.playback {
+            Some(playback) => &playback.frame.entries,
+            None => &self.canvas.entries,
+        };
+
+        for entry in entries {
+            if let CanvasElement::Image { blob, .. } = entry.element {
+                if let Entry::Vacant(texture) = self.textures.entry(blob) {
+                    texture.insert(None);
+                    self.tcp_packet_sender
+                        .send(TcpPacket::BlobRequest(blob))
+                        .unwrap();
+                }
+            }
+        }
+    }
+
+    /// Records a stroke while the left mouse button is held down with the pen,
+    /// streaming its points to the others as it goes and sending it simplified
+    /// once the button is released. Returns whether the mouse was used to draw.
+    fn draw_with_pen(&mut self, camera: &Camera2D) -> bool {
+        if !matches!(self.selected_tool, ToolType::Pen) || self.playback.is_some() {
+            self.pending_stroke = None;
+            return false;
+        }
+
+        let mouse = Vec2::from(mouse_position());
+        if is_mouse_button_pressed(MouseButton::Left) && !root_ui().is_mouse_over(mouse) {
+            self.pending_stroke = Some(Vec::new());
+            self.streamed_points = 0;
+        }
+
+        let Some(points) = self.pending_stroke.as_mut() else {
+            return false;
+        };
+
+        let point = canvas_point(camera, mouse);
+        if points.last() != Some(&point) {
+            points.push(point);
+        }
+
+        let now = get_time();
+        if points.len() > self.streamed_points
+            && now - self.last_streamed >= 1. / LIVE_STROKES_PER_SEC
+        {
+            self.tcp_packet_sender
+                .send(TcpPacket::LiveStrokeRequest {
+                    points: points[self.streamed_points..].to_vec(),
+                    style: self.style.clone(),
+                })
+                .unwrap();
+            self.streamed_points = points.len();
+            self.last_streamed = now;
+        }
+
+        if !is_mouse_button_down(MouseButton::Left) {
+            let element = CanvasElement::Stroke {
+                points: simplify(points, STROKE_TOLERANCE),
+                style: self.style.clone(),
+            };
+            self.tcp_packet_sender
+                .send(TcpPacket::DrawRequest(element))
+                .unwrap();
+            self.pending_stroke = None;
+        }
+
+        true
+    }
+
+    /// The entries drawn on the live canvas, from the bottom one to the top one.
+    fn visible_entries(&self) -> Vec<&CanvasEntry> {
+        self.canvas
+            .stacked()
+            .into_iter()
+            .filter(|entry| {
+                entry.shown
+                    && !self
+                        .canvas
+                        .layer_of(entry)
+                        .is_some_and(|layer| layer.hidden)
+            })
+            .collect()
+    }
+
+    /// Selects entries by clicking them or dragging a band around them with
+    /// the select tool, holding shift to add to the selection, and moves or
+    /// resizes them by dragging them or their handles. Edits are sent once
+    /// the button is released. Returns whether the mouse was used.
+    fn select_with_mouse(&mut self, camera: &Camera2D) -> bool {
+        if !matches!(self.selected_tool, ToolType::Select) || self.playback.is_some() {
+            self.selection_drag = None;
+            return false;
+        }
+
+        if is_key_pressed(KeyCode::Delete) && !self.selection.is_empty() {
+            self.tcp_packet_sender
+                .send(TcpPacket::BatchRequest(
+                    std::mem::take(&mut self.selection),
+                    BatchChange::Delete,
+                ))
+                .unwrap();
+        }
+
+        let mouse = Vec2::from(mouse_position());
+        let point = canvas_point(camera, mouse);
+        if is_mouse_button_pressed(MouseButton::Left) && !root_ui().is_mouse_over(mouse) {
+            let reach = canvas_length(camera, PICK_DISTANCE.max(HANDLE_SIZE / 2.));
+            self.selection_drag = Some(self.start_selection_drag(point, reach));
+        }
+
+        let Some(drag) = self.selection_drag else {
+            return false;
+        };
+
+        let drag = match drag {
+            SelectionDrag::Band(start, _) => SelectionDrag::Band(start, point),
+            SelectionDrag::Move(start, _) => SelectionDrag::Move(start, point),
+            SelectionDrag::Resize { id, anchor, .. } => {
+                let Some(entry) = self.canvas.get_entry(id) else {
+                    self.selection_drag = None;
+                    return true;
+                };
+                let (x, y) = entry.unrotate((point.0 as f32, point.1 as f32));
+                SelectionDrag::Resize {
+                    id,
+                    anchor,
+                    corner: (
+                        x.round().clamp(0., u16::MAX as f32) as u16,
+                        y.round().clamp(0., u16::MAX as f32) as u16,
+                    ),
+                }
+            }
+        };
+        self.selection_drag = Some(drag);
+
+        if !is_mouse_button_down(MouseButton::Left) {
+            self.selection_drag = None;
+            self.finish_selection_drag(drag);
+        }
+
+        true
+    }
+
+    /// Decides what pressing the mouse at `point` with the select tool starts,
+    /// updating the selection when an entry is clicked.
+    fn start_selection_drag(&mut self, point: Point, reach: f32) -> SelectionDrag {
+        let adding = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+
+        // The corners of a single selected entry resize it
+        if let [id] = self.selection[..] {
+            if let Some(entry) = self.canvas.get_entry(id) {
+                let (x, y) = entry.unrotate((point.0 as f32, point.1 as f32));
+                let corners = corners(entry.element.bounds());
+                let grabbed = corners.iter().position(|corner| {
+                    (corner.0 as f32 - x).abs() <= reach && (corner.1 as f32 - y).abs() <= reach
+                });
+                if let Some(index) = grabbed {
+                    return SelectionDrag::Resize {
+                        id,
+                        anchor: corners[(index + 2) % 4],
+                        corner: corners[index],
+                    };
+                }
+            }
+        }
+
+        let clicked = self
+            .visible_entries()
+            .into_iter()
+            .rev()
+            .find(|entry| entry.contains(point, reach))
+            .map(|entry| entry.id);
+
+        match clicked {
+            Some(id) if adding => {
+                match self.selection.iter().position(|selected| *selected == id) {
+                    Some(index) => {
+                        self.selection.remove(index);
+                    }
+                    None => self.selection.push(id),
+                }
+                SelectionDrag::Move(point, point)
+            }
+            Some(id) => {
+                if !self.selection.contains(&id) {
+                    self.selection = vec![id];
+                }
+                SelectionDrag::Move(point, point)
+            }
+            None => {
+                if !adding {
+                    self.selection.clear();
+                }
+                SelectionDrag::Band(point, point)
+            }
+        }
+    }
+
+    /// Selects the entries within the band, or sends the entries moved or
+    /// resized to the server.
+    fn finish_selection_drag(&mut self, drag: SelectionDrag) {
+        if let SelectionDrag::Band(start, end) = drag {
+            let (min, max) = band(start, end);
+            let within: Vec<EntryId> = self
+                .visible_entries()
+                .into_iter()
+                .filter(|entry| {
+                    let (entry_min, entry_max) = entry.element.bounds();
+                    entry_min.0 >= min.0
+                        && entry_min.1 >= min.1
+                        && entry_max.0 <= max.0
+                        && entry_max.1 <= max.1
+                })
+                .map(|entry| entry.id)
+                .filter(|id| !self.selection.contains(id))
+                .collect();
+            self.selection.extend(within);
+            return;
+        }
+
+        // Moving the selection is sent as a single edit, undone as one
+        let packets = match drag {
+            SelectionDrag::Move(start, end) if start != end => {
+                vec![TcpPacket::TransformRequest(
+                    self.selection.clone(),
+                    Transform::Translate(
+                        end.0 as i32 - start.0 as i32,
+                        end.1 as i32 - start.1 as i32,
+                    ),
+                )]
+            }
+            _ => self
+                .dragged_entries(drag)
+                .into_iter()
+                .map(|entry| TcpPacket::UpdateRequest(entry.id, entry.element))
+                .collect(),
+        };
+        for packet in packets {
+            self.tcp_packet_sender.send(packet).unwrap();
+        }
+    }
+
+    /// The selected entries as moving or resizing them by the drag leaves
+    /// them, leaving out the ones it does not change.
+    fn dragged_entries(&self, drag: SelectionDrag) -> Vec<CanvasEntry> {
+        self.selection
+            .iter()
+            .filter_map(|id| self.canvas.get_entry(*id))
+            .filter_map(|entry| {
+                let mut dragged = entry.clone();
+                match drag {
+                    SelectionDrag::Band(_, _) => return None,
+                    SelectionDrag::Move(start, end) => dragged
+                        .element
+                        .translate(end.0 as i32 - start.0 as i32, end.1 as i32 - start.1 as i32),
+                    SelectionDrag::Resize { id, anchor, corner } if id == entry.id => {
+                        let (min, max) = band(anchor, corner);
+                        dragged.element.fit(min, max);
+                    }
+                    SelectionDrag::Resize { .. } => return None,
+                }
+                (dragged != *entry).then_some(dragged)
+            })
+            .collect()
+    }
+
+    /// Outlines the selected entries, with handles on the corners of a single
+    /// one, and shows what dragging with the select tool does.
+    fn draw_selection(&self, camera: &Camera2D) {
+        let outline = Style {
+            stroke_width: canvas_length(camera, 2.).ceil() as u16,
+            stroke_colour: SELECTION_COLOUR,
+            fill_colour: None,
+            ..Style::default()
+        };
+        let handle = canvas_length(camera, HANDLE_SIZE);
+
+        // Moved and resized entries are shown where they are dragged to
+        let dragged = self
+            .selection_drag
+            .map_or_else(Vec::new, |drag| self.dragged_entries(drag));
+        for entry in dragged.iter() {
+            self.draw_action(entry);
+        }
+
+        for id in self.selection.iter() {
+            let Some(entry) = dragged
+                .iter()
+                .find(|entry| entry.id == *id)
+                .or_else(|| self.canvas.get_entry(*id))
+            else {
+                continue;
+            };
+
+            draw_rotated(entry, || {
+                let corners = corners(entry.element.bounds());
+                draw_outline(&vertices(&corners), true, &outline);
+                if self.selection.len() == 1 {
+                    for (x, y) in corners {
+                        draw_rectangle(
+                            x as f32 - handle / 2.,
+                            y as f32 - handle / 2.,
+                            handle,
+                            handle,
+                            SELECTION_COLOUR.into(),
+                        );
+                    }
+                }
+            });
+        }
+
+        if let Some(SelectionDrag::Band(start, end)) = self.selection_drag {
+            let dashed = Style {
+                dash: vec![4, 4],
+                ..outline
+            };
+            draw_outline(&vertices(&corners(band(start, end))), true, &dashed);
+        }
+    }
+
+    /// Drags out a line or shape while the left mouse button is held down
+    /// with a tool drawn that way, previewed as it goes and sent once the
+    /// button is released. Returns whether the mouse was used to draw.
+    fn drag_shape(&mut self, camera: &Camera2D) -> bool {
+        let dragged = matches!(
+            self.selected_tool,
+            ToolType::Line
+                | ToolType::Arrow
+                | ToolType::Rectangle
+                | ToolType::RoundedRect
+                | ToolType::Circle
+                | ToolType::Ellipse
+        );
+        if !dragged || self.playback.is_some() {
+            self.dragged_shape = None;
+            return false;
+        }
+
+        let mouse = Vec2::from(mouse_position());
+        let point = canvas_point(camera, mouse);
+        if is_mouse_button_pressed(MouseButton::Left) && !root_ui().is_mouse_over(mouse) {
+            self.dragged_shape = Some((point, point));
+        }
+
+        let Some((start, end)) = self.dragged_shape.as_mut() else {
+            return false;
+        };
+        *end = point;
+
+        if !is_mouse_button_down(MouseButton::Left) {
+            // Clicking without dragging draws nothing
+            if start != end {
+                if let Some(element) =
+                    dragged_element(self.selected_tool, *start, *end, &self.style)
+                {
+                    self.tcp_packet_sender
+                        .send(TcpPacket::DrawRequest(element))
+                        .unwrap();
+                }
+            }
+            self.dragged_shape = None;
+        }
+
+        true
+    }
+
+    /// Starts typing text where the canvas is clicked with the text tool,
+    /// shown in place with a caret. The text is sent when Enter is pressed or
+    /// the canvas is clicked again, and dropped when Escape is pressed.
+    /// Returns whether the mouse was used to place text.
+    fn type_text(&mut self, camera: &Camera2D) -> bool {
+        if !matches!(self.selected_tool, ToolType::Text) || self.playback.is_some() {
+            self.pending_text = None;
+            return false;
+        }
+
+        let mouse = Vec2::from(mouse_position());
+        let clicked = is_mouse_button_pressed(MouseButton::Left) && !root_ui().is_mouse_over(mouse);
+        if clicked {
+            self.send_pending_text();
+            self.pending_text = Some((canvas_point(camera, mouse), String::new()));
+            // Keys typed before clicking are not part of the text
+            clear_input_queue();
+        }
+
+        let Some((_, text)) = self.pending_text.as_mut() else {
+            return false;
+        };
+
+        // The queue hands out the last character typed first
+        let mut typed = Vec::new();
+        while let Some(character) = get_char_pressed() {
+            typed.push(character);
+        }
+        text.extend(typed.into_iter().rev().filter(|c| !c.is_control()));
+        if is_key_pressed(KeyCode::Backspace) {
+            text.pop();
+        }
+
+        if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::KpEnter) {
+            self.send_pending_text();
+        } else if is_key_pressed(KeyCode::Escape) {
+            self.pending_text = None;
+        }
+
+        clicked
+    }
+
+    /// Sends the text being typed, unless nothing was.
+    fn send_pending_text(&mut self) {
+        let Some(((x, y), text)) = self.pending_text.take() else {
+            return;
+        };
+        if text.trim().is_empty() {
+            return;
+        }
+
+        let element = CanvasElement::Text {
+            x,
+            y,
+            text,
+            style: self.style.clone(),
+        };
+        self.tcp_packet_sender
+            .send(TcpPacket::DrawRequest(element))
+            .unwrap();
+    }
+
+    fn draw_playback_window(&mut self) {
+        let Some(playback) = self.playback.as_mut() else {
+            return;
+        };
+
+        let window_size = vec2(420., 90.);
+        let window_position = vec2(
+            screen_width() / 2. - window_size.x / 2.,
+            screen_height() - window_size.y - 10.,
+        );
+        let mut close = false;
+
+        Window::new(hash!(), window_position, window_size)
+            .label("Playback")
+            .ui(&mut root_ui(), |ui| {
+                let latest = playback.history.latest();
+                let description = match playback.revision() {
+                    Some(revision) => format!(
+                        "Revision {} of {} by {}, {}s ago",
+                        revision.number,
+                        latest,
+                        revision.author,
+                        seconds_since(revision.timestamp)
+                    ),
+                    None => format!("Revision {} of {}", playback.position, latest),
+                };
+                ui.label(None, &description);
+
+                if playback.is_scrubbable() {
+                    let mut position = playback.position as f32;
+                    ui.slider(
+                        hash!(),
+                        "",
+                        playback.history.base_revision as f32..latest as f32,
+                        &mut position,
+                    );
+
+                    // Dragging the scrubber takes over from the playback
+                    let position = position.round() as u64;
+                    if position != playback.position {
+                        playback.playing = false;
+                        playback.seek(position);
+                    }
+
+                    if ui.button(None, if playback.playing { "Pause" } else { "Play" }) {
+                        playback.toggle();
+                    }
+                    ui.same_line(60.);
+                }
+
+                if ui.button(None, "Close") {
+                    close = true;
+                }
+            });
+
+        if close {
+            self.playback = None;
+        }
+    }
+
+    /// Lists the layers from the top one to the bottom one, with buttons to
+    /// draw on them, hide, lock or move them.
+    fn draw_layers_window(&mut self) {
+        let window_size = vec2(300., 200.);
+        let window_position = vec2(screen_width() - window_size.x - 10., 10.);
+        let mut packets = Vec::new();
+        let mut selected = None;
+
+        Window::new(hash!(), window_position, window_size)
+            .label("Layers")
+            .ui(&mut root_ui(), |ui| {
+                for (position, layer) in self.canvas.layers.iter().enumerate().rev() {
+                    let marker = if layer.id == self.active_layer {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    ui.label(None, &format!("{} [{}] {}", marker, layer.id, layer.name));
+
+                    if ui.button(None, "Use") {
+                        selected = Some(layer.id);
+                    }
+                    ui.same_line(0.);
+                    if ui.button(None, if layer.hidden { "Show" } else { "Hide" }) {
+                        packets.push(LayerChange::SetHidden(layer.id, !layer.hidden));
+                    }
+                    ui.same_line(0.);
+                    if ui.button(None, if layer.locked { "Unlock" } else { "Lock" }) {
+                        packets.push(LayerChange::SetLocked(layer.id, !layer.locked));
+                    }
+                    ui.same_line(0.);
+                    if ui.button(None, "Up") {
+                        packets.push(LayerChange::Move(layer.id, position + 1));
+                    }
+                    ui.same_line(0.);
+                    if ui.button(None, "Down") {
+                        packets.push(LayerChange::Move(layer.id, position.saturating_sub(1)));
+                    }
+                    ui.separator();
+                }
+            });
+
+        if let Some(id) = selected {
+            self.active_layer = id;
+            self.tcp_packet_sender
+                .send(TcpPacket::SelectLayer(id))
+                .unwrap();
+        }
+        for change in packets {
+            self.tcp_packet_sender
+                .send(TcpPacket::LayerRequest(change))
+                .unwrap();
+        }
+    }
+
     fn draw_exit_dialog(&mut self) {
         let dialog_size = vec2(200., 70.);
         let screen_size = vec2(screen_width(), screen_height());
@@ -272,3 +1318,233 @@ impl ClientCanvas {
         });
     }
 }
+
+/// The point of the canvas under a position on the screen, through the zoom
+/// and offset of the camera.
+fn canvas_point(camera: &Camera2D, position: Vec2) -> Point {
+    let world = camera.screen_to_world(position);
+    (
+        world.x.round().clamp(0., u16::MAX as f32) as u16,
+        world.y.round().clamp(0., u16::MAX as f32) as u16,
+    )
+}
+
+/// How long a number of pixels on the screen is on the canvas, under the zoom
+/// of the camera.
+fn canvas_length(camera: &Camera2D, pixels: f32) -> f32 {
+    let origin = camera.screen_to_world(vec2(0., 0.));
+    camera.screen_to_world(vec2(pixels, 0.)).distance(origin)
+}
+
+/// The top left and bottom right corners of the rectangle between two points.
+fn band(start: Point, end: Point) -> (Point, Point) {
+    (
+        (start.0.min(end.0), start.1.min(end.1)),
+        (start.0.max(end.0), start.1.max(end.1)),
+    )
+}
+
+/// The corners of a rectangle given by its top left and bottom right ones,
+/// going round clockwise from the top left one.
+fn corners((min, max): (Point, Point)) -> [Point; 4] {
+    [min, (max.0, min.1), max, (min.0, max.1)]
+}
+
+/// Draws whatever `draw` draws turned the way the entry is, about its centre.
+///
+/// Everything drawn goes through the model matrix, so shapes do not need to
+/// know about rotation. The internal context is safe to use here as this is
+/// only called while the canvas is being drawn, on the thread drawing it.
+fn draw_rotated(entry: &CanvasEntry, draw: impl FnOnce()) {
+    if entry.rotation == 0. {
+        return draw();
+    }
+
+    let (x, y) = entry.element.centre();
+    let turn = Mat4::from_translation(vec3(x, y, 0.))
+        * Mat4::from_rotation_z(entry.rotation.to_radians())
+        * Mat4::from_translation(vec3(-x, -y, 0.));
+
+    unsafe { get_internal_gl() }.quad_gl.push_model_matrix(turn);
+    draw();
+    unsafe { get_internal_gl() }.quad_gl.pop_model_matrix();
+}
+
+/// The element a tool draws when dragged from `start` to `end`, or [None]
+/// if it is not drawn by dragging. Circles and ellipses are dragged out from
+/// their centre.
+fn dragged_element(
+    tool: ToolType,
+    start: Point,
+    end: Point,
+    style: &Style,
+) -> Option<CanvasElement> {
+    let (x, y) = (start.0.min(end.0), start.1.min(end.1));
+    let (width, height) = (start.0.abs_diff(end.0), start.1.abs_diff(end.1));
+    let style = style.clone();
+
+    let element = match tool {
+        ToolType::Line => CanvasElement::Line {
+            x1: start.0,
+            y1: start.1,
+            x2: end.0,
+            y2: end.1,
+            style,
+        },
+        ToolType::Arrow => CanvasElement::Arrow {
+            x1: start.0,
+            y1: start.1,
+            x2: end.0,
+            y2: end.1,
+            head: ArrowHead::Filled,
+            style,
+        },
+        ToolType::Rectangle => CanvasElement::Rect {
+            x,
+            y,
+            width,
+            height,
+            style,
+        },
+        ToolType::RoundedRect => CanvasElement::RoundedRect {
+            x,
+            y,
+            width,
+            height,
+            radius: width.min(height) / 4,
+            style,
+        },
+        ToolType::Circle => CanvasElement::Circle {
+            x: start.0,
+            y: start.1,
+            radius: distance(start, end).round() as u16,
+            style,
+        },
+        ToolType::Ellipse => CanvasElement::Ellipse {
+            x: start.0,
+            y: start.1,
+            radius_x: width,
+            radius_y: height,
+            style,
+        },
+        ToolType::Text | ToolType::Pen | ToolType::Polygon | ToolType::Image | ToolType::Select => {
+            return None
+        }
+    };
+
+    Some(element)
+}
+
+/// The points of the canvas as positions to draw at.
+fn vertices(points: &[Point]) -> Vec<Vec2> {
+    points
+        .iter()
+        .map(|point| vec2(point.0 as f32, point.1 as f32))
+        .collect()
+}
+
+/// Points around an ellipse, to draw its outline through.
+fn ellipse_points(centre: Vec2, radius_x: f32, radius_y: f32) -> Vec<Vec2> {
+    (0..ELLIPSE_SEGMENTS)
+        .map(|step| {
+            let angle = step as f32 / ELLIPSE_SEGMENTS as f32 * std::f32::consts::TAU;
+            centre + vec2(radius_x * angle.cos(), radius_y * angle.sin())
+        })
+        .collect()
+}
+
+/// Draws a line through the points in the width, colour and dash pattern of
+/// the style, back to the first point if `closed`. Solid lines have their
+/// joints rounded, and nothing is drawn if the style has no outline.
+fn draw_outline(points: &[Vec2], closed: bool, style: &Style) {
+    if style.stroke_width == 0 || points.is_empty() {
+        return;
+    }
+
+    let width = style.stroke_width as f32;
+    let colour = style.stroke_colour.into();
+    let mut segments: Vec<(Vec2, Vec2)> = points
+        .windows(2)
+        .map(|segment| (segment[0], segment[1]))
+        .collect();
+    if closed && points.len() > 2 {
+        segments.push((points[points.len() - 1], points[0]));
+    }
+
+    if style.dash.iter().all(|&length| length == 0) {
+        for point in points {
+            draw_circle(point.x, point.y, width / 2., colour);
+        }
+        for (start, end) in segments {
+            draw_line(start.x, start.y, end.x, end.y, width, colour);
+        }
+        return;
+    }
+
+    // Patterns of an odd length are repeated, so that dashes and gaps take turns
+    let mut pattern = style.dash.clone();
+    if pattern.len() % 2 == 1 {
+        pattern.extend_from_within(..);
+    }
+
+    // The dash or gap being drawn, and how much of it is left, carried over
+    // from one segment to the next
+    let (mut index, mut left) = (0, pattern[0] as f32);
+    for (start, end) in segments {
+        let length = start.distance(end);
+        let direction = (end - start).normalize_or_zero();
+
+        let mut travelled = 0.;
+        while travelled < length {
+            let step = left.min(length - travelled);
+            if index % 2 == 0 {
+                let (from, to) = (
+                    start + direction * travelled,
+                    start + direction * (travelled + step),
+                );
+                draw_line(from.x, from.y, to.x, to.y, width, colour);
+            }
+
+            travelled += step;
+            left -= step;
+            if left <= 0. {
+                index = (index + 1) % pattern.len();
+                left = pattern[index] as f32;
+            }
+        }
+    }
+}
+
+/// Draws the head of an arrow going from `start` to `end`, at `end`, in the
+/// stroke colour of the style.
+fn draw_arrow_head(start: Vec2, end: Vec2, filled: bool, style: &Style) {
+    const LENGTH: f32 = 20.;
+    const HALF_WIDTH: f32 = 10.;
+
+    let direction = (end - start).normalize_or_zero();
+    if direction == Vec2::ZERO {
+        return;
+    }
+
+    let base = end - direction * LENGTH;
+    let side = direction.perp() * HALF_WIDTH;
+    let (left, right) = (base + side, base - side);
+    let colour = style.stroke_colour.into();
+
+    if filled {
+        draw_triangle(end, left, right, colour);
+    } else {
+        let width = style.stroke_width.max(1) as f32;
+        draw_line(end.x, end.y, left.x, left.y, width, colour);
+        draw_line(end.x, end.y, right.x, right.y, width, colour);
+    }
+}
+
+/// How many whole seconds ago a timestamp in milliseconds since the Unix epoch was.
+fn seconds_since(timestamp: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+
+    now.saturating_sub(timestamp) / 1000
+}
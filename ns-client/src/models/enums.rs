@@ -1,9 +1,19 @@
+use ns_core::models::canvas::{CanvasElement, CanvasEntry};
+
 #[derive(Debug, Copy, Clone)]
 pub enum ToolType {
     Line,
     Circle,
     Rectangle,
     Text,
+    Pen,
+    Ellipse,
+    Polygon,
+    Arrow,
+    RoundedRect,
+    Image,
+    /// Selects entries with the mouse rather than drawing
+    Select,
 }
 
 #[derive(Debug, Clone)]
@@ -17,3 +27,30 @@ pub struct Filter {
     pub tool_type: Option<ToolType>,
     pub ownership: Ownership,
 }
+
+impl Filter {
+    /// Whether an entry is of the kind asked for and drawn by the right user.
+    pub fn matches(&self, entry: &CanvasEntry, nickname: &str) -> bool {
+        let kind = match self.tool_type {
+            Some(ToolType::Line) => matches!(entry.element, CanvasElement::Line { .. }),
+            Some(ToolType::Circle) => matches!(entry.element, CanvasElement::Circle { .. }),
+            Some(ToolType::Rectangle) => matches!(entry.element, CanvasElement::Rect { .. }),
+            Some(ToolType::Text) => matches!(entry.element, CanvasElement::Text { .. }),
+            Some(ToolType::Pen) => matches!(entry.element, CanvasElement::Stroke { .. }),
+            Some(ToolType::Ellipse) => matches!(entry.element, CanvasElement::Ellipse { .. }),
+            Some(ToolType::Polygon) => matches!(entry.element, CanvasElement::Polygon { .. }),
+            Some(ToolType::Arrow) => matches!(entry.element, CanvasElement::Arrow { .. }),
+            Some(ToolType::RoundedRect) => {
+                matches!(entry.element, CanvasElement::RoundedRect { .. })
+            }
+            Some(ToolType::Image) => matches!(entry.element, CanvasElement::Image { .. }),
+            Some(ToolType::Select) => false,
+            None => true,
+        };
+
+        kind && match self.ownership {
+            Ownership::All => true,
+            Ownership::Mine => entry.author == nickname,
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use ns_core::models::{
+    canvas::Canvas,
+    history::{History, Revision},
+};
+
+/// How many revisions are played back every second.
+const REVISIONS_PER_SEC: f32 = 5.;
+
+/// A read-only view of the canvas as it was at some revision, which can be
+/// scrubbed through or played back to watch the drawing evolve.
+pub struct Playback {
+    pub history: History,
+    /// The revision currently shown
+    pub position: u64,
+    pub playing: bool,
+    /// The canvas right after `position`
+    pub frame: Canvas,
+    /// Time since the last revision was played, in seconds
+    elapsed: f32,
+}
+
+impl Playback {
+    /// Starts at the very beginning of the history, ready to be played.
+    pub fn new(history: History) -> Self {
+        let position = history.base_revision;
+        let frame = history.canvas_at(position);
+
+        Playback {
+            history,
+            position,
+            playing: false,
+            frame,
+            elapsed: 0.,
+        }
+    }
+
+    /// A single frame of the canvas, as sent by the server for one revision.
+    pub fn still(revision: u64, canvas: Canvas) -> Self {
+        let mut history = History::new(&canvas);
+        history.base_revision = revision;
+
+        Playback::new(history)
+    }
+
+    /// Whether there is more than one revision to move between.
+    pub fn is_scrubbable(&self) -> bool {
+        self.history.latest() > self.history.base_revision
+    }
+
+    /// The revision being shown, if it is one that was recorded.
+    pub fn revision(&self) -> Option<&Revision> {
+        self.history
+            .revisions
+            .iter()
+            .find(|revision| revision.number == self.position)
+    }
+
+    /// Jumps to the given revision, clamped to the ones available.
+    pub fn seek(&mut self, position: u64) {
+        let position = position.clamp(self.history.base_revision, self.history.latest());
+        if position != self.position {
+            self.position = position;
+            self.frame = self.history.canvas_at(position);
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        // Playing again from the end restarts from the beginning
+        if !self.playing && self.position == self.history.latest() {
+            self.seek(self.history.base_revision);
+        }
+        self.playing = !self.playing;
+        self.elapsed = 0.;
+    }
+
+    /// Advances the playback by `delta` seconds, stopping at the last revision.
+    pub fn advance(&mut self, delta: f32) {
+        if !self.playing {
+            return;
+        }
+
+        self.elapsed += delta;
+        while self.elapsed >= 1. / REVISIONS_PER_SEC && self.position < self.history.latest() {
+            self.elapsed -= 1. / REVISIONS_PER_SEC;
+            if let Some(revision) = self
+                .history
+                .revisions
+                .iter()
+                .find(|revision| revision.number == self.position + 1)
+            {
+                revision.operation.apply(&mut self.frame);
+            }
+            self.position += 1;
+        }
+
+        if self.position == self.history.latest() {
+            self.playing = false;
+        }
+    }
+}
@@ -1,7 +1,20 @@
-use std::{io::Write, sync::mpsc::Sender};
+use std::{
+    io::Write,
+    sync::mpsc::Sender,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use macroquad::texture::Image;
 use ns_core::errors::Result;
-use ns_core::models::{canvas::CanvasElement, packets::TcpPacket};
+use ns_core::models::{
+    blob::{chunk_packets, BlobId},
+    canvas::{
+        ArrowHead, BatchChange, CanvasElement, EntryId, GroupChange, LayerChange, LayerId, Restack,
+        Style, Transform,
+    },
+    history::HistoryPoint,
+    packets::TcpPacket,
+};
 
 use crate::models::canvas::CanvasCommand;
 use crate::models::enums::{Filter, Ownership, ToolType};
@@ -12,9 +25,11 @@ pub fn handle_prompt(
 ) -> Result<()> {
     let stdin = std::io::stdin();
 
-    let mut colour: [u8; 4] = [0, 0, 0, 255];
+    let mut style = Style {
+        fill_colour: Some([0, 0, 0, 255]),
+        ..Style::default()
+    };
     let mut tool = ToolType::Line;
-    let mut selected_id: Option<usize> = None;
 
     loop {
         print!("> ");
@@ -22,191 +37,700 @@ pub fn handle_prompt(
         let mut buffer = String::new();
         stdin.read_line(&mut buffer)?;
         let args = buffer.split_whitespace().collect::<Vec<&str>>();
-        match args.as_slice() {
-            ["draw", ..] => {
-                let element = match tool {
-                    ToolType::Line => {
-                        println!("Drawing line");
-                        let x1 = args[1].parse()?;
-                        let y1 = args[2].parse()?;
-                        let x2 = args[3].parse()?;
-                        let y2 = args[4].parse()?;
-
-                        CanvasElement::Line {
-                            x1,
-                            y1,
-                            x2,
-                            y2,
-                            colour,
-                        }
+
+        // A typo must not end the prompt, so the error is shown and the next
+        // command is read
+        if let Err(e) = run_command(&args, &mut style, &mut tool, &packet_sender, &canvas_sender) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+/// Runs a single command, changing the style and tool new elements are drawn
+/// with or sending the packets and canvas commands it stands for.
+fn run_command(
+    args: &[&str],
+    style: &mut Style,
+    tool: &mut ToolType,
+    packet_sender: &Sender<TcpPacket>,
+    canvas_sender: &Sender<CanvasCommand>,
+) -> Result<()> {
+    match args {
+        ["draw", ..] => {
+            let element = match *tool {
+                ToolType::Line => {
+                    let [x1, y1, x2, y2] = args[1..] else {
+                        eprintln!("Expected <x1> <y1> <x2> <y2>");
+                        return Ok(());
+                    };
+
+                    println!("Drawing line");
+                    CanvasElement::Line {
+                        x1: x1.parse()?,
+                        y1: y1.parse()?,
+                        x2: x2.parse()?,
+                        y2: y2.parse()?,
+                        style: style.clone(),
                     }
-                    ToolType::Circle => {
-                        println!("Drawing circle");
-                        let x = args[1].parse().unwrap();
-                        let y = args[2].parse().unwrap();
-                        let radius = args[3].parse().unwrap();
-
-                        CanvasElement::Circle {
-                            x,
-                            y,
-                            radius,
-                            colour,
-                        }
+                }
+                ToolType::Circle => {
+                    let [x, y, radius] = args[1..] else {
+                        eprintln!("Expected <x> <y> <radius>");
+                        return Ok(());
+                    };
+
+                    println!("Drawing circle");
+                    CanvasElement::Circle {
+                        x: x.parse()?,
+                        y: y.parse()?,
+                        radius: radius.parse()?,
+                        style: style.clone(),
                     }
-                    ToolType::Rectangle => {
-                        println!("Drawing rectangle");
-
-                        let x = args[1].parse().unwrap();
-                        let y = args[2].parse().unwrap();
-                        let width = args[3].parse().unwrap();
-                        let height = args[4].parse().unwrap();
-
-                        CanvasElement::Rect {
-                            x,
-                            y,
-                            width,
-                            height,
-                            colour,
+                }
+                ToolType::Rectangle => {
+                    let [x, y, width, height] = args[1..] else {
+                        eprintln!("Expected <x> <y> <width> <height>");
+                        return Ok(());
+                    };
+
+                    println!("Drawing rectangle");
+                    CanvasElement::Rect {
+                        x: x.parse()?,
+                        y: y.parse()?,
+                        width: width.parse()?,
+                        height: height.parse()?,
+                        style: style.clone(),
+                    }
+                }
+                ToolType::Text => {
+                    let (x, y, text) = match args[1..] {
+                        [x, y, ref text @ ..] if !text.is_empty() => (x, y, text.join(" ")),
+                        _ => {
+                            eprintln!("Expected <x> <y> <text>");
+                            return Ok(());
                         }
+                    };
+
+                    println!("Drawing text");
+                    CanvasElement::Text {
+                        x: x.parse()?,
+                        y: y.parse()?,
+                        text,
+                        style: style.clone(),
+                    }
+                }
+                ToolType::Pen => {
+                    if args.len() == 1 || !args[1..].len().is_multiple_of(2) {
+                        eprintln!("Expected <x1> <y1> <x2> <y2> ...");
+                        return Ok(());
                     }
-                    ToolType::Text => {
-                        println!("Drawing text");
+                    let points = args[1..]
+                        .chunks_exact(2)
+                        .map(|point| Ok((point[0].parse()?, point[1].parse()?)))
+                        .collect::<Result<Vec<_>>>()?;
 
-                        let x = args[1].parse().unwrap();
-                        let y = args[2].parse().unwrap();
-                        let text = args[3..].join(" ");
+                    println!("Drawing stroke");
+                    CanvasElement::Stroke {
+                        points,
+                        style: style.clone(),
+                    }
+                }
+                ToolType::Ellipse => {
+                    let [x, y, radius_x, radius_y] = args[1..] else {
+                        eprintln!("Expected <x> <y> <radius x> <radius y>");
+                        return Ok(());
+                    };
 
-                        CanvasElement::Text { x, y, text, colour }
+                    println!("Drawing ellipse");
+                    CanvasElement::Ellipse {
+                        x: x.parse()?,
+                        y: y.parse()?,
+                        radius_x: radius_x.parse()?,
+                        radius_y: radius_y.parse()?,
+                        style: style.clone(),
+                    }
+                }
+                ToolType::Polygon => {
+                    if !args[1..].len().is_multiple_of(2) {
+                        eprintln!("Expected pairs of coordinates");
+                        return Ok(());
+                    }
+                    let points = args[1..]
+                        .chunks_exact(2)
+                        .map(|point| Ok((point[0].parse()?, point[1].parse()?)))
+                        .collect::<Result<Vec<_>>>()?;
+                    if points.len() < 3 {
+                        eprintln!("A polygon needs at least 3 points");
+                        return Ok(());
                     }
-                };
 
-                let packet = match selected_id {
-                    Some(id) => TcpPacket::UpdateRequest(id, element),
-                    None => TcpPacket::DrawRequest(element),
-                };
-                selected_id = None;
+                    println!("Drawing polygon");
+                    CanvasElement::Polygon {
+                        points,
+                        style: style.clone(),
+                    }
+                }
+                ToolType::Arrow => {
+                    let (x1, y1, x2, y2, head) = match args[1..] {
+                        [x1, y1, x2, y2] | [x1, y1, x2, y2, "filled"] => {
+                            (x1, y1, x2, y2, ArrowHead::Filled)
+                        }
+                        [x1, y1, x2, y2, "open"] => (x1, y1, x2, y2, ArrowHead::Open),
+                        [x1, y1, x2, y2, "double"] => (x1, y1, x2, y2, ArrowHead::Double),
+                        [_, _, _, _, _] => {
+                            eprintln!("Invalid arrow head");
+                            return Ok(());
+                        }
+                        _ => {
+                            eprintln!("Expected <x1> <y1> <x2> <y2> [ open | filled | double ]");
+                            return Ok(());
+                        }
+                    };
 
-                packet_sender.send(packet).unwrap();
-            }
+                    println!("Drawing arrow");
+                    CanvasElement::Arrow {
+                        x1: x1.parse()?,
+                        y1: y1.parse()?,
+                        x2: x2.parse()?,
+                        y2: y2.parse()?,
+                        head,
+                        style: style.clone(),
+                    }
+                }
+                ToolType::RoundedRect => {
+                    let [x, y, width, height, radius] = args[1..] else {
+                        eprintln!("Expected <x> <y> <width> <height> <radius>");
+                        return Ok(());
+                    };
 
-            ["colour", r, g, b, a] => {
-                println!("Changing colour to ({}, {}, {}, {})", r, g, b, a);
-                colour = [
-                    r.parse().unwrap(),
-                    g.parse().unwrap(),
-                    b.parse().unwrap(),
-                    a.parse().unwrap(),
-                ];
+                    println!("Drawing rounded rectangle");
+                    CanvasElement::RoundedRect {
+                        x: x.parse()?,
+                        y: y.parse()?,
+                        width: width.parse()?,
+                        height: height.parse()?,
+                        radius: radius.parse()?,
+                        style: style.clone(),
+                    }
+                }
+                ToolType::Select => {
+                    eprintln!("Nothing to draw with the select tool");
+                    return Ok(());
+                }
+                ToolType::Image => {
+                    let (x, y, size, path) = match args[1..] {
+                        [x, y, path] => (x.parse()?, y.parse()?, None, path),
+                        [x, y, width, height, path] => (
+                            x.parse()?,
+                            y.parse()?,
+                            Some((width.parse()?, height.parse()?)),
+                            path,
+                        ),
+                        _ => {
+                            eprintln!("Expected <x> <y> [ <width> <height> ] <path>");
+                            return Ok(());
+                        }
+                    };
 
-                canvas_sender
-                    .send(CanvasCommand::ChangeColour(colour))
-                    .unwrap();
-            }
+                    let data = match std::fs::read(path) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            eprintln!("Could not read {}: {}", path, e);
+                            return Ok(());
+                        }
+                    };
+                    let image = match Image::from_file_with_format(&data, None) {
+                        Ok(image) => image,
+                        Err(e) => {
+                            eprintln!("{} is not a PNG or TGA image: {}", path, e);
+                            return Ok(());
+                        }
+                    };
+                    let (width, height) = size.unwrap_or((image.width, image.height));
 
-            ["tool", _] => {
-                tool = match args[1] {
-                    "line" => ToolType::Line,
-                    "circle" => ToolType::Circle,
-                    "rectangle" => ToolType::Rectangle,
-                    "text" => ToolType::Text,
-                    _ => {
-                        eprintln!("Invalid tool");
-                        continue;
+                    // The blob goes first, so the server has it by the time it draws the image
+                    let blob = BlobId::of(&data);
+                    println!("Uploading image {}", blob);
+                    for packet in chunk_packets(blob, &data) {
+                        packet_sender.send(packet).unwrap();
                     }
-                };
-                canvas_sender.send(CanvasCommand::ChangeTool(tool)).unwrap();
-            }
+                    canvas_sender.send(CanvasCommand::Blob(blob, data)).unwrap();
 
-            ["select", _] => match args[1] {
-                "none" => {
-                    println!("Deselecting element");
-                    selected_id = None;
+                    CanvasElement::Image {
+                        x,
+                        y,
+                        width,
+                        height,
+                        blob,
+                        // Images are drawn without an outline unless restyled
+                        style: Style {
+                            stroke_width: 0,
+                            ..style.clone()
+                        },
+                    }
                 }
+            };
+
+            canvas_sender
+                .send(CanvasCommand::DrawOverSelection(element))
+                .unwrap();
+        }
+
+        ["colour", r, g, b, a] => {
+            let colour = [r.parse()?, g.parse()?, b.parse()?, a.parse()?];
+            println!("Changing colour to ({}, {}, {}, {})", r, g, b, a);
+            style.stroke_colour = colour;
+            if style.fill_colour.is_some() {
+                style.fill_colour = Some(colour);
+            }
+
+            canvas_sender
+                .send(CanvasCommand::ChangeStyle(style.clone()))
+                .unwrap();
+        }
+
+        ["recolour", r, g, b, a] => {
+            let colour = [r.parse()?, g.parse()?, b.parse()?, a.parse()?];
+            canvas_sender
+                .send(CanvasCommand::ChangeSelection(BatchChange::Recolour(
+                    colour,
+                )))
+                .unwrap();
+        }
+
+        ["tool", _] => {
+            *tool = match args[1] {
+                "line" => ToolType::Line,
+                "circle" => ToolType::Circle,
+                "rectangle" => ToolType::Rectangle,
+                "text" => ToolType::Text,
+                "pen" => ToolType::Pen,
+                "ellipse" => ToolType::Ellipse,
+                "polygon" => ToolType::Polygon,
+                "arrow" => ToolType::Arrow,
+                "roundrect" => ToolType::RoundedRect,
+                "image" => ToolType::Image,
+                "select" => ToolType::Select,
                 _ => {
-                    selected_id = Some(args[1].parse()?);
+                    eprintln!("Invalid tool");
+                    return Ok(());
                 }
-            },
+            };
+            canvas_sender
+                .send(CanvasCommand::ChangeTool(*tool))
+                .unwrap();
+        }
+
+        ["width", _] => {
+            style.stroke_width = args[1].parse()?;
+            canvas_sender
+                .send(CanvasCommand::ChangeStyle(style.clone()))
+                .unwrap();
+        }
 
-            ["show", "all" | "mine"] => match args[1] {
-                "all" => {
-                    canvas_sender.send(CanvasCommand::ShowAll).unwrap();
+        ["style"] => {
+            println!(
+                "width {}, stroke {:?}, fill {}, dash {}, font {}",
+                style.stroke_width,
+                style.stroke_colour,
+                match style.fill_colour {
+                    Some(colour) => format!("{:?}", colour),
+                    None => "none".to_string(),
+                },
+                match style.dash.is_empty() {
+                    true => "none".to_string(),
+                    false => format!("{:?}", style.dash),
+                },
+                style.font_size,
+            );
+        }
+
+        ["style", "apply"] => {
+            canvas_sender
+                .send(CanvasCommand::ChangeSelection(BatchChange::Restyle(
+                    style.clone(),
+                )))
+                .unwrap();
+        }
+
+        ["style", "apply", _] => {
+            let id: EntryId = args[2].parse()?;
+            canvas_sender
+                .send(CanvasCommand::Restyle(id, style.clone()))
+                .unwrap();
+        }
+
+        ["style", "width" | "stroke" | "fill" | "dash" | "font", ..] => {
+            match args[1..] {
+                ["width", width] => style.stroke_width = width.parse()?,
+                ["stroke", r, g, b, a] => {
+                    style.stroke_colour = [r.parse()?, g.parse()?, b.parse()?, a.parse()?]
+                }
+                ["fill", "none"] => style.fill_colour = None,
+                ["fill", r, g, b, a] => {
+                    style.fill_colour = Some([r.parse()?, g.parse()?, b.parse()?, a.parse()?])
                 }
-                "mine" => {
-                    canvas_sender.send(CanvasCommand::ShowMine).unwrap();
+                ["dash", "none"] => style.dash.clear(),
+                ["dash", _, ..] => {
+                    style.dash = args[2..]
+                        .iter()
+                        .map(|length| Ok(length.parse()?))
+                        .collect::<Result<_>>()?
+                }
+                ["font", size] => style.font_size = size.parse()?,
+                _ => {
+                    eprintln!("Invalid style");
+                    return Ok(());
                 }
+            }
+
+            canvas_sender
+                .send(CanvasCommand::ChangeStyle(style.clone()))
+                .unwrap();
+        }
+
+        ["select", "none"] => {
+            println!("Deselecting elements");
+            canvas_sender
+                .send(CanvasCommand::Select(Vec::new()))
+                .unwrap();
+        }
+
+        ["select", "all" | "mine"] => {
+            let filter = Filter {
+                tool_type: None,
+                ownership: match args[1] {
+                    "all" => Ownership::All,
+                    "mine" => Ownership::Mine,
+                    _ => unreachable!(),
+                },
+            };
+
+            canvas_sender
+                .send(CanvasCommand::SelectWhere(filter))
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        ["select", "type", kind] => {
+            let Some(tool_type) = element_kind(kind) else {
+                eprintln!("Invalid element type");
+                return Ok(());
+            };
+            let filter = Filter {
+                tool_type: Some(tool_type),
+                ownership: Ownership::All,
+            };
+
+            canvas_sender
+                .send(CanvasCommand::SelectWhere(filter))
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        ["select", _, ..] => {
+            let ids = args[1..]
+                .iter()
+                .map(|id| Ok(id.parse()?))
+                .collect::<Result<Vec<EntryId>>>()?;
+            canvas_sender.send(CanvasCommand::Select(ids)).unwrap();
+        }
+
+        ["show", "all" | "mine"] => match args[1] {
+            "all" => {
+                canvas_sender.send(CanvasCommand::ShowAll).unwrap();
+            }
+            "mine" => {
+                canvas_sender.send(CanvasCommand::ShowMine).unwrap();
+            }
+            _ => unreachable!(),
+        },
+
+        ["delete"] => {
+            canvas_sender
+                .send(CanvasCommand::ChangeSelection(BatchChange::Delete))
+                .unwrap();
+        }
+
+        ["delete", _] => {
+            let id: EntryId = args[1].parse()?;
+
+            packet_sender.send(TcpPacket::Delete(id)).unwrap();
+        }
+
+        ["move", _, _, ..] | ["scale" | "rotate", _, ..] => {
+            let (transform, ids) = match args[0] {
+                "move" => (
+                    Transform::Translate(args[1].parse()?, args[2].parse()?),
+                    &args[3..],
+                ),
+                "scale" => (Transform::Scale(args[1].parse()?), &args[2..]),
+                "rotate" => (Transform::Rotate(args[1].parse()?), &args[2..]),
                 _ => unreachable!(),
-            },
+            };
+
+            // Without any id, the selected elements are transformed
+            if ids.is_empty() {
+                canvas_sender
+                    .send(CanvasCommand::TransformSelection(transform))
+                    .unwrap();
+                return Ok(());
+            }
+            let ids = ids
+                .iter()
+                .map(|id| Ok(id.parse()?))
+                .collect::<Result<Vec<EntryId>>>()?;
+
+            packet_sender
+                .send(TcpPacket::TransformRequest(ids, transform))
+                .unwrap();
+        }
 
-            ["delete", _] => {
-                let id: usize = args[1].parse()?;
+        ["raise" | "lower" | "front" | "back", ..] if args.len() <= 2 => {
+            let restack = match args[0] {
+                "raise" => Restack::Raise,
+                "lower" => Restack::Lower,
+                "front" => Restack::ToFront,
+                "back" => Restack::ToBack,
+                _ => unreachable!(),
+            };
 
-                packet_sender.send(TcpPacket::Delete(id)).unwrap();
+            // Without an id, the selected elements are restacked
+            match args.get(1) {
+                Some(id) => packet_sender
+                    .send(TcpPacket::RestackRequest(id.parse()?, restack))
+                    .unwrap(),
+                None => canvas_sender
+                    .send(CanvasCommand::ChangeSelection(BatchChange::Restack(
+                        restack,
+                    )))
+                    .unwrap(),
             }
+        }
 
-            ["list", "all" | "line" | "rect" | "circle" | "text", "all" | "mine"] => {
-                let filter = Filter {
-                    tool_type: match args[1] {
-                        "all" => None,
-                        "line" => Some(ToolType::Line),
-                        "circle" => Some(ToolType::Circle),
-                        "text" => Some(ToolType::Text),
-                        _ => unreachable!(),
-                    },
-                    ownership: match args[2] {
-                        "all" => Ownership::All,
-                        "mine" => Ownership::Mine,
+        ["layer", "list"] => {
+            canvas_sender.send(CanvasCommand::ListLayers).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        ["layer", "select", _] => {
+            let id: LayerId = args[2].parse()?;
+
+            packet_sender.send(TcpPacket::SelectLayer(id)).unwrap();
+            canvas_sender.send(CanvasCommand::SelectLayer(id)).unwrap();
+        }
+
+        ["layer", "new", _, ..] => {
+            let change = LayerChange::Create(args[2..].join(" "));
+            packet_sender.send(TcpPacket::LayerRequest(change)).unwrap();
+        }
+
+        ["layer", "rename", _, _, ..] => {
+            let change = LayerChange::Rename(args[2].parse()?, args[3..].join(" "));
+            packet_sender.send(TcpPacket::LayerRequest(change)).unwrap();
+        }
+
+        ["layer", "hide" | "show" | "lock" | "unlock", _] => {
+            let id: LayerId = args[2].parse()?;
+            let change = match args[1] {
+                "hide" => LayerChange::SetHidden(id, true),
+                "show" => LayerChange::SetHidden(id, false),
+                "lock" => LayerChange::SetLocked(id, true),
+                "unlock" => LayerChange::SetLocked(id, false),
+                _ => unreachable!(),
+            };
+
+            packet_sender.send(TcpPacket::LayerRequest(change)).unwrap();
+        }
+
+        ["layer", "move", _, _] => {
+            let change = LayerChange::Move(args[2].parse()?, args[3].parse()?);
+            packet_sender.send(TcpPacket::LayerRequest(change)).unwrap();
+        }
+
+        ["group", "new", _, _, ..] => {
+            let ids = args[3..]
+                .iter()
+                .map(|id| Ok(id.parse()?))
+                .collect::<Result<Vec<EntryId>>>()?;
+            let change = GroupChange::Create(args[2].to_string(), ids);
+            packet_sender.send(TcpPacket::GroupRequest(change)).unwrap();
+        }
+
+        ["group", "move", _, _, _] => {
+            let change = GroupChange::Move(args[2].to_string(), args[3].parse()?, args[4].parse()?);
+            packet_sender.send(TcpPacket::GroupRequest(change)).unwrap();
+        }
+
+        ["group", "style" | "delete" | "dissolve", _] => {
+            let name = args[2].to_string();
+            let change = match args[1] {
+                "style" => GroupChange::Restyle(name, style.clone()),
+                "delete" => GroupChange::Delete(name),
+                "dissolve" => GroupChange::Dissolve(name),
+                _ => unreachable!(),
+            };
+
+            packet_sender.send(TcpPacket::GroupRequest(change)).unwrap();
+        }
+
+        ["list", "all" | "line" | "rect" | "circle" | "text" | "stroke" | "ellipse" | "polygon"
+        | "arrow" | "roundrect" | "image", "all" | "mine"] => {
+            let filter = Filter {
+                tool_type: element_kind(args[1]),
+                ownership: match args[2] {
+                    "all" => Ownership::All,
+                    "mine" => Ownership::Mine,
+                    _ => unreachable!(),
+                },
+            };
+
+            canvas_sender.send(CanvasCommand::List(filter)).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        ["clear", "all" | "mine"] => {
+            packet_sender
+                .send(TcpPacket::ClearRequest {
+                    only_owned: match args[1] {
+                        "all" => false,
+                        "mine" => true,
                         _ => unreachable!(),
                     },
-                };
+                })
+                .unwrap();
+        }
 
-                canvas_sender.send(CanvasCommand::List(filter)).unwrap();
-                std::thread::sleep(std::time::Duration::from_millis(100));
-            }
+        ["undo"] => packet_sender.send(TcpPacket::Undo).unwrap(),
 
-            ["clear", "all" | "mine"] => {
-                packet_sender
-                    .send(TcpPacket::ClearRequest {
-                        only_owned: match args[1] {
-                            "all" => false,
-                            "mine" => true,
-                            _ => unreachable!(),
-                        },
-                    })
-                    .unwrap();
-            }
+        ["playback"] => packet_sender.send(TcpPacket::HistoryRequest).unwrap(),
 
-            ["undo"] => packet_sender.send(TcpPacket::Undo).unwrap(),
-
-            ["help"] => {
-                println!("Commands:");
-                println!("draw <args> - Draw an element on the canvas");
-                println!("  if tool = line:        <x1> <y1> <x2> <y2> - Draw a line");
-                println!("  if tool = circle:      <x> <y> <radius> - Draw a circle");
-                println!("  if tool = rectangle:   <x> <y> <width> <height> - Draw a rectangle");
-                println!("  if tool = text:        <x> <y> <text> - Draw text");
-                println!("colour <r> <g> <b> <a> - Change the colour of the element");
-                println!("tool < line | circle | rectangle | text > - Change the tool");
-                println!("select < id > - Select an element by id");
-                println!("show < all | mine > - Show all elements or only your own");
-                println!("delete < id > - Delete an element by id");
-                println!(
-                    "list < all | line | rect | circle | text > < all | mine > - List elements"
+        ["history", "at" | "ago", _] => {
+            let point = match args[1] {
+                "at" => HistoryPoint::Revision(args[2].parse()?),
+                "ago" => {
+                    let seconds: u64 = args[2].parse()?;
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+                    HistoryPoint::Time(now.saturating_sub(seconds * 1000))
+                }
+                _ => unreachable!(),
+            };
+
+            packet_sender
+                .send(TcpPacket::CanvasAtRequest(point))
+                .unwrap();
+        }
+
+        ["admin", "undo", _] => {
+            let count = args[2].parse()?;
+            packet_sender.send(TcpPacket::AdminUndo(count)).unwrap();
+        }
+
+        ["admin", "revert", _] => {
+            let revision = args[2].parse()?;
+            packet_sender
+                .send(TcpPacket::AdminRevert(revision))
+                .unwrap();
+        }
+
+        ["help"] => {
+            println!("Commands:");
+            println!("draw <args> - Draw an element on the canvas");
+            println!("  if tool = line:        <x1> <y1> <x2> <y2> - Draw a line");
+            println!("  if tool = circle:      <x> <y> <radius> - Draw a circle");
+            println!("  if tool = rectangle:   <x> <y> <width> <height> - Draw a rectangle");
+            println!("  if tool = text:        <x> <y> <text> - Draw text");
+            println!("  if tool = pen:         <x1> <y1> <x2> <y2> ... - Draw a stroke");
+            println!("  if tool = ellipse:     <x> <y> <radius x> <radius y> - Draw an ellipse");
+            println!("  if tool = polygon:     <x1> <y1> <x2> <y2> <x3> <y3> ... - Draw a polygon");
+            println!("  if tool = arrow:       <x1> <y1> <x2> <y2> [ open | filled | double ] - Draw an arrow");
+            println!("  if tool = roundrect:   <x> <y> <width> <height> <radius> - Draw a rounded rectangle");
+            println!("  if tool = image:       <x> <y> [ <width> <height> ] <path> - Upload and draw a PNG image");
+            println!(
+                "colour <r> <g> <b> <a> - Change the colour of the outline, and of the fill if any"
+            );
+            println!("tool < line | circle | rectangle | text | pen | ellipse | polygon | arrow | roundrect | image | select > - Change the tool");
+            println!("width < width > - Change the width of lines and outlines");
+            println!("style - Show the style new elements are drawn with");
+            println!("style width < width > - Change the width of lines and outlines, 0 for none");
+            println!(
+                "style stroke <r> <g> <b> <a> - Change the colour of lines, outlines and text"
+            );
+            println!(
+                "style fill < <r> <g> <b> <a> | none > - Change the colour shapes are filled with"
+            );
+            println!("style dash < <dash> <gap> ... | none > - Dash lines and outlines, or not");
+            println!("style font < size > - Change the size of text");
+            println!(
+                "style apply [ < id > ] - Give an element, or the selected ones, the current style"
+            );
+            println!(
+                "select < id > ... - Select elements by id, drawing over a single one replaces it"
+            );
+            println!("select < all | mine | none > - Select every element, your own or none");
+            println!("select type < line | rect | circle | text | stroke | ellipse | polygon | arrow | roundrect | image > - Select every element of a type");
+            println!("show < all | mine > - Show all elements or only your own");
+            println!("delete [ < id > ] - Delete an element by id, or the selected ones");
+            println!("recolour <r> <g> <b> <a> - Change the colour of the selected elements");
+            println!("move < dx > < dy > [ < id > ... ] - Move elements, or the selected ones");
+            println!("scale < factor > [ < id > ... ] - Scale elements about their centre, or the selected ones");
+            println!("rotate < degrees > [ < id > ... ] - Turn elements clockwise about their centre, or the selected ones");
+            println!("raise | lower [ < id > ] - Move an element, or the selected ones, one step up or down");
+            println!("front | back [ < id > ] - Move an element, or the selected ones, above or below all others");
+            println!(
+                    "list < all | line | rect | circle | text | stroke | ellipse | polygon | arrow | roundrect | image > < all | mine > - List elements"
                 );
-                println!("clear < all | mine > - Clear all elements or only your own");
-                println!("undo - Undo the last action");
-                println!("exit - Exit the program");
-            }
+            println!("clear < all | mine > - Clear all elements or only your own");
+            println!("layer list - List the layers, the one you draw on is marked with *");
+            println!("layer new < name > - Create a layer and draw on it");
+            println!("layer select < id > - Draw on a layer");
+            println!("layer rename < id > < name > - Rename a layer");
+            println!(
+                "layer < hide | show | lock | unlock > < id > - Hide, show, lock or unlock a layer"
+            );
+            println!("layer move < id > < position > - Move a layer, 0 being the bottom");
+            println!("group new < name > < id > ... - Put elements in a group");
+            println!("group move < name > < dx > < dy > - Move every element of a group");
+            println!("group style < name > - Give every element of a group the current style");
+            println!("group delete < name > - Delete every element of a group");
+            println!("group dissolve < name > - Take every element out of a group");
+            println!("undo - Undo the last action");
+            println!("playback - Play back the history of the canvas");
+            println!("history < at | ago > < revision | seconds > - Show the canvas as it was");
+            println!("admin undo < count > - Undo the last operations of everyone (admins only)");
+            println!("admin revert < revision > - Revert the whole canvas (admins only)");
+            println!("exit - Exit the program");
+        }
 
-            ["exit"] => {
-                packet_sender.send(TcpPacket::Disconnect).unwrap();
-                drop(canvas_sender);
-                drop(packet_sender);
-                std::process::exit(0);
-            }
+        ["exit"] => {
+            packet_sender.send(TcpPacket::Disconnect).unwrap();
+            std::process::exit(0);
+        }
 
-            _ => {
-                eprintln!("Invalid command");
-            }
+        _ => {
+            eprintln!("Invalid command");
         }
     }
+
+    Ok(())
+}
+
+/// The type of element a name used by `list` and `select type` stands for,
+/// if any.
+fn element_kind(name: &str) -> Option<ToolType> {
+    match name {
+        "line" => Some(ToolType::Line),
+        "rect" => Some(ToolType::Rectangle),
+        "circle" => Some(ToolType::Circle),
+        "text" => Some(ToolType::Text),
+        "stroke" => Some(ToolType::Pen),
+        "ellipse" => Some(ToolType::Ellipse),
+        "polygon" => Some(ToolType::Polygon),
+        "arrow" => Some(ToolType::Arrow),
+        "roundrect" => Some(ToolType::RoundedRect),
+        "image" => Some(ToolType::Image),
+        _ => None,
+    }
 }
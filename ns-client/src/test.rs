@@ -25,7 +25,7 @@ struct Cli {
 
 fn main() -> Result<()> {
     use models::enums::ToolType;
-    use ns_core::models::canvas::CanvasElement;
+    use ns_core::models::canvas::{ArrowHead, CanvasElement, Style};
     use rand::Rng;
 
     let args = Cli::parse();
@@ -72,27 +72,60 @@ fn main() -> Result<()> {
                 y1,
                 x2,
                 y2,
-                colour,
+                style: Style::solid(colour),
             },
             ToolType::Rectangle => CanvasElement::Rect {
                 x: x1,
                 y: y1,
                 width: x2,
                 height: y2,
-                colour,
+                style: Style::filled(colour),
             },
             ToolType::Circle => CanvasElement::Circle {
                 x: x1,
                 y: y1,
                 radius: x2,
-                colour,
+                style: Style::filled(colour),
             },
             ToolType::Text => CanvasElement::Text {
                 x: x1,
                 y: y1,
                 text: "Hello, World!".to_string(),
-                colour,
+                style: Style::solid(colour),
             },
+            ToolType::Pen => CanvasElement::Stroke {
+                points: vec![(x1, y1), (x2, y2)],
+                style: Style::solid(colour),
+            },
+            ToolType::Ellipse => CanvasElement::Ellipse {
+                x: x1,
+                y: y1,
+                radius_x: x2,
+                radius_y: y2,
+                style: Style::filled(colour),
+            },
+            ToolType::Polygon => CanvasElement::Polygon {
+                points: vec![(x1, y1), (x2, y1), (x2, y2)],
+                style: Style::filled(colour),
+            },
+            ToolType::Arrow => CanvasElement::Arrow {
+                x1,
+                y1,
+                x2,
+                y2,
+                head: ArrowHead::Filled,
+                style: Style::solid(colour),
+            },
+            ToolType::RoundedRect => CanvasElement::RoundedRect {
+                x: x1,
+                y: y1,
+                width: x2,
+                height: y2,
+                radius: 10,
+                style: Style::filled(colour),
+            },
+            // Images need their blob uploaded first
+            ToolType::Image | ToolType::Select => continue,
         };
 
         let packet = TcpPacket::DrawRequest(element);
@@ -14,7 +14,9 @@ pub enum Error {
     EncodeError(#[from] bincode::error::EncodeError),
     #[error("IntParse error: {0}")]
     IntParseError(#[from] std::num::ParseIntError),
-    #[error("Server error: {:?}", .0)]
+    #[error("FloatParse error: {0}")]
+    FloatParseError(#[from] std::num::ParseFloatError),
+    #[error("Server error: {0}")]
     ServerError(#[from] ServerError),
 }
 
@@ -23,6 +25,12 @@ pub enum ServerError {
     UsernameTaken(String),
     UserNotFound,
     LockError,
+    ServerFull,
+    PacketTooLarge(u32),
+    InvalidConfig(String),
+    SnapshotError(String),
+    InvalidBlob(String),
+    BlobStorageFull,
 }
 
 impl std::fmt::Display for ServerError {
@@ -37,15 +45,30 @@ impl std::fmt::Display for ServerError {
             ServerError::LockError => {
                 write!(f, "Failed to lock server state")
             }
+            ServerError::ServerFull => {
+                write!(f, "The server is full")
+            }
+            ServerError::PacketTooLarge(length) => {
+                write!(f, "Packet of {} bytes is too large", length)
+            }
+            ServerError::InvalidConfig(reason) => {
+                write!(f, "Invalid configuration: {}", reason)
+            }
+            ServerError::SnapshotError(reason) => {
+                write!(f, "Snapshot error: {}", reason)
+            }
+            ServerError::InvalidBlob(reason) => {
+                write!(f, "Invalid blob {}", reason)
+            }
+            ServerError::BlobStorageFull => {
+                write!(f, "There is no room left to store blobs")
+            }
         }
     }
 }
 
 impl<T> From<std::sync::mpsc::SendError<T>> for Error {
     fn from(_: std::sync::mpsc::SendError<T>) -> Self {
-        Error::IoError(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "MPSC send error",
-        ))
+        Error::IoError(std::io::Error::other("MPSC send error"))
     }
 }
@@ -0,0 +1,251 @@
+use crate::models::canvas::Point;
+
+/// How far apart two points are.
+pub fn distance(a: Point, b: Point) -> f32 {
+    let (dx, dy) = (a.0 as f32 - b.0 as f32, a.1 as f32 - b.1 as f32);
+    (dx * dx + dy * dy).sqrt()
+}
+
+/// How far a point is from the closest point of the segment between `start`
+/// and `end`.
+pub fn distance_to_segment(point: Point, start: Point, end: Point) -> f32 {
+    let (px, py) = (point.0 as f32, point.1 as f32);
+    let (sx, sy) = (start.0 as f32, start.1 as f32);
+    let (dx, dy) = (end.0 as f32 - sx, end.1 as f32 - sy);
+
+    let length_squared = dx * dx + dy * dy;
+    if length_squared == 0. {
+        return distance(point, start);
+    }
+
+    // Where the point projects onto the segment, from 0 at `start` to 1 at `end`
+    let t = (((px - sx) * dx + (py - sy) * dy) / length_squared).clamp(0., 1.);
+    let (cx, cy) = (sx + t * dx, sy + t * dy);
+
+    ((px - cx).powi(2) + (py - cy).powi(2)).sqrt()
+}
+
+/// Drops the points of a polyline that are within `tolerance` of the line
+/// through the points kept around them, following Ramer–Douglas–Peucker.
+///
+/// The first and last points are always kept, and every point dropped is
+/// within `tolerance` of the simplified polyline.
+pub fn simplify(points: &[Point], tolerance: f32) -> Vec<Point> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+
+    let last = points.len() - 1;
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[last] = true;
+
+    // The spans still to simplify, between two points that are kept
+    let mut spans = vec![(0, last)];
+    while let Some((start, end)) = spans.pop() {
+        let farthest = (start + 1..end)
+            .map(|index| {
+                let distance = distance_to_segment(points[index], points[start], points[end]);
+                (index, distance)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        if let Some((index, distance)) = farthest {
+            if distance > tolerance {
+                keep[index] = true;
+                spans.push((start, index));
+                spans.push((index, end));
+            }
+        }
+    }
+
+    points
+        .iter()
+        .zip(keep)
+        .filter_map(|(point, keep)| keep.then_some(*point))
+        .collect()
+}
+
+/// Whether a point is inside a closed polygon, counting the parts a polygon
+/// crossing itself covers twice as outside.
+pub fn point_in_polygon(point: Point, polygon: &[Point]) -> bool {
+    let (px, py) = (point.0 as f32, point.1 as f32);
+    let mut inside = false;
+
+    // Counts the edges a ray going right from the point crosses
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let (ax, ay, bx, by) = (a.0 as f32, a.1 as f32, b.0 as f32, b.1 as f32);
+        if (ay > py) != (by > py) && px < ax + (py - ay) * (bx - ax) / (by - ay) {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
+
+/// Twice the signed area of the triangle `a`, `b`, `c`, positive when its
+/// corners turn the same way as the x axis turns into the y axis.
+fn cross(a: Point, b: Point, c: Point) -> i64 {
+    let (ax, ay) = (a.0 as i64, a.1 as i64);
+    (b.0 as i64 - ax) * (c.1 as i64 - ay) - (b.1 as i64 - ay) * (c.0 as i64 - ax)
+}
+
+/// Twice the signed area of a polygon, with the same sign as [cross].
+fn signed_area(points: &[Point]) -> i64 {
+    (0..points.len())
+        .map(|i| {
+            let (a, b) = (points[i], points[(i + 1) % points.len()]);
+            a.0 as i64 * b.1 as i64 - b.0 as i64 * a.1 as i64
+        })
+        .sum()
+}
+
+/// Splits a closed polygon into triangles by clipping its ears, returning the
+/// indices of the corners of each triangle, so that it can be filled.
+///
+/// Polygons that cross themselves are filled as well as can be, fanning out
+/// whatever is left once no more ears are found.
+pub fn triangulate(points: &[Point]) -> Vec<[usize; 3]> {
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+    if signed_area(points) < 0 {
+        remaining.reverse();
+    }
+
+    let mut triangles = Vec::new();
+    while remaining.len() > 3 {
+        let count = remaining.len();
+        let corner = |i: usize| {
+            (
+                remaining[(i + count - 1) % count],
+                remaining[i],
+                remaining[(i + 1) % count],
+            )
+        };
+
+        let is_ear = |i: usize| {
+            let (a, b, c) = corner(i);
+            cross(points[a], points[b], points[c]) > 0
+                && remaining.iter().all(|&j| {
+                    j == a
+                        || j == b
+                        || j == c
+                        || cross(points[a], points[b], points[j]) < 0
+                        || cross(points[b], points[c], points[j]) < 0
+                        || cross(points[c], points[a], points[j]) < 0
+                })
+        };
+
+        if let Some(i) = (0..count).find(|&i| is_ear(i)) {
+            let (a, b, c) = corner(i);
+            triangles.push([a, b, c]);
+            remaining.remove(i);
+        } else if let Some(i) = (0..count).find(|&i| {
+            let (a, b, c) = corner(i);
+            cross(points[a], points[b], points[c]) == 0
+        }) {
+            // Corners lying on a straight line add nothing to fill
+            remaining.remove(i);
+        } else {
+            break;
+        }
+    }
+
+    for i in 1..remaining.len().saturating_sub(1) {
+        triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+    }
+
+    triangles
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn points_on_a_straight_line_are_dropped() {
+        let points: Vec<Point> = (0..=10).map(|i| (i * 10, i * 5)).collect();
+        assert_eq!(simplify(&points, 0.5), vec![(0, 0), (100, 50)]);
+    }
+
+    #[test]
+    fn corners_are_kept() {
+        let points = vec![(0, 0), (5, 1), (10, 0), (10, 5), (10, 10), (5, 10)];
+        assert_eq!(
+            simplify(&points, 2.),
+            vec![(0, 0), (10, 0), (10, 10), (5, 10)]
+        );
+    }
+
+    #[test]
+    fn closed_strokes_keep_their_shape() {
+        let points = vec![(0, 0), (10, 0), (10, 10), (0, 10), (0, 0)];
+        assert_eq!(simplify(&points, 1.), points);
+    }
+
+    /// Twice the area covered by the triangles.
+    fn triangulated_area(points: &[Point]) -> i64 {
+        triangulate(points)
+            .iter()
+            .map(|[a, b, c]| cross(points[*a], points[*b], points[*c]).abs())
+            .sum()
+    }
+
+    #[test]
+    fn convex_polygons_are_fanned_out() {
+        let square = vec![(0, 0), (10, 0), (10, 10), (0, 10)];
+        assert_eq!(triangulate(&square).len(), 2);
+        assert_eq!(triangulated_area(&square), 200);
+    }
+
+    #[test]
+    fn concave_polygons_are_covered_exactly() {
+        let l_shape = vec![(0, 0), (20, 0), (20, 10), (10, 10), (10, 20), (0, 20)];
+        assert_eq!(triangulate(&l_shape).len(), 4);
+        assert_eq!(triangulated_area(&l_shape), signed_area(&l_shape).abs());
+
+        // The same shape drawn the other way round
+        let reversed: Vec<Point> = l_shape.iter().rev().copied().collect();
+        assert_eq!(triangulated_area(&reversed), signed_area(&l_shape).abs());
+    }
+
+    #[test]
+    fn points_inside_polygons_are_found() {
+        let l_shape = vec![(0, 0), (20, 0), (20, 10), (10, 10), (10, 20), (0, 20)];
+        assert!(point_in_polygon((5, 15), &l_shape));
+        assert!(point_in_polygon((15, 5), &l_shape));
+        assert!(!point_in_polygon((15, 15), &l_shape));
+        assert!(!point_in_polygon((25, 5), &l_shape));
+        assert!(!point_in_polygon((5, 5), &[(0, 0), (10, 10)]));
+    }
+
+    #[test]
+    fn corners_on_a_straight_line_are_skipped() {
+        let points = vec![(0, 0), (5, 0), (10, 0), (10, 10), (0, 10)];
+        assert_eq!(triangulated_area(&points), 200);
+        assert!(triangulate(&[(0, 0), (10, 10)]).is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn dropped_points_stay_close_to_the_stroke(
+            points in prop::collection::vec((0u16..500, 0u16..500), 0..50),
+            tolerance in 0f32..20.,
+        ) {
+            let simplified = simplify(&points, tolerance);
+
+            prop_assert_eq!(simplified.first(), points.first());
+            prop_assert_eq!(simplified.last(), points.last());
+
+            for point in points.iter() {
+                let closest = simplified
+                    .windows(2)
+                    .map(|segment| distance_to_segment(*point, segment[0], segment[1]))
+                    .fold(f32::INFINITY, f32::min);
+                prop_assert!(simplified.len() < 2 || closest <= tolerance + 1e-3);
+            }
+        }
+    }
+}
@@ -1,2 +1,5 @@
+pub mod blob;
 pub mod canvas;
+pub mod crdt;
+pub mod history;
 pub mod packets;
@@ -0,0 +1,204 @@
+use std::fmt;
+
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    errors::{Result, ServerError},
+    models::packets::TcpPacket,
+};
+
+/// The largest chunk a blob is split into when sent, small enough to fit in
+/// a packet under the default size limit of the server.
+pub const BLOB_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How large, in bytes, the largest packet a blob is sent in can be, without
+/// its length header, so that receivers can be sure to accept it.
+pub fn max_chunk_packet_size() -> usize {
+    let largest = TcpPacket::BlobChunk {
+        id: BlobId([u8::MAX; 32]),
+        size: u64::MAX,
+        offset: u64::MAX,
+        data: vec![u8::MAX; BLOB_CHUNK_SIZE],
+    };
+
+    // The length header is not counted
+    largest
+        .to_bytes()
+        .map_or(usize::MAX, |bytes| bytes.len() - 4)
+}
+
+/// Identifies a blob by the SHA-256 digest of its content, so that the same
+/// image is only ever stored once and can be checked once received.
+///
+/// Written as the 64 hexadecimal digits of the digest.
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlobId(pub [u8; 32]);
+
+impl BlobId {
+    /// The id of a blob with the given content.
+    pub fn of(data: &[u8]) -> Self {
+        BlobId(Sha256::digest(data).into())
+    }
+}
+
+impl fmt::Display for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::Debug for BlobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BlobId({})", self)
+    }
+}
+
+/// Splits a blob into the [TcpPacket::BlobChunk]s it is sent as, in order.
+/// An empty blob is still sent as a single empty chunk.
+pub fn chunk_packets(id: BlobId, data: &[u8]) -> Vec<TcpPacket> {
+    let size = data.len() as u64;
+    if data.is_empty() {
+        return vec![TcpPacket::BlobChunk {
+            id,
+            size,
+            offset: 0,
+            data: Vec::new(),
+        }];
+    }
+
+    data.chunks(BLOB_CHUNK_SIZE)
+        .enumerate()
+        .map(|(index, chunk)| TcpPacket::BlobChunk {
+            id,
+            size,
+            offset: (index * BLOB_CHUNK_SIZE) as u64,
+            data: chunk.to_vec(),
+        })
+        .collect()
+}
+
+/// A blob being received one chunk at a time.
+#[derive(Debug)]
+pub struct PartialBlob {
+    pub id: BlobId,
+    /// How many bytes the whole blob has
+    pub size: u64,
+    data: Vec<u8>,
+}
+
+impl PartialBlob {
+    pub fn new(id: BlobId, size: u64) -> Self {
+        PartialBlob {
+            id,
+            size,
+            data: Vec::new(),
+        }
+    }
+
+    /// Appends a chunk, which must start right where the previous one ended.
+    /// Returns the whole blob once every byte arrived, as long as its content
+    /// matches its id.
+    pub fn push(&mut self, offset: u64, chunk: &[u8]) -> Result<Option<Vec<u8>>> {
+        let invalid = |reason: String| {
+            Err(ServerError::InvalidBlob(format!("{}: {}", self.id, reason)).into())
+        };
+
+        let received = self.data.len() as u64;
+        if offset != received {
+            return invalid(format!(
+                "expected the chunk at {} but got the one at {}",
+                received, offset
+            ));
+        }
+        if received + chunk.len() as u64 > self.size {
+            return invalid(format!("larger than the {} bytes announced", self.size));
+        }
+
+        self.data.extend_from_slice(chunk);
+        if (self.data.len() as u64) < self.size {
+            return Ok(None);
+        }
+
+        if BlobId::of(&self.data) != self.id {
+            return invalid("content does not match the id".to_string());
+        }
+
+        Ok(Some(std::mem::take(&mut self.data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds every chunk packet to a new partial blob.
+    fn receive(packets: Vec<TcpPacket>) -> Result<Option<Vec<u8>>> {
+        let mut blob = None;
+        let mut received = None;
+        for packet in packets {
+            let TcpPacket::BlobChunk {
+                id,
+                size,
+                offset,
+                data,
+            } = packet
+            else {
+                unreachable!();
+            };
+            received = blob
+                .get_or_insert_with(|| PartialBlob::new(id, size))
+                .push(offset, &data)?;
+        }
+
+        Ok(received)
+    }
+
+    #[test]
+    fn chunked_blobs_are_put_back_together() {
+        let data: Vec<u8> = (0..BLOB_CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let packets = chunk_packets(BlobId::of(&data), &data);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(receive(packets).unwrap(), Some(data));
+
+        assert_eq!(
+            receive(chunk_packets(BlobId::of(&[]), &[])).unwrap(),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn chunks_fit_in_the_largest_chunk_packet() {
+        let data = vec![7; BLOB_CHUNK_SIZE * 2];
+        for packet in chunk_packets(BlobId::of(&data), &data) {
+            assert!(packet.to_bytes().unwrap().len() - 4 <= max_chunk_packet_size());
+        }
+        assert!(max_chunk_packet_size() > BLOB_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn chunks_out_of_order_are_refused() {
+        let data = vec![7; BLOB_CHUNK_SIZE + 1];
+        let mut packets = chunk_packets(BlobId::of(&data), &data);
+        packets.swap(0, 1);
+        assert!(receive(packets).is_err());
+    }
+
+    #[test]
+    fn blobs_not_matching_their_id_are_refused() {
+        let data = vec![1, 2, 3];
+        assert!(receive(chunk_packets(BlobId::of(&[1, 2, 4]), &data)).is_err());
+
+        let mut blob = PartialBlob::new(BlobId::of(&data), 2);
+        assert!(blob.push(0, &data).is_err());
+    }
+
+    #[test]
+    fn ids_are_written_in_hexadecimal() {
+        assert_eq!(
+            BlobId::of(b"abc").to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
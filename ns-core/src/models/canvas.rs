@@ -1,83 +1,857 @@
+use std::{fmt, num::ParseIntError, str::FromStr};
+
 use bincode::{Decode, Encode};
 
+use super::blob::BlobId;
+use crate::geometry::{distance, distance_to_segment, point_in_polygon};
+
+/// Identifies who creates entries, e.g. the server or a client working offline.
+pub type SiteId = u32;
+
+/// The site of every entry created by the server.
+pub const SERVER_SITE: SiteId = 0;
+
+/// Identifies a layer of the canvas.
+pub type LayerId = u32;
+
+/// The layer every canvas starts with, which entries are put on by default.
+pub const BASE_LAYER: LayerId = 0;
+
+/// Identifies an entry on every canvas without any coordination: the site
+/// that created it and the value of that site's counter at the time.
+///
+/// Entries created by the server are written as just their counter, e.g. `12`,
+/// and any other as `site:counter`, e.g. `3:12`.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EntryId {
+    pub site: SiteId,
+    pub counter: u64,
+}
+
+/// A point on the canvas, as `(x, y)`.
+pub type Point = (u16, u16);
+
+/// How an element is drawn, shared by every kind of element.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Style {
+    /// The width of lines and outlines, no outline being drawn if 0
+    pub stroke_width: u16,
+    /// The colour of lines, outlines and text
+    pub stroke_colour: [u8; 4],
+    /// The colour the inside of closed shapes is filled with, if any
+    pub fill_colour: Option<[u8; 4]>,
+    /// The lengths of the dashes and of the gaps between them, in turn,
+    /// lines being solid if empty
+    pub dash: Vec<u16>,
+    pub font_size: u16,
+}
+
+impl Style {
+    /// Solid lines of the given colour, the way lines and text used to be drawn.
+    pub fn solid(colour: [u8; 4]) -> Self {
+        Style {
+            stroke_width: 5,
+            stroke_colour: colour,
+            fill_colour: None,
+            dash: Vec::new(),
+            font_size: 50,
+        }
+    }
+
+    /// Filled with the given colour without an outline, the way shapes used
+    /// to be drawn.
+    pub fn filled(colour: [u8; 4]) -> Self {
+        Style {
+            stroke_width: 0,
+            fill_colour: Some(colour),
+            ..Style::solid(colour)
+        }
+    }
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style::solid([0, 0, 0, 255])
+    }
+}
+
+/// Reads styles written when elements only had a colour, as in audit logs,
+/// the way `legacy` gives them a style.
+#[cfg(feature = "serde")]
+fn deserialize_style<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+    legacy: fn([u8; 4]) -> Style,
+) -> Result<Style, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum AnyStyle {
+        Legacy([u8; 4]),
+        Current(Style),
+    }
+
+    Ok(
+        match <AnyStyle as serde::Deserialize>::deserialize(deserializer)? {
+            AnyStyle::Legacy(colour) => legacy(colour),
+            AnyStyle::Current(style) => style,
+        },
+    )
+}
+
+/// Lines, strokes, arrows and text only having a colour were drawn with [Style::solid].
+#[cfg(feature = "serde")]
+fn deserialize_solid<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Style, D::Error> {
+    deserialize_style(deserializer, Style::solid)
+}
+
+/// Shapes only having a colour were drawn with [Style::filled].
+#[cfg(feature = "serde")]
+fn deserialize_filled<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Style, D::Error> {
+    deserialize_style(deserializer, Style::filled)
+}
+
 /// The different types of elements that can be drawn on the canvas.
-#[derive(Encode, Decode, Debug, Clone)]
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CanvasElement {
     Line {
         x1: u16,
         y1: u16,
         x2: u16,
         y2: u16,
-        colour: [u8; 4],
+        #[cfg_attr(
+            feature = "serde",
+            serde(alias = "colour", deserialize_with = "deserialize_solid")
+        )]
+        style: Style,
     },
     Circle {
         x: u16,
         y: u16,
         radius: u16,
-        colour: [u8; 4],
+        #[cfg_attr(
+            feature = "serde",
+            serde(alias = "colour", deserialize_with = "deserialize_filled")
+        )]
+        style: Style,
     },
     Rect {
         x: u16,
         y: u16,
         width: u16,
         height: u16,
-        colour: [u8; 4],
+        #[cfg_attr(
+            feature = "serde",
+            serde(alias = "colour", deserialize_with = "deserialize_filled")
+        )]
+        style: Style,
     },
     Text {
         x: u16,
         y: u16,
         text: String,
-        colour: [u8; 4],
+        #[cfg_attr(
+            feature = "serde",
+            serde(alias = "colour", deserialize_with = "deserialize_solid")
+        )]
+        style: Style,
+    },
+    /// A freehand line through every point, in the order they were drawn
+    Stroke {
+        points: Vec<Point>,
+        #[cfg_attr(
+            feature = "serde",
+            serde(alias = "colour", deserialize_with = "deserialize_solid")
+        )]
+        style: Style,
+    },
+    /// Centered on `(x, y)`, stretching `radius_x` sideways and `radius_y` up and down
+    Ellipse {
+        x: u16,
+        y: u16,
+        radius_x: u16,
+        radius_y: u16,
+        #[cfg_attr(
+            feature = "serde",
+            serde(alias = "colour", deserialize_with = "deserialize_filled")
+        )]
+        style: Style,
+    },
+    /// A shape closed by joining the last point back to the first
+    Polygon {
+        points: Vec<Point>,
+        #[cfg_attr(
+            feature = "serde",
+            serde(alias = "colour", deserialize_with = "deserialize_filled")
+        )]
+        style: Style,
+    },
+    /// A line from `(x1, y1)` pointing at `(x2, y2)`
+    Arrow {
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        head: ArrowHead,
+        #[cfg_attr(
+            feature = "serde",
+            serde(alias = "colour", deserialize_with = "deserialize_solid")
+        )]
+        style: Style,
+    },
+    RoundedRect {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        /// The radius of the corners, at most half the shortest side
+        radius: u16,
+        #[cfg_attr(
+            feature = "serde",
+            serde(alias = "colour", deserialize_with = "deserialize_filled")
+        )]
+        style: Style,
     },
+    /// An image stretched over the rectangle, its content being the blob
+    /// stored under `blob` rather than sent along with the element
+    Image {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        blob: BlobId,
+        style: Style,
+    },
+}
+
+/// How the ends of an arrow are drawn.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ArrowHead {
+    /// Two short lines at the end
+    Open,
+    /// A solid triangle at the end
+    Filled,
+    /// A solid triangle at both ends
+    Double,
+}
+
+impl CanvasElement {
+    pub fn style(&self) -> &Style {
+        match self {
+            CanvasElement::Line { style, .. }
+            | CanvasElement::Circle { style, .. }
+            | CanvasElement::Rect { style, .. }
+            | CanvasElement::Text { style, .. }
+            | CanvasElement::Stroke { style, .. }
+            | CanvasElement::Ellipse { style, .. }
+            | CanvasElement::Polygon { style, .. }
+            | CanvasElement::Arrow { style, .. }
+            | CanvasElement::RoundedRect { style, .. }
+            | CanvasElement::Image { style, .. } => style,
+        }
+    }
+
+    /// The top left and bottom right corners of the smallest rectangle the
+    /// element fits in, ignoring the width of its outline.
+    ///
+    /// Text is taken to be half as wide as it is high per character, as the
+    /// server does not know the font it is drawn with.
+    pub fn bounds(&self) -> (Point, Point) {
+        let around = |x: u16, y: u16, radius_x: u16, radius_y: u16| {
+            (
+                (x.saturating_sub(radius_x), y.saturating_sub(radius_y)),
+                (x.saturating_add(radius_x), y.saturating_add(radius_y)),
+            )
+        };
+
+        match self {
+            CanvasElement::Line { x1, y1, x2, y2, .. }
+            | CanvasElement::Arrow { x1, y1, x2, y2, .. } => {
+                ((*x1.min(x2), *y1.min(y2)), (*x1.max(x2), *y1.max(y2)))
+            }
+            CanvasElement::Circle { x, y, radius, .. } => around(*x, *y, *radius, *radius),
+            CanvasElement::Ellipse {
+                x,
+                y,
+                radius_x,
+                radius_y,
+                ..
+            } => around(*x, *y, *radius_x, *radius_y),
+            CanvasElement::Rect {
+                x,
+                y,
+                width,
+                height,
+                ..
+            }
+            | CanvasElement::RoundedRect {
+                x,
+                y,
+                width,
+                height,
+                ..
+            }
+            | CanvasElement::Image {
+                x,
+                y,
+                width,
+                height,
+                ..
+            } => (
+                (*x, *y),
+                (x.saturating_add(*width), y.saturating_add(*height)),
+            ),
+            // Drawn with its baseline at `y`
+            CanvasElement::Text { x, y, text, style } => {
+                let width = (text.chars().count() as u32 * style.font_size as u32 / 2) as u16;
+                (
+                    (*x, y.saturating_sub(style.font_size)),
+                    (x.saturating_add(width), *y),
+                )
+            }
+            CanvasElement::Stroke { points, .. } | CanvasElement::Polygon { points, .. } => {
+                let Some(&first) = points.first() else {
+                    return ((0, 0), (0, 0));
+                };
+                points.iter().fold((first, first), |(min, max), &(x, y)| {
+                    ((min.0.min(x), min.1.min(y)), (max.0.max(x), max.1.max(y)))
+                })
+            }
+        }
+    }
+
+    /// The middle of the [bounds](CanvasElement::bounds) of the element, which
+    /// it is scaled and rotated about.
+    pub fn centre(&self) -> (f32, f32) {
+        let (min, max) = self.bounds();
+        (
+            (min.0 as f32 + max.0 as f32) / 2.,
+            (min.1 as f32 + max.1 as f32) / 2.,
+        )
+    }
+
+    /// Whether the point is on the element, being within `tolerance` of its
+    /// lines, or of the outline of or anywhere inside its shape.
+    pub fn contains(&self, point: Point, tolerance: f32) -> bool {
+        let reach = tolerance + self.style().stroke_width as f32 / 2.;
+        let near_segment =
+            |start: Point, end: Point| distance_to_segment(point, start, end) <= reach;
+
+        match self {
+            CanvasElement::Line { x1, y1, x2, y2, .. }
+            | CanvasElement::Arrow { x1, y1, x2, y2, .. } => near_segment((*x1, *y1), (*x2, *y2)),
+            CanvasElement::Stroke { points, .. } => match points.as_slice() {
+                [dot] => distance(point, *dot) <= reach,
+                _ => points
+                    .windows(2)
+                    .any(|segment| near_segment(segment[0], segment[1])),
+            },
+            CanvasElement::Polygon { points, .. } => {
+                point_in_polygon(point, points)
+                    || (0..points.len())
+                        .any(|i| near_segment(points[i], points[(i + 1) % points.len()]))
+            }
+            CanvasElement::Circle { x, y, radius, .. } => {
+                distance(point, (*x, *y)) <= *radius as f32 + reach
+            }
+            CanvasElement::Ellipse {
+                x,
+                y,
+                radius_x,
+                radius_y,
+                ..
+            } => {
+                let dx = (point.0 as f32 - *x as f32) / (*radius_x as f32 + reach);
+                let dy = (point.1 as f32 - *y as f32) / (*radius_y as f32 + reach);
+                dx * dx + dy * dy <= 1.
+            }
+            CanvasElement::Rect { .. }
+            | CanvasElement::RoundedRect { .. }
+            | CanvasElement::Image { .. }
+            | CanvasElement::Text { .. } => {
+                let (min, max) = self.bounds();
+                let (x, y) = (point.0 as f32, point.1 as f32);
+                x >= min.0 as f32 - reach
+                    && x <= max.0 as f32 + reach
+                    && y >= min.1 as f32 - reach
+                    && y <= max.1 as f32 + reach
+            }
+        }
+    }
+
+    /// Stretches the element so that its [bounds](CanvasElement::bounds) go
+    /// from `min` to `max`. Circles stay round, fitting the smaller side, and
+    /// text is stretched through its font size.
+    pub fn fit(&mut self, min: Point, max: Point) {
+        let (from_min, from_max) = self.bounds();
+        let (width, height) = (max.0.saturating_sub(min.0), max.1.saturating_sub(min.1));
+        let factor = |to: u16, from: u16| match from {
+            0 => 1.,
+            from => to as f32 / from as f32,
+        };
+        let (scale_x, scale_y) = (
+            factor(width, from_max.0 - from_min.0),
+            factor(height, from_max.1 - from_min.1),
+        );
+        let point = |x: &mut u16, y: &mut u16| {
+            *x = (min.0 as f32 + (*x - from_min.0) as f32 * scale_x).round() as u16;
+            *y = (min.1 as f32 + (*y - from_min.1) as f32 * scale_y).round() as u16;
+        };
+        let (centre_x, centre_y) = (min.0 + width / 2, min.1 + height / 2);
+
+        match self {
+            CanvasElement::Line { x1, y1, x2, y2, .. }
+            | CanvasElement::Arrow { x1, y1, x2, y2, .. } => {
+                point(x1, y1);
+                point(x2, y2);
+            }
+            CanvasElement::Stroke { points, .. } | CanvasElement::Polygon { points, .. } => {
+                for (x, y) in points.iter_mut() {
+                    point(x, y);
+                }
+            }
+            CanvasElement::Circle { x, y, radius, .. } => {
+                (*x, *y) = (centre_x, centre_y);
+                *radius = width.min(height) / 2;
+            }
+            CanvasElement::Ellipse {
+                x,
+                y,
+                radius_x,
+                radius_y,
+                ..
+            } => {
+                (*x, *y) = (centre_x, centre_y);
+                (*radius_x, *radius_y) = (width / 2, height / 2);
+            }
+            CanvasElement::Rect {
+                x,
+                y,
+                width: w,
+                height: h,
+                ..
+            }
+            | CanvasElement::Image {
+                x,
+                y,
+                width: w,
+                height: h,
+                ..
+            } => {
+                (*x, *y, *w, *h) = (min.0, min.1, width, height);
+            }
+            CanvasElement::RoundedRect {
+                x,
+                y,
+                width: w,
+                height: h,
+                radius,
+                ..
+            } => {
+                (*x, *y, *w, *h) = (min.0, min.1, width, height);
+                *radius = (*radius as f32 * scale_x.min(scale_y)).round() as u16;
+            }
+            // Drawn with its baseline at `y`
+            CanvasElement::Text { x, y, style, .. } => {
+                (*x, *y) = (min.0, max.1);
+                style.font_size = height.max(1);
+            }
+        }
+    }
+
+    /// Makes the element `factor` times as large about its centre, keeping at
+    /// least one unit of any size. Text is scaled through its font size.
+    pub fn scale(&mut self, factor: f32) {
+        let (cx, cy) = self.centre();
+        let clamp = |value: f32| value.round().clamp(0., u16::MAX as f32) as u16;
+        let size = |value: &mut u16| *value = clamp(*value as f32 * factor).max(1);
+        let point = |x: &mut u16, y: &mut u16| {
+            *x = clamp(cx + (*x as f32 - cx) * factor);
+            *y = clamp(cy + (*y as f32 - cy) * factor);
+        };
+
+        match self {
+            CanvasElement::Line { x1, y1, x2, y2, .. }
+            | CanvasElement::Arrow { x1, y1, x2, y2, .. } => {
+                point(x1, y1);
+                point(x2, y2);
+            }
+            CanvasElement::Circle { x, y, radius, .. } => {
+                point(x, y);
+                size(radius);
+            }
+            CanvasElement::Ellipse {
+                x,
+                y,
+                radius_x,
+                radius_y,
+                ..
+            } => {
+                point(x, y);
+                size(radius_x);
+                size(radius_y);
+            }
+            CanvasElement::Rect {
+                x,
+                y,
+                width,
+                height,
+                ..
+            }
+            | CanvasElement::Image {
+                x,
+                y,
+                width,
+                height,
+                ..
+            } => {
+                point(x, y);
+                size(width);
+                size(height);
+            }
+            CanvasElement::RoundedRect {
+                x,
+                y,
+                width,
+                height,
+                radius,
+                ..
+            } => {
+                point(x, y);
+                size(width);
+                size(height);
+                size(radius);
+            }
+            CanvasElement::Text { x, y, style, .. } => {
+                point(x, y);
+                size(&mut style.font_size);
+            }
+            CanvasElement::Stroke { points, .. } | CanvasElement::Polygon { points, .. } => {
+                for (x, y) in points.iter_mut() {
+                    point(x, y);
+                }
+            }
+        }
+    }
+
+    /// Moves the element by `(dx, dy)`, stopping at the edges of the canvas.
+    pub fn translate(&mut self, dx: i32, dy: i32) {
+        let shift = |value: &mut u16, by: i32| {
+            *value = (*value as i32).saturating_add(by).clamp(0, u16::MAX as i32) as u16;
+        };
+        let shift_point = |(x, y): &mut Point| {
+            shift(x, dx);
+            shift(y, dy);
+        };
+
+        match self {
+            CanvasElement::Line { x1, y1, x2, y2, .. }
+            | CanvasElement::Arrow { x1, y1, x2, y2, .. } => {
+                shift(x1, dx);
+                shift(y1, dy);
+                shift(x2, dx);
+                shift(y2, dy);
+            }
+            CanvasElement::Circle { x, y, .. }
+            | CanvasElement::Rect { x, y, .. }
+            | CanvasElement::Text { x, y, .. }
+            | CanvasElement::Ellipse { x, y, .. }
+            | CanvasElement::RoundedRect { x, y, .. }
+            | CanvasElement::Image { x, y, .. } => {
+                shift(x, dx);
+                shift(y, dy);
+            }
+            CanvasElement::Stroke { points, .. } | CanvasElement::Polygon { points, .. } => {
+                points.iter_mut().for_each(shift_point);
+            }
+        }
+    }
+
+    pub fn set_style(&mut self, new_style: Style) {
+        match self {
+            CanvasElement::Line { style, .. }
+            | CanvasElement::Circle { style, .. }
+            | CanvasElement::Rect { style, .. }
+            | CanvasElement::Text { style, .. }
+            | CanvasElement::Stroke { style, .. }
+            | CanvasElement::Ellipse { style, .. }
+            | CanvasElement::Polygon { style, .. }
+            | CanvasElement::Arrow { style, .. }
+            | CanvasElement::RoundedRect { style, .. }
+            | CanvasElement::Image { style, .. } => *style = new_style,
+        }
+    }
+}
+
+impl EntryId {
+    pub fn new(site: SiteId, counter: u64) -> Self {
+        EntryId { site, counter }
+    }
+}
+
+impl fmt::Display for EntryId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.site == SERVER_SITE {
+            write!(f, "{}", self.counter)
+        } else {
+            write!(f, "{}:{}", self.site, self.counter)
+        }
+    }
+}
+
+impl FromStr for EntryId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((site, counter)) => Ok(EntryId::new(site.parse()?, counter.parse()?)),
+            None => Ok(EntryId::new(SERVER_SITE, s.parse()?)),
+        }
+    }
 }
 
-#[derive(Debug, Encode, Decode, Clone)]
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CanvasEntry {
-    pub id: usize,
+    pub id: EntryId,
     pub shown: bool,
     pub element: CanvasElement,
     pub author: String,
+    /// Where the entry is stacked within its layer, entries with a higher `z`
+    /// are drawn on top
+    pub z: i64,
+    pub layer: LayerId,
+    /// The name of the group the entry is in, if any, which it is moved,
+    /// restyled and deleted along with
+    pub group: Option<String>,
+    /// How far the element is turned clockwise about its centre, in degrees
+    /// from 0 up to 360
+    pub rotation: f32,
+}
+
+impl CanvasEntry {
+    /// Where a point of the canvas would be if the element was not rotated,
+    /// which is how its shape and handles are compared against the point.
+    pub fn unrotate(&self, point: (f32, f32)) -> (f32, f32) {
+        if self.rotation == 0. {
+            return point;
+        }
+
+        let (cx, cy) = self.element.centre();
+        let (sin, cos) = (-self.rotation).to_radians().sin_cos();
+        let (dx, dy) = (point.0 - cx, point.1 - cy);
+        (cx + dx * cos - dy * sin, cy + dx * sin + dy * cos)
+    }
+
+    /// Whether the point is on the element as it is drawn, rotated.
+    pub fn contains(&self, point: Point, tolerance: f32) -> bool {
+        let (x, y) = self.unrotate((point.0 as f32, point.1 as f32));
+        let clamp = |value: f32| value.round().clamp(0., u16::MAX as f32) as u16;
+        self.element.contains((clamp(x), clamp(y)), tolerance)
+    }
+
+    pub fn transform(&mut self, transform: Transform) {
+        match transform {
+            Transform::Translate(dx, dy) => self.element.translate(dx, dy),
+            Transform::Scale(factor) => self.element.scale(factor),
+            Transform::Rotate(degrees) => {
+                self.rotation = (self.rotation + degrees).rem_euclid(360.)
+            }
+        }
+    }
+}
+
+/// A named group of entries, drawn above the layers below it and below the
+/// layers above it, whatever the `z` of their entries.
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub struct Layer {
+    pub id: LayerId,
+    pub name: String,
+    /// Hidden layers are not drawn, but their entries are kept
+    pub hidden: bool,
+    /// The entries of a locked layer cannot be drawn, changed or deleted
+    pub locked: bool,
+    /// Who may rename, hide, lock or move the layer, or anyone if [None]
+    pub owner: Option<String>,
+}
+
+/// A change to the layers of the canvas, rather than to its entries.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub enum LayerChange {
+    /// Adds a layer with this name on top of the others
+    Create(String),
+    Rename(LayerId, String),
+    SetHidden(LayerId, bool),
+    SetLocked(LayerId, bool),
+    /// Moves the layer to this position, counting from the bottom
+    Move(LayerId, usize),
+}
+
+impl LayerChange {
+    /// The layer being changed, or [None] for a layer yet to be created.
+    pub fn layer(&self) -> Option<LayerId> {
+        match self {
+            LayerChange::Create(_) => None,
+            LayerChange::Rename(id, _)
+            | LayerChange::SetHidden(id, _)
+            | LayerChange::SetLocked(id, _)
+            | LayerChange::Move(id, _) => Some(*id),
+        }
+    }
+}
+
+/// A change to where an element is, how large it is or how it is turned,
+/// sent instead of the whole element.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    /// Moves the element by `(dx, dy)`
+    Translate(i32, i32),
+    /// Makes the element this many times as large about its centre
+    Scale(f32),
+    /// Turns the element clockwise about its centre by this many degrees
+    Rotate(f32),
+}
+
+impl Transform {
+    /// Whether the transform leaves elements in a shape that can be drawn,
+    /// which rules out rotations that are not finite, and scale factors that
+    /// are not finite or would flatten or mirror the element.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Transform::Translate(_, _) => true,
+            Transform::Scale(factor) => factor.is_finite() && factor > 0.,
+            Transform::Rotate(degrees) => degrees.is_finite(),
+        }
+    }
+}
+
+/// A change to several entries at once, e.g. the ones selected, made and
+/// undone as one.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub enum BatchChange {
+    Delete,
+    /// Gives every line, outline and text this colour, and every fill too
+    /// unless the entry is not filled
+    Recolour([u8; 4]),
+    Restyle(Style),
+    /// Moves every entry in the stacking order, keeping them in the same
+    /// order among themselves
+    Restack(Restack),
+}
+
+/// A change to every entry of a group at once, made and undone as one.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub enum GroupChange {
+    /// Puts the entries in the group with this name, taking them out of any other
+    Create(String, Vec<EntryId>),
+    /// Takes every entry out of the group, leaving them on the canvas
+    Dissolve(String),
+    /// Moves every entry of the group by `(dx, dy)`
+    Move(String, i32, i32),
+    Restyle(String, Style),
+    Delete(String),
+}
+
+impl GroupChange {
+    /// The name of the group being changed.
+    pub fn group(&self) -> &str {
+        match self {
+            GroupChange::Create(name, _)
+            | GroupChange::Dissolve(name)
+            | GroupChange::Move(name, _, _)
+            | GroupChange::Restyle(name, _)
+            | GroupChange::Delete(name) => name,
+        }
+    }
+}
+
+/// How to move an entry in the stacking order.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum Restack {
+    /// Above the entry right above it
+    Raise,
+    /// Below the entry right below it
+    Lower,
+    /// Above every other entry
+    ToFront,
+    /// Below every other entry
+    ToBack,
 }
 
 #[derive(Clone)]
 pub struct Canvas {
     pub entries: Vec<CanvasEntry>,
-    pub current_action_id: usize,
+    /// The site the ids of new entries are created for
+    pub site: SiteId,
+    /// The counter of the next entry created
+    pub next_counter: u64,
+    /// From the bottom layer to the top one
+    pub layers: Vec<Layer>,
+    /// The id of the next layer created
+    pub next_layer: LayerId,
 }
 
 impl Canvas {
     pub fn new() -> Self {
+        Self::with_site(SERVER_SITE)
+    }
+
+    pub fn with_site(site: SiteId) -> Self {
         Self {
             entries: Vec::new(),
-            current_action_id: 0,
+            site,
+            next_counter: 0,
+            layers: vec![Layer {
+                id: BASE_LAYER,
+                name: "Base".to_string(),
+                hidden: false,
+                locked: false,
+                owner: None,
+            }],
+            next_layer: BASE_LAYER + 1,
         }
     }
 
     pub fn add_action(&mut self, user: String, element: &CanvasElement) -> CanvasEntry {
+        self.add_to_layer(BASE_LAYER, user, element)
+    }
+
+    pub fn add_to_layer(
+        &mut self,
+        layer: LayerId,
+        user: String,
+        element: &CanvasElement,
+    ) -> CanvasEntry {
         let entry = CanvasEntry {
-            id: self.current_action_id,
+            id: EntryId::new(self.site, self.next_counter),
             shown: true,
             element: element.clone(),
             author: user.clone(),
+            z: self.top_z().map_or(0, |z| z + 1),
+            layer,
+            group: None,
+            rotation: 0.,
         };
         self.entries.push(entry.clone());
-        self.current_action_id += 1;
+        self.next_counter += 1;
         entry
     }
 
-    pub fn get_entry(&self, id: usize) -> Option<&CanvasEntry> {
+    pub fn get_entry(&self, id: EntryId) -> Option<&CanvasEntry> {
         self.entries.iter().find(|entry| entry.id == id)
     }
 
-    pub fn update_entry(&mut self, id: usize, element: &CanvasElement) -> Option<CanvasEntry> {
+    pub fn update_entry(&mut self, id: EntryId, element: &CanvasElement) -> Option<CanvasEntry> {
         let index = self.entries.iter().position(|x| x.id == id);
 
         if let Some(index) = index {
             let entry = CanvasEntry {
-                id,
                 element: element.clone(),
-                shown: self.entries[index].shown,
-                author: self.entries[index].author.clone(),
+                ..self.entries[index].clone()
             };
             self.entries[index] = entry.clone();
             Some(entry)
@@ -86,11 +860,101 @@ impl Canvas {
         }
     }
 
-    pub fn delete_entry(&mut self, id: usize) {
+    pub fn top_z(&self) -> Option<i64> {
+        self.entries.iter().map(|entry| entry.z).max()
+    }
+
+    pub fn bottom_z(&self) -> Option<i64> {
+        self.entries.iter().map(|entry| entry.z).min()
+    }
+
+    /// The entries from the bottom of the stack to the top, the order they
+    /// are drawn in. Entries on a layer that does not exist are drawn first.
+    pub fn stacked(&self) -> Vec<&CanvasEntry> {
+        let mut entries: Vec<&CanvasEntry> = self.entries.iter().collect();
+        entries.sort_by_key(|entry| {
+            let position = self
+                .layers
+                .iter()
+                .position(|layer| layer.id == entry.layer)
+                .map_or(0, |position| position + 1);
+            (position, entry.z, entry.id)
+        });
+        entries
+    }
+
+    pub fn layer(&self, id: LayerId) -> Option<&Layer> {
+        self.layers.iter().find(|layer| layer.id == id)
+    }
+
+    /// The layer the entry is on, if it exists.
+    pub fn layer_of(&self, entry: &CanvasEntry) -> Option<&Layer> {
+        self.layer(entry.layer)
+    }
+
+    /// The entries in the group with this name.
+    pub fn group(&self, name: &str) -> Vec<&CanvasEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.group.as_deref() == Some(name))
+            .collect()
+    }
+
+    /// Whether the entry with this id is on a locked layer.
+    pub fn is_locked(&self, id: EntryId) -> bool {
+        self.get_entry(id)
+            .and_then(|entry| self.layer_of(entry))
+            .is_some_and(|layer| layer.locked)
+    }
+
+    /// Applies a change to the layers, returning the id of the layer changed,
+    /// or [None] if it does not exist.
+    pub fn change_layer(&mut self, change: LayerChange, owner: &str) -> Option<LayerId> {
+        let index = match change.layer() {
+            Some(id) => Some(self.layers.iter().position(|layer| layer.id == id)?),
+            None => None,
+        };
+
+        match (change, index) {
+            (LayerChange::Create(name), _) => {
+                let id = self.next_layer;
+                self.layers.push(Layer {
+                    id,
+                    name,
+                    hidden: false,
+                    locked: false,
+                    owner: Some(owner.to_string()),
+                });
+                self.next_layer += 1;
+                Some(id)
+            }
+            (LayerChange::Rename(id, name), Some(index)) => {
+                self.layers[index].name = name;
+                Some(id)
+            }
+            (LayerChange::SetHidden(id, hidden), Some(index)) => {
+                self.layers[index].hidden = hidden;
+                Some(id)
+            }
+            (LayerChange::SetLocked(id, locked), Some(index)) => {
+                self.layers[index].locked = locked;
+                Some(id)
+            }
+            (LayerChange::Move(id, position), Some(index)) => {
+                let layer = self.layers.remove(index);
+                let position = position.min(self.layers.len());
+                self.layers.insert(position, layer);
+                Some(id)
+            }
+            (_, None) => None,
+        }
+    }
+
+    pub fn delete_entry(&mut self, id: EntryId) {
         self.entries.retain(|entry| entry.id != id);
     }
 
-    pub fn overwrite_entry(&mut self, id: usize, new_entry: CanvasEntry) {
+    pub fn overwrite_entry(&mut self, id: EntryId, new_entry: CanvasEntry) {
         if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) {
             *entry = new_entry;
         }
@@ -105,6 +969,137 @@ impl Default for Canvas {
 
 impl std::fmt::Display for CanvasEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[{}] by [{}], {:?}", self.id, self.author, self.element)
+        write!(f, "[{}] by [{}], {:?}", self.id, self.author, self.element)?;
+        if self.rotation != 0. {
+            write!(f, " turned by {} degrees", self.rotation)?;
+        }
+        if let Some(group) = &self.group {
+            write!(f, " in group {}", group)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> CanvasElement {
+        CanvasElement::Rect {
+            x,
+            y,
+            width,
+            height,
+            style: Style::filled([0, 0, 0, 255]),
+        }
+    }
+
+    #[test]
+    fn lines_are_hit_within_tolerance() {
+        let line = CanvasElement::Line {
+            x1: 0,
+            y1: 0,
+            x2: 100,
+            y2: 0,
+            style: Style {
+                stroke_width: 4,
+                ..Style::default()
+            },
+        };
+        // Half the width of the line plus the tolerance
+        assert!(line.contains((50, 5), 3.));
+        assert!(!line.contains((50, 6), 3.));
+        assert!(!line.contains((110, 0), 3.));
+    }
+
+    #[test]
+    fn shapes_are_hit_anywhere_inside() {
+        assert!(rect(10, 10, 20, 20).contains((20, 20), 0.));
+        assert!(!rect(10, 10, 20, 20).contains((35, 20), 0.));
+
+        let circle = CanvasElement::Circle {
+            x: 50,
+            y: 50,
+            radius: 10,
+            style: Style::default(),
+        };
+        assert!(circle.contains((50, 50), 0.));
+        // The outline is 5 wide
+        assert!(circle.contains((62, 50), 0.));
+        assert!(!circle.contains((60, 60), 0.));
+    }
+
+    #[test]
+    fn rotated_entries_are_hit_where_they_are_drawn() {
+        let mut canvas = Canvas::new();
+        let mut entry = canvas.add_action("alice".to_string(), &rect(100, 195, 100, 10));
+        assert!(entry.contains((190, 200), 0.));
+
+        entry.rotation = 90.;
+        assert!(!entry.contains((190, 200), 0.));
+        assert!(entry.contains((150, 240), 0.));
+    }
+
+    #[test]
+    fn fitted_elements_fill_their_new_bounds() {
+        let mut element = rect(10, 10, 20, 20);
+        element.fit((0, 0), (50, 10));
+        assert_eq!(element.bounds(), ((0, 0), (50, 10)));
+
+        let mut stroke = CanvasElement::Stroke {
+            points: vec![(10, 10), (20, 30), (30, 20)],
+            style: Style::default(),
+        };
+        stroke.fit((0, 0), (40, 10));
+        assert_eq!(stroke.bounds(), ((0, 0), (40, 10)));
+
+        // Circles stay round
+        let mut circle = CanvasElement::Circle {
+            x: 50,
+            y: 50,
+            radius: 10,
+            style: Style::default(),
+        };
+        circle.fit((0, 0), (40, 20));
+        assert_eq!(circle.bounds(), ((10, 0), (30, 20)));
+    }
+
+    #[test]
+    fn transforms_that_cannot_be_drawn_are_invalid() {
+        assert!(Transform::Translate(-5, 5).is_valid());
+        assert!(Transform::Scale(0.5).is_valid());
+        assert!(Transform::Rotate(-90.).is_valid());
+
+        assert!(!Transform::Rotate(f32::NAN).is_valid());
+        assert!(!Transform::Rotate(f32::INFINITY).is_valid());
+        assert!(!Transform::Scale(f32::NAN).is_valid());
+        assert!(!Transform::Scale(f32::INFINITY).is_valid());
+        assert!(!Transform::Scale(0.).is_valid());
+        assert!(!Transform::Scale(-1.).is_valid());
+    }
+
+    #[test]
+    fn translations_stop_at_the_edges() {
+        let mut element = CanvasElement::Line {
+            x1: 10,
+            y1: 10,
+            x2: 20,
+            y2: 20,
+            style: Style::solid([0, 0, 0, 255]),
+        };
+
+        element.translate(i32::MAX, i32::MIN);
+
+        assert!(matches!(
+            element,
+            CanvasElement::Line {
+                x1: u16::MAX,
+                y1: 0,
+                x2: u16::MAX,
+                y2: 0,
+                ..
+            }
+        ));
     }
 }
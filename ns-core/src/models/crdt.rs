@@ -0,0 +1,617 @@
+use std::collections::BTreeMap;
+
+use bincode::{Decode, Encode};
+
+use crate::models::canvas::{
+    CanvasElement, CanvasEntry, EntryId, LayerId, SiteId, Style, BASE_LAYER,
+};
+
+/// A Lamport timestamp. Ordering by counter first and by site second gives
+/// every replica the same total order over all writes, which decides which
+/// of two concurrent writes wins.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub counter: u64,
+    pub site: SiteId,
+}
+
+/// A last-writer-wins register.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct Register<T> {
+    pub value: T,
+    pub timestamp: Timestamp,
+}
+
+/// The state of one entry, with a register per field so that concurrent
+/// changes to different fields, such as moving and restyling, are both kept.
+///
+/// Every field of a [CanvasEntry] has a register, except `shown` which each
+/// client decides for itself. [LiveEntry::into_entry] lists them all, so that
+/// a field added to [CanvasEntry] cannot be forgotten here.
+///
+/// A field stays empty until a write to it is seen, which is what lets the
+/// operations on an entry arrive in any order.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Default)]
+pub struct CrdtEntry {
+    pub author: Option<Register<String>>,
+    pub shape: Option<Register<CanvasElement>>,
+    pub style: Option<Register<Style>>,
+    pub z: Option<Register<i64>>,
+    pub layer: Option<Register<LayerId>>,
+    pub group: Option<Register<Option<String>>>,
+    pub rotation: Option<Register<f32>>,
+    pub removed: Option<Register<bool>>,
+}
+
+/// A change made to one entry by one replica.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub enum Change {
+    Insert {
+        author: String,
+        element: CanvasElement,
+    },
+    /// Replaces the geometry of the element, keeping its style
+    Shape(CanvasElement),
+    Style(Style),
+    /// Moves the entry in the stacking order of its layer
+    Restack(i64),
+    Layer(LayerId),
+    /// Puts the entry in a group, or takes it out of any
+    Group(Option<String>),
+    /// Turns the entry to this many degrees, rather than by them, so that the
+    /// last write wins like for every other field
+    Rotate(f32),
+    Remove,
+    /// Brings a removed entry back, e.g. when undoing the removal
+    Restore,
+}
+
+/// An operation to be sent to every other replica. Operations can be applied
+/// in any order, and more than once, and still lead to the same canvas.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct CrdtOp {
+    pub id: EntryId,
+    pub timestamp: Timestamp,
+    pub change: Change,
+}
+
+/// An entry as currently shown by a replica.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveEntry {
+    pub author: String,
+    pub element: CanvasElement,
+    pub z: i64,
+    pub layer: LayerId,
+    pub group: Option<String>,
+    pub rotation: f32,
+}
+
+impl LiveEntry {
+    /// The entry as it is stored on a canvas, shown.
+    pub fn into_entry(self, id: EntryId) -> CanvasEntry {
+        CanvasEntry {
+            id,
+            shown: true,
+            element: self.element,
+            author: self.author,
+            z: self.z,
+            layer: self.layer,
+            group: self.group,
+            rotation: self.rotation,
+        }
+    }
+}
+
+/// One copy of the canvas as a conflict-free replicated data type: a map from
+/// [EntryId] to last-writer-wins registers, where removed entries are kept as
+/// tombstones so that a late update cannot bring them back.
+///
+/// Replicas converge to the same canvas once they have seen the same
+/// operations, whether those were exchanged one by one with
+/// [Replica::apply] or as whole states with [Replica::merge].
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct Replica {
+    pub site: SiteId,
+    clock: u64,
+    entries: BTreeMap<EntryId, CrdtEntry>,
+}
+
+impl<T> Register<T> {
+    fn new(value: T, timestamp: Timestamp) -> Self {
+        Register { value, timestamp }
+    }
+}
+
+/// Keeps whichever of the two writes to a field is the most recent.
+fn merge_register<T: Clone>(field: &mut Option<Register<T>>, other: &Option<Register<T>>) {
+    if let Some(other) = other {
+        if field
+            .as_ref()
+            .is_none_or(|current| other.timestamp > current.timestamp)
+        {
+            *field = Some(other.clone());
+        }
+    }
+}
+
+impl CrdtEntry {
+    /// The fields written by a single operation.
+    fn from_op(op: &CrdtOp) -> Self {
+        match &op.change {
+            Change::Insert { author, element } => CrdtEntry {
+                author: Some(Register::new(author.clone(), op.timestamp)),
+                shape: Some(Register::new(element.clone(), op.timestamp)),
+                style: Some(Register::new(element.style().clone(), op.timestamp)),
+                z: Some(Register::new(0, op.timestamp)),
+                layer: Some(Register::new(BASE_LAYER, op.timestamp)),
+                group: Some(Register::new(None, op.timestamp)),
+                rotation: Some(Register::new(0., op.timestamp)),
+                removed: Some(Register::new(false, op.timestamp)),
+            },
+            Change::Shape(element) => CrdtEntry {
+                shape: Some(Register::new(element.clone(), op.timestamp)),
+                ..CrdtEntry::default()
+            },
+            Change::Style(style) => CrdtEntry {
+                style: Some(Register::new(style.clone(), op.timestamp)),
+                ..CrdtEntry::default()
+            },
+            Change::Restack(z) => CrdtEntry {
+                z: Some(Register::new(*z, op.timestamp)),
+                ..CrdtEntry::default()
+            },
+            Change::Layer(layer) => CrdtEntry {
+                layer: Some(Register::new(*layer, op.timestamp)),
+                ..CrdtEntry::default()
+            },
+            Change::Group(group) => CrdtEntry {
+                group: Some(Register::new(group.clone(), op.timestamp)),
+                ..CrdtEntry::default()
+            },
+            Change::Rotate(rotation) => CrdtEntry {
+                rotation: Some(Register::new(*rotation, op.timestamp)),
+                ..CrdtEntry::default()
+            },
+            Change::Remove => CrdtEntry {
+                removed: Some(Register::new(true, op.timestamp)),
+                ..CrdtEntry::default()
+            },
+            Change::Restore => CrdtEntry {
+                removed: Some(Register::new(false, op.timestamp)),
+                ..CrdtEntry::default()
+            },
+        }
+    }
+
+    pub fn merge(&mut self, other: &CrdtEntry) {
+        merge_register(&mut self.author, &other.author);
+        merge_register(&mut self.shape, &other.shape);
+        merge_register(&mut self.style, &other.style);
+        merge_register(&mut self.z, &other.z);
+        merge_register(&mut self.layer, &other.layer);
+        merge_register(&mut self.group, &other.group);
+        merge_register(&mut self.rotation, &other.rotation);
+        merge_register(&mut self.removed, &other.removed);
+    }
+
+    /// What the entry looks like, unless it was removed or its insertion has
+    /// not been seen yet.
+    pub fn live(&self) -> Option<LiveEntry> {
+        if self.removed.as_ref().is_none_or(|removed| removed.value) {
+            return None;
+        }
+
+        let mut element = self.shape.as_ref()?.value.clone();
+        if let Some(style) = &self.style {
+            element.set_style(style.value.clone());
+        }
+
+        Some(LiveEntry {
+            author: self.author.as_ref()?.value.clone(),
+            element,
+            z: self.z.as_ref()?.value,
+            layer: self.layer.as_ref()?.value,
+            group: self.group.as_ref()?.value.clone(),
+            rotation: self.rotation.as_ref()?.value,
+        })
+    }
+}
+
+impl Replica {
+    pub fn new(site: SiteId) -> Self {
+        Replica {
+            site,
+            clock: 0,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn tick(&mut self) -> Timestamp {
+        self.clock += 1;
+        Timestamp {
+            counter: self.clock,
+            site: self.site,
+        }
+    }
+
+    /// Makes a local change, returning the operation to send to the others.
+    fn make(&mut self, id: EntryId, change: Change) -> CrdtOp {
+        let op = CrdtOp {
+            id,
+            timestamp: self.tick(),
+            change,
+        };
+        self.apply(&op);
+        op
+    }
+
+    pub fn insert(&mut self, author: &str, element: CanvasElement) -> CrdtOp {
+        // Entries are named after the timestamp they are inserted at
+        let id = EntryId::new(self.site, self.clock + 1);
+        self.make(
+            id,
+            Change::Insert {
+                author: author.to_string(),
+                element,
+            },
+        )
+    }
+
+    /// Replaces a live entry by `element`, only writing the fields that
+    /// changed so that concurrent changes to the others are kept.
+    pub fn update(&mut self, id: EntryId, element: CanvasElement) -> Vec<CrdtOp> {
+        let Some(current) = self.get(id) else {
+            return Vec::new();
+        };
+
+        let mut ops = Vec::new();
+        let mut reshaped = current.element.clone();
+        reshaped.set_style(element.style().clone());
+        if reshaped != element {
+            ops.push(self.make(id, Change::Shape(element.clone())));
+        }
+        if current.element.style() != element.style() {
+            ops.push(self.make(id, Change::Style(element.style().clone())));
+        }
+        ops
+    }
+
+    pub fn restack(&mut self, id: EntryId, z: i64) -> Option<CrdtOp> {
+        (self.get(id)?.z != z).then(|| self.make(id, Change::Restack(z)))
+    }
+
+    pub fn move_to_layer(&mut self, id: EntryId, layer: LayerId) -> Option<CrdtOp> {
+        (self.get(id)?.layer != layer).then(|| self.make(id, Change::Layer(layer)))
+    }
+
+    pub fn set_group(&mut self, id: EntryId, group: Option<String>) -> Option<CrdtOp> {
+        (self.get(id)?.group != group).then(|| self.make(id, Change::Group(group)))
+    }
+
+    pub fn rotate(&mut self, id: EntryId, rotation: f32) -> Option<CrdtOp> {
+        (self.get(id)?.rotation != rotation).then(|| self.make(id, Change::Rotate(rotation)))
+    }
+
+    pub fn remove(&mut self, id: EntryId) -> Option<CrdtOp> {
+        self.get(id)?;
+        Some(self.make(id, Change::Remove))
+    }
+
+    pub fn restore(&mut self, id: EntryId) -> Option<CrdtOp> {
+        if !self.entries.contains_key(&id) || self.get(id).is_some() {
+            return None;
+        }
+        Some(self.make(id, Change::Restore))
+    }
+
+    /// Applies an operation made by any replica, including this one.
+    pub fn apply(&mut self, op: &CrdtOp) {
+        self.clock = self.clock.max(op.timestamp.counter);
+        self.entries
+            .entry(op.id)
+            .or_default()
+            .merge(&CrdtEntry::from_op(op));
+    }
+
+    /// Merges the whole state of another replica into this one.
+    pub fn merge(&mut self, other: &Replica) {
+        self.clock = self.clock.max(other.clock);
+        for (id, entry) in other.entries.iter() {
+            self.entries.entry(*id).or_default().merge(entry);
+        }
+    }
+
+    pub fn get(&self, id: EntryId) -> Option<LiveEntry> {
+        self.entries.get(&id).and_then(CrdtEntry::live)
+    }
+
+    /// Every live entry, in the order they were inserted in.
+    pub fn entries(&self) -> Vec<(EntryId, LiveEntry)> {
+        let mut entries: Vec<(EntryId, LiveEntry)> = self
+            .entries
+            .iter()
+            .filter_map(|(id, entry)| entry.live().map(|live| (*id, live)))
+            .collect();
+        entries.sort_by_key(|(id, _)| (id.counter, id.site));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const SITES: usize = 3;
+
+    fn circle(radius: u16, colour: u8) -> CanvasElement {
+        CanvasElement::Circle {
+            x: 10,
+            y: 10,
+            radius,
+            style: Style::filled([colour, 0, 0, 255]),
+        }
+    }
+
+    /// Something one of the replicas does, where `pick` chooses among the
+    /// entries it knows about.
+    #[derive(Debug, Clone)]
+    enum Step {
+        Insert {
+            site: usize,
+            radius: u16,
+        },
+        Update {
+            site: usize,
+            pick: usize,
+            radius: u16,
+            colour: u8,
+        },
+        Remove {
+            site: usize,
+            pick: usize,
+        },
+        Restore {
+            site: usize,
+            pick: usize,
+        },
+        /// Changes one of the fields that are not part of the element, from
+        /// the stacking order to the rotation depending on `field`
+        Arrange {
+            site: usize,
+            pick: usize,
+            field: u8,
+            value: u8,
+        },
+        Sync {
+            from: usize,
+            to: usize,
+        },
+    }
+
+    fn step() -> impl Strategy<Value = Step> {
+        let site = 0..SITES;
+        prop_oneof![
+            (site.clone(), any::<u16>()).prop_map(|(site, radius)| Step::Insert { site, radius }),
+            (site.clone(), any::<usize>(), any::<u16>(), any::<u8>()).prop_map(
+                |(site, pick, radius, colour)| Step::Update {
+                    site,
+                    pick,
+                    radius,
+                    colour
+                }
+            ),
+            (site.clone(), any::<usize>()).prop_map(|(site, pick)| Step::Remove { site, pick }),
+            (site.clone(), any::<usize>()).prop_map(|(site, pick)| Step::Restore { site, pick }),
+            (site.clone(), any::<usize>(), 0..4u8, 0..4u8).prop_map(
+                |(site, pick, field, value)| Step::Arrange {
+                    site,
+                    pick,
+                    field,
+                    value
+                }
+            ),
+            (site.clone(), site).prop_map(|(from, to)| Step::Sync { from, to }),
+        ]
+    }
+
+    /// Runs the steps on fresh replicas, returning them along with every
+    /// operation they made.
+    fn run(steps: &[Step]) -> (Vec<Replica>, Vec<CrdtOp>) {
+        let mut replicas: Vec<Replica> = (0..SITES as SiteId).map(Replica::new).collect();
+        let mut ops = Vec::new();
+
+        let known = |replica: &Replica, pick: usize| {
+            let ids: Vec<EntryId> = replica.entries.keys().copied().collect();
+            (!ids.is_empty()).then(|| ids[pick % ids.len()])
+        };
+
+        for step in steps {
+            match *step {
+                Step::Insert { site, radius } => {
+                    ops.push(replicas[site].insert(&format!("user{site}"), circle(radius, 0)));
+                }
+                Step::Update {
+                    site,
+                    pick,
+                    radius,
+                    colour,
+                } => {
+                    if let Some(id) = known(&replicas[site], pick) {
+                        ops.extend(replicas[site].update(id, circle(radius, colour)));
+                    }
+                }
+                Step::Remove { site, pick } => {
+                    if let Some(id) = known(&replicas[site], pick) {
+                        ops.extend(replicas[site].remove(id));
+                    }
+                }
+                Step::Restore { site, pick } => {
+                    if let Some(id) = known(&replicas[site], pick) {
+                        ops.extend(replicas[site].restore(id));
+                    }
+                }
+                Step::Arrange {
+                    site,
+                    pick,
+                    field,
+                    value,
+                } => {
+                    if let Some(id) = known(&replicas[site], pick) {
+                        let replica = &mut replicas[site];
+                        ops.extend(match field {
+                            0 => replica.restack(id, value as i64),
+                            1 => replica.move_to_layer(id, value as LayerId),
+                            2 => replica.set_group(id, (value > 0).then(|| format!("g{value}"))),
+                            _ => replica.rotate(id, value as f32 * 90.),
+                        });
+                    }
+                }
+                Step::Sync { from, to } => {
+                    let from = replicas[from].clone();
+                    replicas[to].merge(&from);
+                }
+            }
+        }
+
+        (replicas, ops)
+    }
+
+    /// Reorders the operations according to one sort key per operation.
+    fn shuffle(ops: &[CrdtOp], keys: &[u64]) -> Vec<CrdtOp> {
+        let mut keyed: Vec<(u64, usize, &CrdtOp)> = ops
+            .iter()
+            .enumerate()
+            .map(|(index, op)| (keys.get(index).copied().unwrap_or(0), index, op))
+            .collect();
+        keyed.sort_by_key(|(key, index, _)| (*key, *index));
+        keyed.into_iter().map(|(_, _, op)| op.clone()).collect()
+    }
+
+    #[test]
+    fn concurrent_changes_to_different_fields_are_both_kept() {
+        let mut alice = Replica::new(0);
+        let mut bob = Replica::new(1);
+
+        let insert = alice.insert("alice", circle(5, 0));
+        bob.apply(&insert);
+
+        let moved = alice.update(insert.id, circle(20, 0));
+        let recoloured = bob.update(insert.id, circle(5, 200));
+
+        moved.iter().for_each(|op| bob.apply(op));
+        recoloured.iter().for_each(|op| alice.apply(op));
+
+        assert_eq!(alice.entries(), bob.entries());
+        assert_eq!(alice.get(insert.id).unwrap().element, circle(20, 200));
+    }
+
+    #[test]
+    fn every_field_of_an_entry_is_replicated() {
+        let mut alice = Replica::new(0);
+        let mut bob = Replica::new(1);
+
+        let insert = alice.insert("alice", circle(5, 0));
+        let id = insert.id;
+        bob.apply(&insert);
+
+        let mut ops = vec![
+            alice.restack(id, 3).unwrap(),
+            alice.set_group(id, Some("g".to_string())).unwrap(),
+        ];
+        ops.extend(bob.move_to_layer(id, 2));
+        ops.extend(bob.rotate(id, 45.));
+        ops.iter().for_each(|op| {
+            alice.apply(op);
+            bob.apply(op);
+        });
+
+        assert_eq!(alice.entries(), bob.entries());
+        assert_eq!(
+            alice.get(id).unwrap().into_entry(id),
+            CanvasEntry {
+                id,
+                shown: true,
+                element: circle(5, 0),
+                author: "alice".to_string(),
+                z: 3,
+                layer: 2,
+                group: Some("g".to_string()),
+                rotation: 45.,
+            }
+        );
+    }
+
+    #[test]
+    fn a_removal_wins_over_a_concurrent_update() {
+        let mut alice = Replica::new(0);
+        let mut bob = Replica::new(1);
+
+        let insert = alice.insert("alice", circle(5, 0));
+        bob.apply(&insert);
+
+        let update = bob.update(insert.id, circle(20, 0));
+        let removal = alice.remove(insert.id).unwrap();
+
+        update.iter().for_each(|op| alice.apply(op));
+        bob.apply(&removal);
+
+        assert!(alice.entries().is_empty());
+        assert!(bob.entries().is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn replicas_converge_once_fully_synced(steps in prop::collection::vec(step(), 0..60)) {
+            let (mut replicas, _) = run(&steps);
+
+            for to in 0..SITES {
+                for from in 0..SITES {
+                    let from = replicas[from].clone();
+                    replicas[to].merge(&from);
+                }
+            }
+
+            for replica in replicas.iter().skip(1) {
+                prop_assert_eq!(replica.entries(), replicas[0].entries());
+            }
+        }
+
+        #[test]
+        fn operations_converge_in_any_order(
+            steps in prop::collection::vec(step(), 0..60),
+            keys in prop::collection::vec(any::<u64>(), 0..200),
+            duplicate in any::<bool>(),
+        ) {
+            let (_, ops) = run(&steps);
+
+            let mut in_order = Replica::new(100);
+            ops.iter().for_each(|op| in_order.apply(op));
+
+            let mut shuffled = Replica::new(101);
+            let mut delivered = shuffle(&ops, &keys);
+            if duplicate {
+                delivered.extend(delivered.clone());
+            }
+            delivered.iter().for_each(|op| shuffled.apply(op));
+
+            prop_assert_eq!(shuffled.entries(), in_order.entries());
+        }
+
+        #[test]
+        fn merging_states_matches_applying_operations(steps in prop::collection::vec(step(), 0..60)) {
+            let (replicas, ops) = run(&steps);
+
+            let mut merged = Replica::new(100);
+            for replica in replicas.iter().rev() {
+                merged.merge(replica);
+            }
+
+            let mut applied = Replica::new(101);
+            ops.iter().for_each(|op| applied.apply(op));
+
+            prop_assert_eq!(merged.entries(), applied.entries());
+        }
+    }
+}
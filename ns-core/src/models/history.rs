@@ -0,0 +1,674 @@
+use bincode::{Decode, Encode};
+
+use crate::models::canvas::{
+    BatchChange, Canvas, CanvasEntry, EntryId, GroupChange, Layer, Restack, Style, Transform,
+};
+
+/// A change made to the canvas, holding enough to both replay and revert it.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// An entry was added to the canvas.
+    Draw(CanvasEntry),
+    /// An entry was replaced by a new version of itself.
+    Update {
+        before: CanvasEntry,
+        after: CanvasEntry,
+    },
+    /// An entry was removed from the canvas.
+    Delete(CanvasEntry),
+    /// Several entries were removed at once.
+    Clear(Vec<CanvasEntry>),
+    /// Several entries were added back at once, the inverse of [Operation::Clear].
+    Restore(Vec<CanvasEntry>),
+    /// Every entry of the canvas was replaced.
+    Replace {
+        before: Vec<CanvasEntry>,
+        after: Vec<CanvasEntry>,
+    },
+    /// Several operations made one after the other, undone as one.
+    Batch(Vec<Operation>),
+    /// The layers were changed, e.g. one was created, renamed or moved.
+    Layers {
+        before: Vec<Layer>,
+        after: Vec<Layer>,
+    },
+}
+
+/// An [Operation] along with who made it and when.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct Revision {
+    /// Increases by one with every operation, starting from 1
+    pub number: u64,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub author: String,
+    pub operation: Operation,
+}
+
+/// A point in the history of the canvas.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum HistoryPoint {
+    /// Right after the revision with that number.
+    Revision(u64),
+    /// Right after the last revision made at or before that many milliseconds
+    /// since the Unix epoch.
+    Time(u64),
+}
+
+/// The ordered log of every operation made to the canvas.
+///
+/// Only the most recent revisions are kept, older ones are folded into `base`
+/// and `base_layers`, which are the entries and layers of the canvas as it was
+/// right after revision `base_revision`.
+#[derive(Encode, Decode, Debug, Clone, Default)]
+pub struct History {
+    pub base: Vec<CanvasEntry>,
+    pub base_layers: Vec<Layer>,
+    pub base_revision: u64,
+    pub revisions: Vec<Revision>,
+}
+
+impl Operation {
+    /// Applies the operation to the canvas.
+    pub fn apply(&self, canvas: &mut Canvas) {
+        match self {
+            Operation::Draw(entry) => {
+                canvas.delete_entry(entry.id);
+                canvas.entries.push(entry.clone());
+            }
+            Operation::Update { after, .. } => canvas.overwrite_entry(after.id, after.clone()),
+            Operation::Delete(entry) => canvas.delete_entry(entry.id),
+            Operation::Clear(entries) => {
+                canvas
+                    .entries
+                    .retain(|entry| entries.iter().all(|removed| removed.id != entry.id));
+            }
+            Operation::Restore(entries) => {
+                for entry in entries {
+                    canvas.delete_entry(entry.id);
+                    canvas.entries.push(entry.clone());
+                }
+            }
+            Operation::Replace { after, .. } => canvas.entries = after.clone(),
+            Operation::Batch(operations) => {
+                for operation in operations {
+                    operation.apply(canvas);
+                }
+            }
+            Operation::Layers { after, .. } => canvas.layers = after.clone(),
+        }
+    }
+
+    /// The operation moving an entry in the stacking order of its layer, or
+    /// `None` if it cannot move any further that way.
+    pub fn restack(canvas: &Canvas, id: EntryId, restack: Restack) -> Option<Operation> {
+        let layer = canvas.get_entry(id)?.layer;
+        let stack: Vec<&CanvasEntry> = canvas
+            .stacked()
+            .into_iter()
+            .filter(|entry| entry.layer == layer)
+            .collect();
+        let position = stack.iter().position(|entry| entry.id == id)?;
+        let entry = stack[position];
+        let (bottom, top) = (stack[0].z, stack[stack.len() - 1].z);
+
+        let move_to = |entry: &CanvasEntry, z: i64| Operation::Update {
+            before: entry.clone(),
+            after: CanvasEntry { z, ..entry.clone() },
+        };
+
+        // Swaps places with a neighbour, or only steps over it if they share a `z`
+        let swap = |other: &CanvasEntry, step: i64| {
+            if other.z == entry.z {
+                move_to(entry, other.z + step)
+            } else {
+                Operation::Batch(vec![move_to(entry, other.z), move_to(other, entry.z)])
+            }
+        };
+
+        match restack {
+            Restack::Raise => stack.get(position + 1).map(|other| swap(other, 1)),
+            Restack::Lower => position.checked_sub(1).map(|below| swap(stack[below], -1)),
+            Restack::ToFront => (position + 1 < stack.len()).then(|| move_to(entry, top + 1)),
+            Restack::ToBack => (position > 0).then(|| move_to(entry, bottom - 1)),
+        }
+    }
+
+    /// The operation changing every entry of a group as one, or `None` if
+    /// there is nothing to change.
+    pub fn group(canvas: &Canvas, change: &GroupChange) -> Option<Operation> {
+        let entries = match change {
+            GroupChange::Create(_, ids) => canvas
+                .entries
+                .iter()
+                .filter(|entry| ids.contains(&entry.id))
+                .collect(),
+            _ => canvas.group(change.group()),
+        };
+        if entries.is_empty() {
+            return None;
+        }
+
+        let change_entry = |entry: &CanvasEntry| {
+            let mut after = entry.clone();
+            match change {
+                GroupChange::Create(name, _) => after.group = Some(name.clone()),
+                GroupChange::Dissolve(_) => after.group = None,
+                GroupChange::Move(_, dx, dy) => after.element.translate(*dx, *dy),
+                GroupChange::Restyle(_, style) => after.element.set_style(style.clone()),
+                GroupChange::Delete(_) => unreachable!(),
+            }
+            after
+        };
+
+        if let GroupChange::Delete(_) = change {
+            return Some(Operation::Clear(entries.into_iter().cloned().collect()));
+        }
+
+        let updates: Vec<Operation> = entries
+            .into_iter()
+            .map(|entry| (entry, change_entry(entry)))
+            .filter(|(before, after)| *before != after)
+            .map(|(before, after)| Operation::Update {
+                before: before.clone(),
+                after,
+            })
+            .collect();
+
+        (!updates.is_empty()).then_some(Operation::Batch(updates))
+    }
+
+    /// The operation making the change to every entry with one of these ids,
+    /// or `None` if none of them exists or the change does nothing.
+    pub fn batch(canvas: &Canvas, ids: &[EntryId], change: &BatchChange) -> Option<Operation> {
+        let entries: Vec<&CanvasEntry> = canvas
+            .stacked()
+            .into_iter()
+            .filter(|entry| ids.contains(&entry.id))
+            .collect();
+        if entries.is_empty() {
+            return None;
+        }
+
+        let operations: Vec<Operation> = match change {
+            BatchChange::Delete => {
+                return Some(Operation::Clear(entries.into_iter().cloned().collect()))
+            }
+            BatchChange::Recolour(_) | BatchChange::Restyle(_) => entries
+                .into_iter()
+                .filter_map(|before| {
+                    let current = before.element.style();
+                    let style = match change {
+                        BatchChange::Recolour(colour) => Style {
+                            stroke_colour: *colour,
+                            fill_colour: current.fill_colour.map(|_| *colour),
+                            ..current.clone()
+                        },
+                        BatchChange::Restyle(style) => style.clone(),
+                        _ => unreachable!(),
+                    };
+
+                    let mut after = before.clone();
+                    after.element.set_style(style);
+                    (*before != after).then(|| Operation::Update {
+                        before: before.clone(),
+                        after,
+                    })
+                })
+                .collect(),
+            BatchChange::Restack(restack) => {
+                // Entries raised or sent to the back go first from the top, and
+                // the others first from the bottom, so that they keep their
+                // order among themselves
+                let mut ids: Vec<EntryId> = entries.iter().map(|entry| entry.id).collect();
+                if matches!(restack, Restack::Raise | Restack::ToBack) {
+                    ids.reverse();
+                }
+
+                let mut canvas = canvas.clone();
+                ids.into_iter()
+                    .filter_map(|id| {
+                        let operation = Operation::restack(&canvas, id, *restack)?;
+                        operation.apply(&mut canvas);
+                        Some(operation)
+                    })
+                    .collect()
+            }
+        };
+
+        (!operations.is_empty()).then_some(Operation::Batch(operations))
+    }
+
+    /// The operation transforming every entry with one of these ids, or `None`
+    /// if none of them exists or the transform changes nothing.
+    pub fn transform(canvas: &Canvas, ids: &[EntryId], transform: Transform) -> Option<Operation> {
+        let updates: Vec<Operation> = canvas
+            .entries
+            .iter()
+            .filter(|entry| ids.contains(&entry.id))
+            .filter_map(|before| {
+                let mut after = before.clone();
+                after.transform(transform);
+                (*before != after).then(|| Operation::Update {
+                    before: before.clone(),
+                    after,
+                })
+            })
+            .collect();
+
+        (!updates.is_empty()).then_some(Operation::Batch(updates))
+    }
+
+    /// The ids of the entries that are not in the state this operation expects
+    /// to start from, e.g. because someone else changed them in the meantime.
+    pub fn conflicts(&self, canvas: &Canvas) -> Vec<EntryId> {
+        let differs = |expected: &CanvasEntry| canvas.get_entry(expected.id) != Some(expected);
+
+        match self {
+            Operation::Draw(entry) => canvas
+                .get_entry(entry.id)
+                .map(|_| entry.id)
+                .into_iter()
+                .collect(),
+            Operation::Update { before, .. } => {
+                differs(before).then_some(before.id).into_iter().collect()
+            }
+            Operation::Delete(entry) => differs(entry).then_some(entry.id).into_iter().collect(),
+            Operation::Clear(entries) => entries
+                .iter()
+                .filter(|entry| differs(entry))
+                .map(|entry| entry.id)
+                .collect(),
+            Operation::Restore(entries) => entries
+                .iter()
+                .filter(|entry| canvas.get_entry(entry.id).is_some())
+                .map(|entry| entry.id)
+                .collect(),
+            Operation::Replace { before, .. } => {
+                let mut ids: Vec<EntryId> = before
+                    .iter()
+                    .filter(|entry| differs(entry))
+                    .map(|entry| entry.id)
+                    .collect();
+                ids.extend(
+                    canvas
+                        .entries
+                        .iter()
+                        .filter(|entry| before.iter().all(|expected| expected.id != entry.id))
+                        .map(|entry| entry.id),
+                );
+                ids
+            }
+            Operation::Batch(operations) => {
+                // Each operation expects the ones before it to have been applied
+                let mut canvas = canvas.clone();
+                let mut ids = Vec::new();
+                for operation in operations {
+                    ids.extend(operation.conflicts(&canvas));
+                    operation.apply(&mut canvas);
+                }
+                ids.sort();
+                ids.dedup();
+                ids
+            }
+            // No entry is changed
+            Operation::Layers { .. } => Vec::new(),
+        }
+    }
+
+    /// The ids of the entries this operation would add, change or remove on a
+    /// locked layer, be it the layer they are on or the one they would go on.
+    pub fn locked(&self, canvas: &Canvas) -> Vec<EntryId> {
+        let mut after = canvas.clone();
+        self.apply(&mut after);
+
+        let locked = |entry: &CanvasEntry| canvas.layer_of(entry).is_some_and(|layer| layer.locked);
+        let changed =
+            |entry: &CanvasEntry, other: &Canvas| other.get_entry(entry.id) != Some(entry);
+        let mut ids: Vec<EntryId> = canvas
+            .entries
+            .iter()
+            .filter(|entry| changed(entry, &after))
+            .chain(after.entries.iter().filter(|entry| changed(entry, canvas)))
+            .filter(|entry| locked(entry))
+            .map(|entry| entry.id)
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    /// Rewrites the operation so that it starts from the current state of the
+    /// canvas while still leading to the same entries, so that forcing it
+    /// through over a conflict records what actually changed. Returns `None`
+    /// if there is nothing left to change.
+    pub fn rebase(&self, canvas: &Canvas) -> Option<Operation> {
+        let current = |id: EntryId| canvas.get_entry(id).cloned();
+
+        match self {
+            Operation::Draw(entry) | Operation::Update { after: entry, .. } => {
+                Some(match current(entry.id) {
+                    Some(before) if before == *entry => return None,
+                    Some(before) => Operation::Update {
+                        before,
+                        after: entry.clone(),
+                    },
+                    None => Operation::Draw(entry.clone()),
+                })
+            }
+            Operation::Delete(entry) => current(entry.id).map(Operation::Delete),
+            Operation::Clear(entries) => {
+                let existing: Vec<CanvasEntry> = entries
+                    .iter()
+                    .filter_map(|entry| current(entry.id))
+                    .collect();
+                (!existing.is_empty()).then_some(Operation::Clear(existing))
+            }
+            Operation::Restore(_) | Operation::Replace { .. } => {
+                let mut after = canvas.clone();
+                self.apply(&mut after);
+                (after.entries != canvas.entries).then(|| Operation::Replace {
+                    before: canvas.entries.clone(),
+                    after: after.entries,
+                })
+            }
+            Operation::Batch(operations) => {
+                let mut canvas = canvas.clone();
+                let mut rebased = Vec::new();
+                for operation in operations {
+                    if let Some(operation) = operation.rebase(&canvas) {
+                        operation.apply(&mut canvas);
+                        rebased.push(operation);
+                    }
+                }
+                (!rebased.is_empty()).then_some(Operation::Batch(rebased))
+            }
+            Operation::Layers { after, .. } => {
+                (canvas.layers != *after).then(|| Operation::Layers {
+                    before: canvas.layers.clone(),
+                    after: after.clone(),
+                })
+            }
+        }
+    }
+
+    /// The operation that undoes this one.
+    pub fn inverse(&self) -> Operation {
+        match self {
+            Operation::Draw(entry) => Operation::Delete(entry.clone()),
+            Operation::Update { before, after } => Operation::Update {
+                before: after.clone(),
+                after: before.clone(),
+            },
+            Operation::Delete(entry) => Operation::Draw(entry.clone()),
+            Operation::Clear(entries) => Operation::Restore(entries.clone()),
+            Operation::Restore(entries) => Operation::Clear(entries.clone()),
+            Operation::Replace { before, after } => Operation::Replace {
+                before: after.clone(),
+                after: before.clone(),
+            },
+            Operation::Batch(operations) => {
+                Operation::Batch(operations.iter().rev().map(Operation::inverse).collect())
+            }
+            Operation::Layers { before, after } => Operation::Layers {
+                before: after.clone(),
+                after: before.clone(),
+            },
+        }
+    }
+}
+
+impl History {
+    /// Starts a history whose first revision will be made on top of the
+    /// entries and layers of `base`.
+    pub fn new(base: &Canvas) -> Self {
+        History {
+            base: base.entries.clone(),
+            base_layers: base.layers.clone(),
+            base_revision: 0,
+            revisions: Vec::new(),
+        }
+    }
+
+    /// The number of the most recent revision.
+    pub fn latest(&self) -> u64 {
+        self.revisions
+            .last()
+            .map_or(self.base_revision, |revision| revision.number)
+    }
+
+    /// Appends an operation to the history, returning its revision.
+    pub fn record(&mut self, author: &str, timestamp: u64, operation: Operation) -> &Revision {
+        let revision = Revision {
+            number: self.latest() + 1,
+            timestamp,
+            author: author.to_string(),
+            operation,
+        };
+        self.revisions.push(revision);
+        self.revisions.last().unwrap()
+    }
+
+    /// Forgets every revision, continuing the numbering on top of a new base.
+    pub fn restart(&mut self, base: &Canvas) {
+        self.base_revision = self.latest();
+        self.base = base.entries.clone();
+        self.base_layers = base.layers.clone();
+        self.revisions.clear();
+    }
+
+    /// Folds the oldest revisions into the base until at most `max` are left.
+    pub fn trim(&mut self, max: usize) {
+        let excess = self.revisions.len().saturating_sub(max);
+        if excess == 0 {
+            return;
+        }
+
+        let mut canvas = self.base_canvas();
+        for revision in self.revisions.drain(..excess) {
+            revision.operation.apply(&mut canvas);
+            self.base_revision = revision.number;
+        }
+        self.base = canvas.entries;
+        self.base_layers = canvas.layers;
+    }
+
+    /// Resolves a point in time to the number of the revision it falls on,
+    /// clamped to the revisions still kept.
+    pub fn resolve(&self, point: HistoryPoint) -> u64 {
+        match point {
+            HistoryPoint::Revision(number) => number.clamp(self.base_revision, self.latest()),
+            HistoryPoint::Time(timestamp) => self
+                .revisions
+                .iter()
+                .take_while(|revision| revision.timestamp <= timestamp)
+                .last()
+                .map_or(self.base_revision, |revision| revision.number),
+        }
+    }
+
+    /// Rebuilds the canvas as it was right after the given revision.
+    pub fn canvas_at(&self, number: u64) -> Canvas {
+        let mut canvas = self.base_canvas();
+        for revision in self
+            .revisions
+            .iter()
+            .take_while(|revision| revision.number <= number)
+        {
+            revision.operation.apply(&mut canvas);
+        }
+        canvas
+    }
+
+    fn base_canvas(&self) -> Canvas {
+        Canvas {
+            entries: self.base.clone(),
+            layers: self.base_layers.clone(),
+            ..Canvas::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::canvas::{CanvasElement, LayerChange};
+
+    fn circle(radius: u16) -> CanvasElement {
+        CanvasElement::Circle {
+            x: 10,
+            y: 10,
+            radius,
+            style: Style::filled([0, 0, 0, 255]),
+        }
+    }
+
+    /// A canvas along with its history, recording every operation made to it
+    /// and the canvas right after, the way the server does.
+    struct Recorder {
+        canvas: Canvas,
+        history: History,
+        after: Vec<Canvas>,
+    }
+
+    impl Recorder {
+        fn new() -> Self {
+            let canvas = Canvas::new();
+            Recorder {
+                history: History::new(&canvas),
+                after: vec![canvas.clone()],
+                canvas,
+            }
+        }
+
+        fn record(&mut self, operation: Operation) {
+            operation.apply(&mut self.canvas);
+            let timestamp = 10 * (self.history.latest() + 1);
+            self.history.record("alice", timestamp, operation);
+            self.after.push(self.canvas.clone());
+        }
+
+        fn draw(&mut self, radius: u16) -> CanvasEntry {
+            let entry = self.canvas.add_action("alice".to_string(), &circle(radius));
+            self.canvas.delete_entry(entry.id);
+            self.record(Operation::Draw(entry.clone()));
+            entry
+        }
+    }
+
+    /// Whether both canvases have the same entries, in whatever order, and
+    /// the same layers.
+    fn same(canvas: &Canvas, expected: &Canvas) -> bool {
+        let sorted = |canvas: &Canvas| {
+            let mut entries = canvas.entries.clone();
+            entries.sort_by_key(|entry| entry.id);
+            entries
+        };
+        sorted(canvas) == sorted(expected) && canvas.layers == expected.layers
+    }
+
+    #[test]
+    fn every_operation_is_replayed() {
+        let mut recorder = Recorder::new();
+
+        let first = recorder.draw(1);
+        let second = recorder.draw(2);
+        let mut updated = first.clone();
+        updated.element = circle(3);
+        recorder.record(Operation::Update {
+            before: first.clone(),
+            after: updated.clone(),
+        });
+        recorder.record(Operation::Clear(vec![second.clone()]));
+        recorder.record(Operation::Restore(vec![second.clone()]));
+        let mut layers = recorder.canvas.clone();
+        layers.change_layer(LayerChange::Create("sketch".to_string()), "alice");
+        recorder.record(Operation::Layers {
+            before: recorder.canvas.layers.clone(),
+            after: layers.layers,
+        });
+        let third = recorder.draw(4);
+        recorder.record(Operation::Replace {
+            before: recorder.canvas.entries.clone(),
+            after: vec![third.clone()],
+        });
+        recorder.record(Operation::Batch(vec![
+            Operation::Delete(third),
+            Operation::Draw(updated),
+        ]));
+
+        for (number, expected) in recorder.after.iter().enumerate() {
+            let canvas = recorder.history.canvas_at(number as u64);
+            assert!(same(&canvas, expected), "revision {number}");
+        }
+        assert_eq!(recorder.after.last().unwrap().layers.len(), 2);
+    }
+
+    #[test]
+    fn trimming_folds_the_oldest_revisions_into_the_base() {
+        let mut recorder = Recorder::new();
+        for radius in 1..=5 {
+            recorder.draw(radius);
+        }
+
+        recorder.history.trim(10);
+        assert_eq!(recorder.history.revisions.len(), 5);
+        assert_eq!(recorder.history.base_revision, 0);
+
+        recorder.history.trim(2);
+        assert_eq!(recorder.history.revisions.len(), 2);
+        assert_eq!(recorder.history.base_revision, 3);
+        assert_eq!(recorder.history.base.len(), 3);
+        assert_eq!(recorder.history.latest(), 5);
+
+        // Revisions still kept are rebuilt the same, older ones not at all
+        for number in 3..=5 {
+            let canvas = recorder.history.canvas_at(number);
+            assert!(same(&canvas, &recorder.after[number as usize]));
+        }
+        assert!(same(&recorder.history.canvas_at(1), &recorder.after[3]));
+    }
+
+    #[test]
+    fn points_are_resolved_within_the_revisions_kept() {
+        let mut recorder = Recorder::new();
+        // Revision n is made at 10n milliseconds
+        for radius in 1..=5 {
+            recorder.draw(radius);
+        }
+        recorder.history.trim(3);
+        let history = &recorder.history;
+
+        assert_eq!(history.resolve(HistoryPoint::Revision(0)), 2);
+        assert_eq!(history.resolve(HistoryPoint::Revision(4)), 4);
+        assert_eq!(history.resolve(HistoryPoint::Revision(100)), 5);
+
+        assert_eq!(history.resolve(HistoryPoint::Time(0)), 2);
+        assert_eq!(history.resolve(HistoryPoint::Time(10)), 2);
+        assert_eq!(history.resolve(HistoryPoint::Time(35)), 3);
+        assert_eq!(history.resolve(HistoryPoint::Time(40)), 4);
+        assert_eq!(history.resolve(HistoryPoint::Time(u64::MAX)), 5);
+    }
+
+    #[test]
+    fn restarting_keeps_the_numbering() {
+        let mut recorder = Recorder::new();
+        recorder.draw(1);
+        recorder.draw(2);
+
+        let mut base = recorder.canvas.clone();
+        base.entries.clear();
+        base.change_layer(LayerChange::Create("sketch".to_string()), "alice");
+        recorder.history.restart(&base);
+
+        assert!(recorder.history.revisions.is_empty());
+        assert_eq!(recorder.history.base_revision, 2);
+        assert_eq!(recorder.history.latest(), 2);
+        assert!(same(&recorder.history.canvas_at(2), &base));
+
+        recorder.canvas = base;
+        recorder.draw(3);
+        assert_eq!(recorder.history.latest(), 3);
+        assert_eq!(recorder.history.canvas_at(3).entries.len(), 1);
+    }
+}
@@ -1,6 +1,13 @@
 use crate::{
     errors::Result,
-    models::canvas::{CanvasElement, CanvasEntry},
+    models::{
+        blob::BlobId,
+        canvas::{
+            BatchChange, CanvasElement, CanvasEntry, EntryId, GroupChange, Layer, LayerChange,
+            LayerId, Point, Restack, Style, Transform,
+        },
+        history::{History, HistoryPoint},
+    },
 };
 
 use bincode::{config, Decode, Encode};
@@ -17,22 +24,89 @@ pub enum TcpPacket {
     DrawResponse(CanvasEntry),
     /// Sent by the client to the server when the user wants to delete an element from the canvas.
     /// Also sent by the server to the clients as an update to the canvas.
-    Delete(usize),
+    Delete(EntryId),
     /// Sent by the client to the server when the user wants to clear the canvas.
     /// The boolean is true if the client requested for a full clear
     ClearRequest { only_owned: bool },
     /// Sent by the server to the clients when the server wants to clear the canvas.
-    ClearResponse { ids_to_delete: Vec<usize> },
+    ClearResponse { ids_to_delete: Vec<EntryId> },
     /// Sent by the client to the server when the user wants to update an entry on the canvas.
-    UpdateRequest(usize, CanvasElement),
+    UpdateRequest(EntryId, CanvasElement),
     /// Sent by the server to the clients when the server wants to update a specific entry on the canvas.
-    UpdateResponse(usize, CanvasEntry),
+    UpdateResponse(EntryId, CanvasEntry),
     /// Sent by the server to the clients when the server wants to load the entire canvas at the beginning.
     LoadCanvas(Vec<CanvasEntry>),
     /// Sent by the server to the client when the server wants to notify the client of something.
     Notification(String),
     /// Sent by the client to the server when the user wants to undo an action.
     Undo,
+    /// Sent by the server to the clients right before it shuts down, along with the reason.
+    ServerShutdown(String),
+    /// Sent by the server to a client whose request was refused, e.g. because it was throttled.
+    Error(String),
+    /// Sent by the client to the server when the user wants to play back the history of the canvas.
+    HistoryRequest,
+    /// Sent by the server to a client with the most recent revisions it keeps, as many as fit in a packet.
+    HistoryResponse(History),
+    /// Sent by the client to the server when the user wants to see the canvas as it was at some point.
+    CanvasAtRequest(HistoryPoint),
+    /// Sent by the server to a client with the canvas right after the given revision.
+    CanvasAtResponse {
+        revision: u64,
+        entries: Vec<CanvasEntry>,
+        layers: Vec<Layer>,
+    },
+    /// Sent by an admin to the server to undo the last operations in the room, whoever made them.
+    AdminUndo(u64),
+    /// Sent by an admin to the server to bring the whole canvas back to a revision.
+    AdminRevert(u64),
+    /// Sent by the client to the server when the user wants to move an entry up or down the stack.
+    /// The server answers every client with an [TcpPacket::UpdateResponse] per entry that moved.
+    RestackRequest(EntryId, Restack),
+    /// Sent by the client to the server when the user wants to create, rename, hide, lock or move a layer.
+    LayerRequest(LayerChange),
+    /// Sent by the server to the clients with every layer, from the bottom one to the top one,
+    /// when joining and whenever they change.
+    LayerResponse(Vec<Layer>),
+    /// Sent by the client to the server to choose the layer the user draws on.
+    SelectLayer(LayerId),
+    /// Sent by the client to the server while the user is drawing a stroke, with the points
+    /// added since the last one. The stroke itself is sent with a [TcpPacket::DrawRequest].
+    LiveStrokeRequest { points: Vec<Point>, style: Style },
+    /// Sent by the server to the other clients with the points added to the stroke `author` is
+    /// drawing, to be shown until the stroke ends.
+    LiveStrokeResponse {
+        author: String,
+        points: Vec<Point>,
+        style: Style,
+    },
+    /// Sent by the server to the clients once the stroke the user was drawing is drawn for good,
+    /// refused, or abandoned because they left.
+    LiveStrokeEnd(String),
+    /// Part of a blob, sent in order by the client to upload it and by the server in response to
+    /// a [TcpPacket::BlobRequest]. `size` is the size of the whole blob and `offset` where in it
+    /// `data` starts, the blob being checked against `id` once every chunk arrived.
+    BlobChunk {
+        id: BlobId,
+        size: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Sent by the server to the client once the blob it uploaded was checked and stored.
+    BlobStored(BlobId),
+    /// Sent by the client to the server to download a blob, e.g. the content of an image.
+    BlobRequest(BlobId),
+    /// Sent by the client to the server to change every entry of a group at once, undone as one
+    /// action. Changed entries are sent back as [TcpPacket::UpdateResponse]s.
+    GroupRequest(GroupChange),
+    /// Sent by the client to the server to move, scale or rotate entries, undone as one action.
+    TransformRequest(Vec<EntryId>, Transform),
+    /// Sent by the server to all clients when entries were transformed, so that they transform
+    /// their copies the same way rather than receiving every element again.
+    TransformResponse(Vec<EntryId>, Transform),
+    /// Sent by the client to the server to make the same change to several entries, e.g. the
+    /// ones selected, undone as one action.
+    BatchRequest(Vec<EntryId>, BatchChange),
 }
 
 impl TcpPacket {
@@ -57,4 +131,44 @@ impl TcpPacket {
     pub fn try_from_bytes(bytes: &[u8]) -> Result<Self> {
         Ok(bincode::decode_from_slice(bytes, config::standard())?.0)
     }
+
+    /// The name of the packet variant, without its payload.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TcpPacket::Connect(_) => "Connect",
+            TcpPacket::Disconnect => "Disconnect",
+            TcpPacket::DrawRequest(_) => "DrawRequest",
+            TcpPacket::DrawResponse(_) => "DrawResponse",
+            TcpPacket::Delete(_) => "Delete",
+            TcpPacket::ClearRequest { .. } => "ClearRequest",
+            TcpPacket::ClearResponse { .. } => "ClearResponse",
+            TcpPacket::UpdateRequest(_, _) => "UpdateRequest",
+            TcpPacket::UpdateResponse(_, _) => "UpdateResponse",
+            TcpPacket::LoadCanvas(_) => "LoadCanvas",
+            TcpPacket::Notification(_) => "Notification",
+            TcpPacket::Undo => "Undo",
+            TcpPacket::ServerShutdown(_) => "ServerShutdown",
+            TcpPacket::Error(_) => "Error",
+            TcpPacket::HistoryRequest => "HistoryRequest",
+            TcpPacket::HistoryResponse(_) => "HistoryResponse",
+            TcpPacket::CanvasAtRequest(_) => "CanvasAtRequest",
+            TcpPacket::CanvasAtResponse { .. } => "CanvasAtResponse",
+            TcpPacket::AdminUndo(_) => "AdminUndo",
+            TcpPacket::AdminRevert(_) => "AdminRevert",
+            TcpPacket::RestackRequest(_, _) => "RestackRequest",
+            TcpPacket::LayerRequest(_) => "LayerRequest",
+            TcpPacket::LayerResponse(_) => "LayerResponse",
+            TcpPacket::SelectLayer(_) => "SelectLayer",
+            TcpPacket::LiveStrokeRequest { .. } => "LiveStrokeRequest",
+            TcpPacket::LiveStrokeResponse { .. } => "LiveStrokeResponse",
+            TcpPacket::LiveStrokeEnd(_) => "LiveStrokeEnd",
+            TcpPacket::BlobChunk { .. } => "BlobChunk",
+            TcpPacket::BlobStored(_) => "BlobStored",
+            TcpPacket::BlobRequest(_) => "BlobRequest",
+            TcpPacket::GroupRequest(_) => "GroupRequest",
+            TcpPacket::TransformRequest(_, _) => "TransformRequest",
+            TcpPacket::TransformResponse(_, _) => "TransformResponse",
+            TcpPacket::BatchRequest(_, _) => "BatchRequest",
+        }
+    }
 }
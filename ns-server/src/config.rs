@@ -0,0 +1,688 @@
+use std::{
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use ns_core::errors::{Result, ServerError};
+use ns_core::models::blob::{max_chunk_packet_size, BLOB_CHUNK_SIZE};
+use serde::Deserialize;
+use tracing_subscriber::filter::{Directive, LevelFilter};
+
+use crate::Args;
+
+/// The full server configuration, read from a TOML file and then overridden by
+/// any command line arguments.
+///
+/// Every section and every key is optional, falling back to the defaults below.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The name of the canvas hosted by this server, attached to the logs
+    pub room: String,
+    pub network: NetworkConfig,
+    pub timeouts: TimeoutConfig,
+    pub history: HistoryConfig,
+    pub limits: LimitsConfig,
+    pub logging: LoggingConfig,
+    pub persistence: PersistenceConfig,
+    pub http: HttpConfig,
+    pub audit: AuditConfig,
+    pub admin: AdminConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// The address the server listens on
+    pub address: String,
+    /// The port the server listens on
+    pub port: u16,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// How long a client can stay silent before being disconnected
+    pub read_timeout_secs: u64,
+    /// How long to wait for the connection threads when shutting down
+    pub shutdown_grace_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// How long a user can be away before their undo history is dropped
+    pub expiry_secs: u64,
+    /// The maximum number of actions kept per user, unbounded if missing
+    pub max_actions: Option<usize>,
+    /// The number of revisions of the canvas kept for playback
+    pub max_revisions: usize,
+    /// What to do when undoing an action whose entries someone else changed since
+    pub undo_conflict: UndoConflict,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UndoConflict {
+    /// Drop the action and tell the user nothing was undone
+    Skip,
+    /// Undo the action anyway, overwriting the other changes
+    Force,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// The largest packet, in bytes, the server accepts from a client
+    pub max_packet_size: u32,
+    /// The maximum number of users connected at the same time
+    pub max_sessions: usize,
+    /// How many canvas mutations per second a session can sustain
+    pub mutations_per_sec: f64,
+    /// How many canvas mutations a session can send in a single burst
+    pub mutation_burst: u32,
+    /// The maximum number of entries a user can have on the canvas, unbounded if missing
+    pub max_entries_per_user: Option<usize>,
+    /// How many times per second the stroke a user is drawing is passed on to the others
+    pub live_strokes_per_sec: f64,
+    /// The largest blob, in bytes, a client can upload, e.g. an image
+    pub max_blob_size: u64,
+    /// The most bytes all the blobs stored can take together, unbounded if missing
+    pub max_blob_storage: Option<u64>,
+    /// How many live stroke and blob chunk packets per second a session can sustain
+    pub streamed_per_sec: f64,
+    /// How many live stroke and blob chunk packets a session can send in a single burst
+    pub streamed_burst: u32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Either a level (`trace`, `debug`, `info`, `warn`, `error` or `off`) or a
+    /// list of `target=level` directives, ignored if `RUST_LOG` is set
+    pub level: String,
+    /// How each line is formatted
+    pub format: LogFormat,
+    /// Where the log files are written, only logging to stdout if missing
+    pub directory: Option<PathBuf>,
+    /// The name of the log files, suffixed by the date when rotating
+    pub file_prefix: String,
+    /// How often a new log file is started
+    pub rotation: LogRotation,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// Where the canvas is saved, persistence is disabled if missing
+    pub snapshot_path: Option<PathBuf>,
+    /// How often the canvas is saved to disk
+    pub snapshot_interval_secs: u64,
+    /// Where uploaded blobs are stored, in a `blobs` directory next to the
+    /// snapshot if missing, or only in memory if persistence is disabled too
+    pub blob_directory: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Where `/metrics`, `/healthz` and `/readyz` are served, disabled if missing
+    pub address: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Where every canvas mutation is appended as a JSON line, disabled if missing
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// The users allowed to undo and revert the whole room
+    pub users: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            room: "default".to_string(),
+            network: NetworkConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            history: HistoryConfig::default(),
+            limits: LimitsConfig::default(),
+            logging: LoggingConfig::default(),
+            persistence: PersistenceConfig::default(),
+            http: HttpConfig::default(),
+            audit: AuditConfig::default(),
+            admin: AdminConfig::default(),
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            address: "127.0.0.1".to_string(),
+            port: 6666,
+        }
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            read_timeout_secs: 600,
+            shutdown_grace_secs: 5,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            expiry_secs: 60,
+            max_actions: None,
+            max_revisions: 10_000,
+            undo_conflict: UndoConflict::Skip,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_packet_size: 1024 * 1024,
+            max_sessions: 64,
+            mutations_per_sec: 20.0,
+            mutation_burst: 40,
+            max_entries_per_user: None,
+            live_strokes_per_sec: 20.0,
+            max_blob_size: 8 * 1024 * 1024,
+            max_blob_storage: None,
+            streamed_per_sec: 200.0,
+            streamed_burst: 256,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            directory: None,
+            file_prefix: "netsketch-server.log".to_string(),
+            rotation: LogRotation::Daily,
+        }
+    }
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        PersistenceConfig {
+            snapshot_path: None,
+            snapshot_interval_secs: 30,
+            blob_directory: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds the configuration from the file given on the command line, if
+    /// any, applies the command line overrides and validates the result.
+    pub fn load(args: &Args) -> Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => ServerConfig::default(),
+        };
+
+        if let Some(address) = &args.address {
+            config.network.address = address.clone();
+        }
+        if let Some(port) = args.port {
+            config.network.port = port;
+        }
+        if let Some(level) = &args.log_level {
+            config.logging.level = level.clone();
+        }
+        if let Some(snapshot_path) = &args.snapshot_path {
+            config.persistence.snapshot_path = Some(snapshot_path.clone());
+        }
+        if let Some(http_address) = &args.http_address {
+            config.http.address = Some(http_address.clone());
+        }
+        if let Some(audit_log) = &args.audit_log {
+            config.audit.path = Some(audit_log.clone());
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            ServerError::InvalidConfig(format!("Could not read {}: {}", path.display(), e))
+        })?;
+
+        toml::from_str(&contents)
+            .map_err(|e| ServerError::InvalidConfig(format!("{}: {}", path.display(), e)).into())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Err(ServerError::InvalidConfig(message).into());
+
+        let bind_address = format!("{}:{}", self.network.address, self.network.port);
+        if bind_address.to_socket_addrs().is_err() {
+            return invalid(format!(
+                "network.address: cannot resolve {}",
+                self.network.address
+            ));
+        }
+
+        if self.timeouts.read_timeout_secs == 0 {
+            return invalid("timeouts.read_timeout_secs must be greater than 0".to_string());
+        }
+
+        if self.history.max_actions == Some(0) {
+            return invalid("history.max_actions must be greater than 0".to_string());
+        }
+
+        if self.history.max_revisions == 0 {
+            return invalid("history.max_revisions must be greater than 0".to_string());
+        }
+
+        // Blobs are uploaded in chunks, which must get through
+        let chunk_packet_size = max_chunk_packet_size();
+        if (self.limits.max_packet_size as usize) < chunk_packet_size {
+            return invalid(format!(
+                "limits.max_packet_size must be at least {} to receive blobs",
+                chunk_packet_size
+            ));
+        }
+
+        if self.limits.max_sessions == 0 {
+            return invalid("limits.max_sessions must be greater than 0".to_string());
+        }
+
+        if !(self.limits.mutations_per_sec > 0.0 && self.limits.mutations_per_sec.is_finite()) {
+            return invalid("limits.mutations_per_sec must be a positive number".to_string());
+        }
+
+        if self.limits.mutation_burst == 0 {
+            return invalid("limits.mutation_burst must be greater than 0".to_string());
+        }
+
+        if !(self.limits.live_strokes_per_sec > 0.0 && self.limits.live_strokes_per_sec.is_finite())
+        {
+            return invalid("limits.live_strokes_per_sec must be a positive number".to_string());
+        }
+
+        if self.limits.max_blob_size == 0 {
+            return invalid("limits.max_blob_size must be greater than 0".to_string());
+        }
+
+        if self.limits.max_blob_storage == Some(0) {
+            return invalid("limits.max_blob_storage must be greater than 0".to_string());
+        }
+
+        if !(self.limits.streamed_per_sec > 0.0 && self.limits.streamed_per_sec.is_finite()) {
+            return invalid("limits.streamed_per_sec must be a positive number".to_string());
+        }
+
+        // The largest blob must be able to be uploaded in one go
+        let max_blob_chunks = self.limits.max_blob_size.div_ceil(BLOB_CHUNK_SIZE as u64);
+        if u64::from(self.limits.streamed_burst) < max_blob_chunks {
+            return invalid(format!(
+                "limits.streamed_burst must be at least {} to receive blobs of limits.max_blob_size",
+                max_blob_chunks
+            ));
+        }
+
+        if self.limits.max_entries_per_user == Some(0) {
+            return invalid("limits.max_entries_per_user must be greater than 0".to_string());
+        }
+
+        let valid_directive = |directive: &str| match directive.split_once('=') {
+            Some(_) => directive.parse::<Directive>().is_ok(),
+            None => LevelFilter::from_str(directive).is_ok(),
+        };
+        if !self
+            .logging
+            .level
+            .split(',')
+            .map(str::trim)
+            .all(valid_directive)
+        {
+            return invalid(format!(
+                "logging.level: invalid filter {:?}, expected a level (trace, debug, info, warn, error, off) or target=level directives",
+                self.logging.level
+            ));
+        }
+
+        if let Some(directory) = &self.logging.directory {
+            if !directory.is_dir() {
+                return invalid(format!(
+                    "logging.directory: {} does not exist",
+                    directory.display()
+                ));
+            }
+            if self.logging.file_prefix.is_empty() {
+                return invalid("logging.file_prefix must not be empty".to_string());
+            }
+        }
+
+        if let Some(snapshot_path) = &self.persistence.snapshot_path {
+            if self.persistence.snapshot_interval_secs == 0 {
+                return invalid(
+                    "persistence.snapshot_interval_secs must be greater than 0".to_string(),
+                );
+            }
+
+            let directory = parent_directory(snapshot_path);
+            if !directory.is_dir() {
+                return invalid(format!(
+                    "persistence.snapshot_path: directory {} does not exist",
+                    directory.display()
+                ));
+            }
+        }
+
+        if let Some(blob_directory) = &self.persistence.blob_directory {
+            if !parent_directory(blob_directory).is_dir() {
+                return invalid(format!(
+                    "persistence.blob_directory: directory {} does not exist",
+                    parent_directory(blob_directory).display()
+                ));
+            }
+        }
+
+        if let Some(audit_path) = &self.audit.path {
+            if !parent_directory(audit_path).is_dir() {
+                return invalid(format!(
+                    "audit.path: directory {} does not exist",
+                    parent_directory(audit_path).display()
+                ));
+            }
+        }
+
+        if let Some(http_address) = &self.http.address {
+            if http_address.to_socket_addrs().is_err() {
+                return invalid(format!(
+                    "http.address: cannot resolve {}, expected host:port",
+                    http_address
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.read_timeout_secs)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.timeouts.shutdown_grace_secs)
+    }
+
+    /// Where uploaded blobs are stored, if anywhere on disk.
+    pub fn blob_directory(&self) -> Option<PathBuf> {
+        match (
+            &self.persistence.blob_directory,
+            &self.persistence.snapshot_path,
+        ) {
+            (Some(directory), _) => Some(directory.clone()),
+            (None, Some(snapshot_path)) => Some(parent_directory(snapshot_path).join("blobs")),
+            (None, None) => None,
+        }
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admin.users.iter().any(|admin| admin == username)
+    }
+}
+
+/// The directory a file would be created in, `.` for bare file names.
+fn parent_directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    type Change<'a> = Box<dyn Fn(&mut ServerConfig) + 'a>;
+
+    /// Writes a configuration file unique to this test, returning its path.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "netsketch-config-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse_from(std::iter::once("netsketch-server").chain(args.iter().copied()))
+    }
+
+    fn error(result: Result<ServerConfig>) -> String {
+        match result {
+            Ok(config) => panic!("expected an error, got {:?}", config),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        let config = ServerConfig::load(&args(&[])).unwrap();
+
+        assert_eq!(config.network.port, 6666);
+        assert_eq!(config.logging.level, "info");
+    }
+
+    #[test]
+    fn the_command_line_overrides_the_file() {
+        let path = config_file(
+            "overrides",
+            r#"
+                room = "studio"
+
+                [network]
+                address = "0.0.0.0"
+                port = 7000
+
+                [logging]
+                level = "debug"
+            "#,
+        );
+        let path = path.to_str().unwrap();
+
+        let config = ServerConfig::load(&args(&["--config", path, "--port", "8000"])).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // Set on the command line
+        assert_eq!(config.network.port, 8000);
+        // Set in the file only
+        assert_eq!(config.room, "studio");
+        assert_eq!(config.network.address, "0.0.0.0");
+        assert_eq!(config.logging.level, "debug");
+        // Set nowhere
+        assert_eq!(config.history.max_revisions, 10_000);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        for (name, contents) in [
+            ("unknown-key", "rooms = \"studio\""),
+            ("unknown-section", "[netwerk]\nport = 7000"),
+            ("unknown-nested-key", "[network]\nprot = 7000"),
+        ] {
+            let path = config_file(name, contents);
+            let result = ServerConfig::from_file(&path);
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(error(result).contains("unknown field"), "{}", contents);
+        }
+    }
+
+    #[test]
+    fn missing_files_are_reported() {
+        let path = std::env::temp_dir().join("netsketch-config-missing.toml");
+
+        assert!(error(ServerConfig::from_file(&path)).contains("Could not read"));
+    }
+
+    #[test]
+    fn invalid_configurations_are_rejected() {
+        let missing = PathBuf::from("/netsketch/missing/directory/file");
+        let cases: Vec<(&str, Change<'_>)> = vec![
+            (
+                "network.address",
+                Box::new(|c| c.network.address = "not an address".to_string()),
+            ),
+            (
+                "timeouts.read_timeout_secs",
+                Box::new(|c| c.timeouts.read_timeout_secs = 0),
+            ),
+            (
+                "history.max_actions",
+                Box::new(|c| c.history.max_actions = Some(0)),
+            ),
+            (
+                "history.max_revisions",
+                Box::new(|c| c.history.max_revisions = 0),
+            ),
+            (
+                "limits.max_packet_size",
+                Box::new(|c| c.limits.max_packet_size = max_chunk_packet_size() as u32 - 1),
+            ),
+            (
+                "limits.max_sessions",
+                Box::new(|c| c.limits.max_sessions = 0),
+            ),
+            (
+                "limits.mutations_per_sec",
+                Box::new(|c| c.limits.mutations_per_sec = 0.0),
+            ),
+            (
+                "limits.mutations_per_sec",
+                Box::new(|c| c.limits.mutations_per_sec = f64::NAN),
+            ),
+            (
+                "limits.mutation_burst",
+                Box::new(|c| c.limits.mutation_burst = 0),
+            ),
+            (
+                "limits.live_strokes_per_sec",
+                Box::new(|c| c.limits.live_strokes_per_sec = f64::INFINITY),
+            ),
+            (
+                "limits.max_blob_size",
+                Box::new(|c| c.limits.max_blob_size = 0),
+            ),
+            (
+                "limits.max_blob_storage",
+                Box::new(|c| c.limits.max_blob_storage = Some(0)),
+            ),
+            (
+                "limits.streamed_per_sec",
+                Box::new(|c| c.limits.streamed_per_sec = -1.0),
+            ),
+            (
+                "limits.streamed_burst",
+                Box::new(|c| c.limits.streamed_burst = 0),
+            ),
+            (
+                "limits.streamed_burst",
+                Box::new(|c| c.limits.max_blob_size = 1024 * 1024 * 1024),
+            ),
+            (
+                "limits.max_entries_per_user",
+                Box::new(|c| c.limits.max_entries_per_user = Some(0)),
+            ),
+            (
+                "logging.level",
+                Box::new(|c| c.logging.level = "loud".to_string()),
+            ),
+            (
+                "logging.directory",
+                Box::new(|c| c.logging.directory = Some(PathBuf::from("/netsketch/missing"))),
+            ),
+            (
+                "logging.file_prefix",
+                Box::new(|c| {
+                    c.logging.directory = Some(std::env::temp_dir());
+                    c.logging.file_prefix = String::new();
+                }),
+            ),
+            (
+                "persistence.snapshot_interval_secs",
+                Box::new(|c| {
+                    c.persistence.snapshot_path = Some(std::env::temp_dir().join("canvas"));
+                    c.persistence.snapshot_interval_secs = 0;
+                }),
+            ),
+            (
+                "persistence.snapshot_path",
+                Box::new(|c| c.persistence.snapshot_path = Some(missing.clone())),
+            ),
+            (
+                "persistence.blob_directory",
+                Box::new(|c| c.persistence.blob_directory = Some(missing.clone())),
+            ),
+            (
+                "audit.path",
+                Box::new(|c| c.audit.path = Some(missing.clone())),
+            ),
+            (
+                "http.address",
+                Box::new(|c| c.http.address = Some("8080".to_string())),
+            ),
+        ];
+
+        for (key, change) in cases {
+            let mut config = ServerConfig::default();
+            change(&mut config);
+
+            let message = error(config.validate().map(|_| config));
+            assert!(message.contains(key), "{}: {}", key, message);
+        }
+    }
+
+    #[test]
+    fn the_smallest_packet_size_holding_a_chunk_is_valid() {
+        let mut config = ServerConfig::default();
+        config.limits.max_packet_size = max_chunk_packet_size() as u32;
+
+        assert!(config.validate().is_ok());
+    }
+}
@@ -1,61 +1,244 @@
+mod config;
 mod models;
 mod operations;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::{
+    io::ErrorKind,
+    net::TcpListener,
+    path::PathBuf,
     process::exit,
-    sync::{Arc, Mutex},
-    thread::spawn,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{sleep, spawn},
+    time::Duration,
 };
-use tracing::error;
+use tracing::{error, info};
 
-use models::ServerState;
-use operations::{handle_client, init_server};
+use config::ServerConfig;
+use models::{AuditLog, BlobStore, Metrics, ServerState};
+use operations::{
+    init_server, load_snapshot, lock_state, query_audit_log, save_snapshot, serve_connection,
+    serve_http, shutdown_server, AuditFilter, Connection, HttpContext,
+};
 
 #[derive(Parser)]
 struct Args {
-    /// The address of the netsketch server
+    /// Path to a TOML configuration file
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+    /// The address of the netsketch server, overrides the configuration file
     #[clap(short, long)]
-    address: String,
-    /// The port of the netsketch server
+    address: Option<String>,
+    /// The port of the netsketch server, overrides the configuration file
     #[clap(short, long)]
-    port: u16,
+    port: Option<u16>,
+    /// The log level, overrides the configuration file
+    #[clap(long)]
+    log_level: Option<String>,
+    /// Where to save the canvas, overrides the configuration file
+    #[clap(long)]
+    snapshot_path: Option<PathBuf>,
+    /// Where to serve the monitoring endpoints, overrides the configuration file
+    #[clap(long)]
+    http_address: Option<String>,
+    /// Where to record every canvas mutation, overrides the configuration file
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the audit log records matching the filters instead of starting the server
+    Audit {
+        /// Only print what this user did
+        #[clap(long)]
+        user: Option<String>,
+        /// Only print records from this time onwards, e.g. 2024-04-01T09:00:00Z
+        #[clap(long)]
+        since: Option<String>,
+        /// Only print records up to this time, e.g. 2024-04-01T17:00:00Z
+        #[clap(long)]
+        until: Option<String>,
+    },
 }
 
 fn main() {
-    let tcp_listener = match init_server(Args::parse()) {
+    let args = Args::parse();
+
+    let config = match ServerConfig::load(&args) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{e}");
+            exit(1);
+        }
+    };
+
+    if let Some(Command::Audit { user, since, until }) = args.command {
+        let Some(path) = &config.audit.path else {
+            eprintln!("No audit log configured, pass --audit-log or set audit.path");
+            exit(1);
+        };
+
+        let result = AuditFilter::parse(user, since.as_deref(), until.as_deref())
+            .and_then(|filter| query_audit_log(path, &filter, &mut std::io::stdout().lock()));
+        if let Err(e) = result {
+            eprintln!("{e}");
+            exit(1);
+        }
+        return;
+    }
+
+    let tcp_listener = match init_server(&config) {
         Ok(server_state) => server_state,
         Err(e) => {
             error!("{e}");
             exit(1);
         }
     };
+    let listener_bound = Arc::new(AtomicBool::new(true));
 
-    let server_state = Arc::new(Mutex::new(ServerState::new()));
+    let mut server_state = match &config.persistence.snapshot_path {
+        Some(path) => match load_snapshot(path) {
+            Ok(Some(canvas)) => {
+                info!(
+                    "Restored {} entries from {}",
+                    canvas.entries.len(),
+                    path.display()
+                );
+                ServerState::from_canvas(canvas)
+            }
+            Ok(None) => ServerState::new(),
+            Err(e) => {
+                error!("Failed to load {}: {e}", path.display());
+                exit(1);
+            }
+        },
+        None => ServerState::new(),
+    };
 
-    for stream in tcp_listener.incoming() {
+    if let Some(path) = &config.audit.path {
+        match AuditLog::open(path) {
+            Ok(audit_log) => server_state.audit_log = Some(audit_log),
+            Err(e) => {
+                error!("Failed to open the audit log {}: {e}", path.display());
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(directory) = config.blob_directory() {
+        match BlobStore::open(&directory) {
+            Ok(blobs) => server_state.blobs = Arc::new(blobs),
+            Err(e) => {
+                error!(
+                    "Failed to open the blob directory {}: {e}",
+                    directory.display()
+                );
+                exit(1);
+            }
+        }
+    }
+    let server_state = Arc::new(Mutex::new(server_state));
+    let metrics = Arc::new(Metrics::new());
+
+    if let Some(http_address) = &config.http.address {
+        match TcpListener::bind(http_address) {
+            Ok(http_listener) => {
+                let context = HttpContext {
+                    server_state: server_state.clone(),
+                    metrics: metrics.clone(),
+                    listener_bound: listener_bound.clone(),
+                    snapshot_path: config.persistence.snapshot_path.clone(),
+                };
+                spawn(move || serve_http(http_listener, context));
+            }
+            Err(e) => {
+                error!("Failed to bind the HTTP server to {http_address}: {e}");
+                exit(1);
+            }
+        }
+    }
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    {
+        let shutdown = shutdown.clone();
+        if let Err(e) = ctrlc::set_handler(move || shutdown.store(true, Ordering::SeqCst)) {
+            error!("Failed to install the signal handler: {e}");
+            exit(1);
+        }
+    }
+
+    // Polling lets the accept loop notice the shutdown flag
+    if let Err(e) = tcp_listener.set_nonblocking(true) {
+        error!("{e}");
+        exit(1);
+    }
+
+    if let Some(path) = config.persistence.snapshot_path.clone() {
         let server_state = server_state.clone();
-        match stream {
-            Ok(stream) => {
-                spawn(move || loop {
-                    if handle_client(stream.try_clone().unwrap(), server_state.clone()).is_err() {
-                        match server_state.lock() {
-                            Ok(mut server_state) => {
-                                if let Err(e) = server_state.disconnect_user(stream) {
-                                    error!("Failed to disconnect: {e}");
-                                }
-                                break;
-                            }
-                            Err(_) => {
-                                error!("Failed to lock server state");
-                            }
-                        }
+        let interval = Duration::from_secs(config.persistence.snapshot_interval_secs);
+        spawn(move || loop {
+            sleep(interval);
+            let canvas = lock_state(&server_state, Some(&path)).canvas.clone();
+            if let Err(e) = save_snapshot(&canvas, &path) {
+                error!("Failed to save {}: {e}", path.display());
+            }
+        });
+    }
+
+    let mut connections: Vec<Connection> = Vec::new();
+
+    while !shutdown.load(Ordering::SeqCst) {
+        match tcp_listener.accept() {
+            Ok((stream, _)) => {
+                let (stream, connection_stream) = match stream
+                    .set_nonblocking(false)
+                    .and_then(|_| stream.try_clone())
+                {
+                    Ok(connection_stream) => (stream, connection_stream),
+                    Err(e) => {
+                        error!("{e}", e = e.kind());
+                        continue;
                     }
+                };
+
+                let server_state = server_state.clone();
+                let shutdown = shutdown.clone();
+                let config = config.clone();
+                let metrics = metrics.clone();
+                let handle = spawn(move || {
+                    serve_connection(stream, server_state, &config, &metrics, &shutdown)
                 });
+
+                connections.push(Connection {
+                    stream: connection_stream,
+                    handle,
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                sleep(Duration::from_millis(100));
             }
             Err(e) => {
                 error!("{e}", e = e.kind());
             }
         }
+
+        connections.retain(|connection| !connection.handle.is_finished());
     }
+
+    // Stop accepting new connections before telling everyone we're leaving
+    drop(tcp_listener);
+    listener_bound.store(false, Ordering::SeqCst);
+
+    shutdown_server(
+        &server_state,
+        connections,
+        "The server is shutting down",
+        &config,
+    );
 }
@@ -1,7 +1,13 @@
+mod audit;
+mod blob_store;
+mod metrics;
+mod rate_limiter;
 mod server_state;
 mod session;
 mod user_data;
 
+pub use audit::{AuditContext, AuditLog, AuditOperation, AuditRecord};
+pub use blob_store::BlobStore;
+pub use metrics::{Gauges, Metrics};
 pub use server_state::ServerState;
-pub use user_data::Action;
 pub use user_data::UserData;
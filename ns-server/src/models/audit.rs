@@ -0,0 +1,154 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+};
+
+use ns_core::errors::Result;
+use ns_core::models::canvas::{CanvasElement, CanvasEntry, EntryId, SERVER_SITE};
+use serde::{Deserialize, Deserializer, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// The kind of mutation an [AuditRecord] describes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    Draw,
+    Update,
+    Delete,
+    Clear,
+    Undo,
+    /// An entry was moved up or down the stack
+    Restack,
+    /// An admin brought the whole room back to an earlier revision
+    Revert,
+    /// A group of entries was changed at once
+    Group,
+    /// An entry was moved, scaled or rotated
+    Transform,
+    /// Several entries were changed at once, e.g. the ones a user selected
+    Batch,
+}
+
+/// A single line of the audit log, describing what happened to one entry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    /// When the mutation was processed, in RFC 3339 format
+    pub timestamp: String,
+    pub username: String,
+    pub peer: String,
+    pub room: String,
+    pub operation: AuditOperation,
+    #[serde(deserialize_with = "deserialize_entry_id")]
+    pub entry_id: EntryId,
+    /// The element before the mutation, missing if the entry did not exist
+    pub before: Option<CanvasElement>,
+    /// The element after the mutation, missing if the entry was removed
+    pub after: Option<CanvasElement>,
+}
+
+/// Reads entry ids written before they were [EntryId]s, as plain numbers.
+fn deserialize_entry_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<EntryId, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum AnyEntryId {
+        Legacy(u64),
+        Current(EntryId),
+    }
+
+    Ok(match AnyEntryId::deserialize(deserializer)? {
+        AnyEntryId::Legacy(counter) => EntryId::new(SERVER_SITE, counter),
+        AnyEntryId::Current(id) => id,
+    })
+}
+
+/// Who performed the mutations being recorded.
+pub struct AuditContext<'a> {
+    pub username: &'a str,
+    pub peer: SocketAddr,
+    pub room: &'a str,
+}
+
+impl AuditContext<'_> {
+    pub fn record(
+        &self,
+        operation: AuditOperation,
+        entry_id: EntryId,
+        before: Option<&CanvasElement>,
+        after: Option<&CanvasElement>,
+    ) -> AuditRecord {
+        AuditRecord {
+            timestamp: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            username: self.username.to_string(),
+            peer: self.peer.to_string(),
+            room: self.room.to_string(),
+            operation,
+            entry_id,
+            before: before.cloned(),
+            after: after.cloned(),
+        }
+    }
+
+    /// Records every entry that differs between two states of the canvas,
+    /// which is how wholesale replacements such as undoing a clear are logged.
+    pub fn diff(
+        &self,
+        operation: AuditOperation,
+        before: &[CanvasEntry],
+        after: &[CanvasEntry],
+    ) -> Vec<AuditRecord> {
+        let find = |entries: &'_ [CanvasEntry], id: EntryId| {
+            entries.iter().find(|entry| entry.id == id).cloned()
+        };
+
+        let mut records = Vec::new();
+
+        // Entries that only moved in the stack are recorded too, with the same
+        // element before and after
+        for entry in before {
+            let after_entry = find(after, entry.id);
+            if after_entry.as_ref() != Some(entry) {
+                records.push(self.record(
+                    operation,
+                    entry.id,
+                    Some(&entry.element),
+                    after_entry.as_ref().map(|entry| &entry.element),
+                ));
+            }
+        }
+
+        for entry in after {
+            if find(before, entry.id).is_none() {
+                records.push(self.record(operation, entry.id, None, Some(&entry.element)));
+            }
+        }
+
+        records
+    }
+}
+
+/// An append-only file of [AuditRecord]s, one JSON object per line.
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { file })
+    }
+
+    pub fn append(&mut self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record).map_err(std::io::Error::from)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+use ns_core::errors::{Result, ServerError};
+use ns_core::models::blob::BlobId;
+
+/// The blobs uploaded by clients, by id. They are written to a directory,
+/// one file named after the id each, or only kept in memory without one.
+///
+/// Several connections can read and write blobs at the same time.
+pub struct BlobStore {
+    directory: Option<PathBuf>,
+    memory: Mutex<HashMap<BlobId, Vec<u8>>>,
+    /// The bytes taken by the blobs stored, counting those being written
+    used: AtomicU64,
+    /// Numbers the temporary files, so that two uploads of the same blob do
+    /// not write to the same one
+    next_temporary: AtomicU64,
+}
+
+impl BlobStore {
+    pub fn in_memory() -> Self {
+        BlobStore {
+            directory: None,
+            memory: Mutex::new(HashMap::new()),
+            used: AtomicU64::new(0),
+            next_temporary: AtomicU64::new(0),
+        }
+    }
+
+    /// Stores blobs in `directory`, creating it if needed.
+    pub fn open(directory: &Path) -> Result<Self> {
+        fs::create_dir_all(directory)?;
+
+        // Temporary files are left behind by uploads that never finished
+        let mut used = 0;
+        for file in fs::read_dir(directory)? {
+            let file = file?;
+            if file
+                .path()
+                .extension()
+                .is_none_or(|extension| extension != "tmp")
+            {
+                used += file.metadata()?.len();
+            }
+        }
+
+        Ok(BlobStore {
+            directory: Some(directory.to_path_buf()),
+            memory: Mutex::new(HashMap::new()),
+            used: AtomicU64::new(used),
+            next_temporary: AtomicU64::new(0),
+        })
+    }
+
+    /// The bytes taken by the blobs stored.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub fn contains(&self, id: BlobId) -> bool {
+        match &self.directory {
+            Some(directory) => directory.join(id.to_string()).is_file(),
+            None => self.memory().contains_key(&id),
+        }
+    }
+
+    pub fn get(&self, id: BlobId) -> Result<Option<Vec<u8>>> {
+        let Some(directory) = &self.directory else {
+            return Ok(self.memory().get(&id).cloned());
+        };
+
+        match fs::read(directory.join(id.to_string())) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stores a blob whose content was already checked against its id, going
+    /// through a temporary file so that a stored blob is always complete.
+    ///
+    /// Fails with [ServerError::BlobStorageFull] if the blobs stored would then
+    /// take more than `capacity` bytes.
+    pub fn put(&self, id: BlobId, data: Vec<u8>, capacity: Option<u64>) -> Result<()> {
+        let size = data.len() as u64;
+
+        let Some(directory) = &self.directory else {
+            if let Entry::Vacant(entry) = self.memory().entry(id) {
+                self.reserve(size, capacity)?;
+                entry.insert(data);
+            }
+            return Ok(());
+        };
+
+        let path = directory.join(id.to_string());
+        if path.is_file() {
+            return Ok(());
+        }
+
+        self.reserve(size, capacity)?;
+        let temporary = self.next_temporary.fetch_add(1, Ordering::Relaxed);
+        let temporary_path = directory.join(format!("{}.{}.tmp", id, temporary));
+        let written =
+            fs::write(&temporary_path, data).and_then(|()| fs::rename(&temporary_path, &path));
+        if written.is_err() {
+            self.used.fetch_sub(size, Ordering::SeqCst);
+        }
+
+        Ok(written?)
+    }
+
+    /// Counts `size` more bytes as used, unless that goes over `capacity`.
+    fn reserve(&self, size: u64, capacity: Option<u64>) -> Result<()> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                let total = used.saturating_add(size);
+                capacity
+                    .is_none_or(|capacity| total <= capacity)
+                    .then_some(total)
+            })
+            .map_err(|_| ServerError::BlobStorageFull)?;
+
+        Ok(())
+    }
+
+    /// The blobs kept in memory, still usable if a thread panicked holding them
+    /// since every insertion is a single step.
+    fn memory(&self) -> MutexGuard<'_, HashMap<BlobId, Vec<u8>>> {
+        self.memory
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ns_core::errors::Error;
+
+    use super::*;
+
+    #[test]
+    fn blobs_are_read_back_from_disk() {
+        let directory =
+            std::env::temp_dir().join(format!("netsketch-blobs-{}", std::process::id()));
+        let data = b"not really an image".to_vec();
+        let id = BlobId::of(&data);
+
+        let store = BlobStore::open(&directory).unwrap();
+        assert!(!store.contains(id));
+        assert_eq!(store.get(id).unwrap(), None);
+
+        store.put(id, data.clone(), None).unwrap();
+
+        // A server started later finds it too
+        let store = BlobStore::open(&directory).unwrap();
+        assert!(store.contains(id));
+        assert_eq!(store.get(id).unwrap(), Some(data.clone()));
+        assert_eq!(store.used(), data.len() as u64);
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn blobs_are_only_stored_while_there_is_room() {
+        let store = BlobStore::in_memory();
+        let first = b"first".to_vec();
+        let second = b"second".to_vec();
+
+        store
+            .put(BlobId::of(&first), first.clone(), Some(8))
+            .unwrap();
+        // Storing it again takes no more room
+        store
+            .put(BlobId::of(&first), first.clone(), Some(8))
+            .unwrap();
+        assert!(matches!(
+            store.put(BlobId::of(&second), second.clone(), Some(8)),
+            Err(Error::ServerError(ServerError::BlobStorageFull))
+        ));
+        assert!(!store.contains(BlobId::of(&second)));
+        assert_eq!(store.used(), first.len() as u64);
+
+        store.put(BlobId::of(&second), second, Some(11)).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Upper bounds, in seconds, of the buckets of the lock hold time histogram.
+const LOCK_HOLD_BUCKETS: [f64; 6] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.1];
+
+/// Counters collected while the server runs, rendered in the Prometheus text
+/// format by the `/metrics` endpoint.
+///
+/// Gauges that can be read off the [ServerState](super::ServerState) directly,
+/// such as the number of sessions, are not stored here.
+#[derive(Default)]
+pub struct Metrics {
+    packets_received: Mutex<BTreeMap<&'static str, u64>>,
+    broadcast_bytes: AtomicU64,
+    decode_errors: AtomicU64,
+    lock_hold_buckets: [AtomicU64; LOCK_HOLD_BUCKETS.len()],
+    lock_hold_count: AtomicU64,
+    lock_hold_nanos: AtomicU64,
+}
+
+/// Records how long the server state lock was held once dropped, see
+/// [Metrics::time_lock_hold].
+pub struct LockHoldTimer<'a> {
+    metrics: &'a Metrics,
+    start: Instant,
+}
+
+impl Drop for LockHoldTimer<'_> {
+    fn drop(&mut self) {
+        self.metrics.record_lock_hold(self.start.elapsed());
+    }
+}
+
+/// Point-in-time values sampled from the server state when scraping.
+pub struct Gauges {
+    pub sessions: usize,
+    pub canvas_entries: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_packet(&self, packet_type: &'static str) {
+        if let Ok(mut packets_received) = self.packets_received.lock() {
+            *packets_received.entry(packet_type).or_insert(0) += 1;
+        }
+    }
+
+    pub fn record_broadcast(&self, bytes: usize) {
+        self.broadcast_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_lock_hold(&self, held_for: Duration) {
+        let seconds = held_for.as_secs_f64();
+        for (bucket, bound) in self.lock_hold_buckets.iter().zip(LOCK_HOLD_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.lock_hold_count.fetch_add(1, Ordering::Relaxed);
+        self.lock_hold_nanos
+            .fetch_add(held_for.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Starts timing a critical section, which should be right after the lock
+    /// is acquired so that the timer is dropped around the same time as the guard.
+    pub fn time_lock_hold(&self) -> LockHoldTimer<'_> {
+        LockHoldTimer {
+            metrics: self,
+            start: Instant::now(),
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+
+        // Writing to a String never fails
+        let _ = writeln!(out, "# HELP netsketch_sessions Users currently connected.");
+        let _ = writeln!(out, "# TYPE netsketch_sessions gauge");
+        let _ = writeln!(out, "netsketch_sessions {}", gauges.sessions);
+
+        let _ = writeln!(
+            out,
+            "# HELP netsketch_canvas_entries Entries currently on the canvas."
+        );
+        let _ = writeln!(out, "# TYPE netsketch_canvas_entries gauge");
+        let _ = writeln!(out, "netsketch_canvas_entries {}", gauges.canvas_entries);
+
+        let _ = writeln!(
+            out,
+            "# HELP netsketch_packets_received_total Packets received from clients, by type."
+        );
+        let _ = writeln!(out, "# TYPE netsketch_packets_received_total counter");
+        if let Ok(packets_received) = self.packets_received.lock() {
+            for (packet_type, count) in packets_received.iter() {
+                let _ = writeln!(
+                    out,
+                    "netsketch_packets_received_total{{type=\"{}\"}} {}",
+                    packet_type, count
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP netsketch_broadcast_bytes_total Bytes sent to clients as canvas updates."
+        );
+        let _ = writeln!(out, "# TYPE netsketch_broadcast_bytes_total counter");
+        let _ = writeln!(
+            out,
+            "netsketch_broadcast_bytes_total {}",
+            self.broadcast_bytes.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP netsketch_decode_errors_total Packets that could not be decoded."
+        );
+        let _ = writeln!(out, "# TYPE netsketch_decode_errors_total counter");
+        let _ = writeln!(
+            out,
+            "netsketch_decode_errors_total {}",
+            self.decode_errors.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP netsketch_state_lock_hold_seconds Time spent holding the server state lock per packet."
+        );
+        let _ = writeln!(out, "# TYPE netsketch_state_lock_hold_seconds histogram");
+        for (bucket, bound) in self.lock_hold_buckets.iter().zip(LOCK_HOLD_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "netsketch_state_lock_hold_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.lock_hold_count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "netsketch_state_lock_hold_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(
+            out,
+            "netsketch_state_lock_hold_seconds_sum {}",
+            self.lock_hold_nanos.load(Ordering::Relaxed) as f64 / 1e9
+        );
+        let _ = writeln!(out, "netsketch_state_lock_hold_seconds_count {}", count);
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(rendered: &str) -> Vec<&str> {
+        rendered
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect()
+    }
+
+    #[test]
+    fn every_metric_is_rendered() {
+        let metrics = Metrics::new();
+        metrics.record_packet("DrawRequest");
+        metrics.record_packet("DrawRequest");
+        metrics.record_packet("Connect");
+        metrics.record_broadcast(120);
+        metrics.record_broadcast(30);
+        metrics.record_decode_error();
+
+        let rendered = metrics.render(&Gauges {
+            sessions: 2,
+            canvas_entries: 5,
+        });
+
+        for line in [
+            "netsketch_sessions 2",
+            "netsketch_canvas_entries 5",
+            "netsketch_packets_received_total{type=\"Connect\"} 1",
+            "netsketch_packets_received_total{type=\"DrawRequest\"} 2",
+            "netsketch_broadcast_bytes_total 150",
+            "netsketch_decode_errors_total 1",
+            "netsketch_state_lock_hold_seconds_count 0",
+        ] {
+            assert!(lines(&rendered).contains(&line), "{line} in\n{rendered}");
+        }
+    }
+
+    #[test]
+    fn every_metric_has_a_help_and_type() {
+        let rendered = Metrics::new().render(&Gauges {
+            sessions: 0,
+            canvas_entries: 0,
+        });
+
+        for line in lines(&rendered) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = name
+                .strip_suffix("_bucket")
+                .or_else(|| name.strip_suffix("_sum"))
+                .or_else(|| name.strip_suffix("_count"))
+                .unwrap_or(name);
+            assert!(rendered.contains(&format!("# HELP {family} ")), "{family}");
+            assert!(rendered.contains(&format!("# TYPE {family} ")), "{family}");
+        }
+    }
+
+    #[test]
+    fn lock_hold_buckets_are_cumulative() {
+        let metrics = Metrics::new();
+        metrics.record_lock_hold(Duration::from_micros(50));
+        metrics.record_lock_hold(Duration::from_millis(3));
+        metrics.record_lock_hold(Duration::from_secs(1));
+
+        let rendered = metrics.render(&Gauges {
+            sessions: 0,
+            canvas_entries: 0,
+        });
+
+        for line in [
+            "netsketch_state_lock_hold_seconds_bucket{le=\"0.0001\"} 1",
+            "netsketch_state_lock_hold_seconds_bucket{le=\"0.001\"} 1",
+            "netsketch_state_lock_hold_seconds_bucket{le=\"0.005\"} 2",
+            "netsketch_state_lock_hold_seconds_bucket{le=\"0.1\"} 2",
+            "netsketch_state_lock_hold_seconds_bucket{le=\"+Inf\"} 3",
+            "netsketch_state_lock_hold_seconds_sum 1.00305",
+            "netsketch_state_lock_hold_seconds_count 3",
+        ] {
+            assert!(lines(&rendered).contains(&line), "{line} in\n{rendered}");
+        }
+    }
+}
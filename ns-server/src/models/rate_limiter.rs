@@ -0,0 +1,77 @@
+use std::time::Instant;
+
+/// A token bucket refilling continuously at `rate` tokens per second, up to
+/// `burst` tokens, so that short bursts are allowed but sustained floods are not.
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available, returning whether it did.
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn a_full_bucket_allows_a_burst() {
+        let mut bucket = TokenBucket::new(1.0, 3);
+        let now = bucket.last_refill;
+
+        assert!((0..3).all(|_| bucket.try_take_at(now)));
+        assert!(!bucket.try_take_at(now));
+    }
+
+    #[test]
+    fn tokens_are_refilled_at_the_rate() {
+        let mut bucket = TokenBucket::new(2.0, 1);
+        let start = bucket.last_refill;
+
+        assert!(bucket.try_take_at(start));
+        assert!(!bucket.try_take_at(start + Duration::from_millis(250)));
+        assert!(bucket.try_take_at(start + Duration::from_millis(500)));
+        assert!(!bucket.try_take_at(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn refills_never_exceed_the_burst() {
+        let mut bucket = TokenBucket::new(10.0, 2);
+        let later = bucket.last_refill + Duration::from_secs(60);
+
+        assert!((0..2).all(|_| bucket.try_take_at(later)));
+        assert!(!bucket.try_take_at(later));
+    }
+}
@@ -1,33 +1,99 @@
-use std::{collections::HashMap, net::TcpStream};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    net::TcpStream,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tracing::{error, info};
 
 use ns_core::errors::{Result, ServerError};
-use ns_core::models::canvas::Canvas;
+use ns_core::models::{
+    canvas::{Canvas, CanvasEntry, EntryId, Layer, Point, BASE_LAYER},
+    history::{History, Operation},
+    packets::TcpPacket,
+};
 
-use super::{session::Session, user_data::UserData};
+use super::{
+    audit::{AuditLog, AuditRecord},
+    blob_store::BlobStore,
+    rate_limiter::TokenBucket,
+    session::Session,
+    user_data::UserData,
+};
+use crate::config::{HistoryConfig, LimitsConfig};
+
+/// Something going wrong while a mutation is made, see [ServerState::fault].
+#[cfg(test)]
+pub type Fault = Box<dyn FnOnce(&mut ServerState) + Send>;
 
 pub struct ServerState {
     pub canvas: Canvas,
     pub sessions: Vec<Session>,
     pub users: HashMap<String, UserData>,
+    /// Where every mutation of the canvas is recorded, if enabled
+    pub audit_log: Option<AuditLog>,
+    /// Every revision of the canvas since the server started
+    pub history: History,
+    /// The points of the strokes users are drawing that were not passed on
+    /// yet, by username
+    pub live_strokes: HashMap<String, Vec<Point>>,
+    /// The content of the images on the canvas, by id, shared so that it can
+    /// be read and written without holding the rest of the state
+    pub blobs: Arc<BlobStore>,
+    /// Runs in the middle of the next [ServerState::record], to make a handler
+    /// fail halfway through a mutation
+    #[cfg(test)]
+    pub fault: Option<Fault>,
 }
 
 impl ServerState {
     pub fn new() -> Self {
+        let canvas = Canvas::new();
         ServerState {
-            canvas: Canvas::new(),
+            history: History::new(&canvas),
+            canvas,
             sessions: Vec::new(),
             users: HashMap::new(),
+            audit_log: None,
+            live_strokes: HashMap::new(),
+            blobs: Arc::new(BlobStore::in_memory()),
+            #[cfg(test)]
+            fault: None,
+        }
+    }
+
+    pub fn from_canvas(canvas: Canvas) -> Self {
+        ServerState {
+            history: History::new(&canvas),
+            canvas,
+            ..ServerState::new()
         }
     }
 
-    pub fn connect_user(&mut self, stream: &TcpStream, username: String) -> Result<()> {
+    pub fn connect_user(
+        &mut self,
+        stream: &TcpStream,
+        username: String,
+        limits: &LimitsConfig,
+    ) -> Result<()> {
         if self.sessions.iter().any(|x| x.username == username) {
             error!("Username {} is already connected", username);
             return Err(ServerError::UsernameTaken(username).into());
+        } else if self.sessions.len() >= limits.max_sessions {
+            error!("Refusing {}, the server is full", username);
+            return Err(ServerError::ServerFull.into());
         } else {
-            self.sessions
-                .push(Session::new(stream.try_clone()?, username.clone()));
+            let rate_limiter = TokenBucket::new(limits.mutations_per_sec, limits.mutation_burst);
+            let stroke_limiter = TokenBucket::new(limits.live_strokes_per_sec, 1);
+            let stream_limiter = TokenBucket::new(limits.streamed_per_sec, limits.streamed_burst);
+            self.sessions.push(Session::new(
+                stream.try_clone()?,
+                username.clone(),
+                rate_limiter,
+                stroke_limiter,
+                stream_limiter,
+            ));
         }
 
         Ok(())
@@ -36,13 +102,224 @@ impl ServerState {
     pub fn disconnect_user(&mut self, stream: TcpStream) -> Result<()> {
         info!("Ending session belonging to {:?}", stream.peer_addr()?);
         let peer_addr = stream.peer_addr()?;
+        let username = self.get_username(&stream).cloned();
 
         self.sessions
             .retain(|x| x.stream.peer_addr().ok() != Some(peer_addr));
 
+        // Whatever they were drawing will never be finished
+        if let Some(username) = username {
+            self.end_live_stroke(&username)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops the stroke the user is drawing, if any, telling every session to
+    /// stop showing it and returning the number of bytes written.
+    pub fn end_live_stroke(&mut self, username: &str) -> Result<usize> {
+        match self.live_strokes.remove(username) {
+            Some(_) => self.broadcast(&TcpPacket::LiveStrokeEnd(username.to_string())),
+            None => Ok(0),
+        }
+    }
+
+    /// Checks the invariants that a handler panicking halfway through a
+    /// mutation could have broken, returning the first violation found.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        let mut ids = HashSet::new();
+        for entry in self.canvas.entries.iter() {
+            if !ids.insert(entry.id) {
+                return Err(format!("entry {} appears more than once", entry.id));
+            }
+            if entry.id.site == self.canvas.site && entry.id.counter >= self.canvas.next_counter {
+                return Err(format!(
+                    "entry {} is not below the next counter {}",
+                    entry.id, self.canvas.next_counter
+                ));
+            }
+            if self.canvas.layer_of(entry).is_none() {
+                return Err(format!(
+                    "entry {} is on layer {} which does not exist",
+                    entry.id, entry.layer
+                ));
+            }
+        }
+
+        let mut usernames = HashSet::new();
+        for session in self.sessions.iter() {
+            if !usernames.insert(&session.username) {
+                return Err(format!("{} has more than one session", session.username));
+            }
+        }
+
         Ok(())
     }
 
+    /// Restores the invariants checked by [ServerState::validate] in place,
+    /// keeping the first occurrence of any duplicated entry or session.
+    pub fn repair(&mut self) {
+        let mut ids = HashSet::new();
+        self.canvas.entries.retain(|entry| ids.insert(entry.id));
+
+        let site = self.canvas.site;
+        let next_counter = self
+            .canvas
+            .entries
+            .iter()
+            .filter(|entry| entry.id.site == site)
+            .map(|entry| entry.id.counter + 1)
+            .max()
+            .unwrap_or(0);
+        self.canvas.next_counter = self.canvas.next_counter.max(next_counter);
+
+        // Entries left on a missing layer go back to the base one
+        if self.canvas.layer(BASE_LAYER).is_none() {
+            self.canvas.layers.insert(0, Canvas::new().layers.remove(0));
+        }
+        let layers: HashSet<_> = self.canvas.layers.iter().map(|layer| layer.id).collect();
+        for entry in self.canvas.entries.iter_mut() {
+            if !layers.contains(&entry.layer) {
+                entry.layer = BASE_LAYER;
+            }
+        }
+
+        let mut usernames = HashSet::new();
+        self.sessions
+            .retain(|session| usernames.insert(session.username.clone()));
+    }
+
+    /// Appends the records to the audit log, if there is one. Failing to do so
+    /// is logged rather than interrupting the mutation being recorded.
+    pub fn audit(&mut self, records: impl IntoIterator<Item = AuditRecord>) {
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            for record in records {
+                if let Err(e) = audit_log.append(&record) {
+                    error!("Failed to write to the audit log: {e}");
+                }
+            }
+        }
+    }
+
+    /// Adds an operation that was just applied to the canvas to its history,
+    /// dropping the oldest revisions past the configured maximum.
+    pub fn record(&mut self, author: &str, operation: Operation, config: &HistoryConfig) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+
+        #[cfg(test)]
+        if let Some(fault) = self.fault.take() {
+            fault(self);
+        }
+
+        self.history.record(author, timestamp, operation);
+        self.history.trim(config.max_revisions);
+    }
+
+    /// Applies an operation to the canvas on behalf of `author`, records it,
+    /// and sends every session the entries and layers that changed, returning
+    /// the total number of bytes written.
+    pub fn apply(
+        &mut self,
+        author: &str,
+        operation: Operation,
+        config: &HistoryConfig,
+    ) -> Result<usize> {
+        let before = self.canvas.entries.clone();
+        let layers_before = self.canvas.layers.clone();
+        operation.apply(&mut self.canvas);
+        self.record(author, operation, config);
+
+        self.broadcast_changes(&before, &layers_before)
+    }
+
+    /// Sends every session the packets turning `before` and `layers_before`
+    /// into the current canvas.
+    fn broadcast_changes(
+        &mut self,
+        before: &[CanvasEntry],
+        layers_before: &[Layer],
+    ) -> Result<usize> {
+        let after = self.canvas.entries.clone();
+        let mut packets = Vec::new();
+
+        // The layers go first, so that no entry arrives on a layer not known yet
+        if self.canvas.layers != layers_before {
+            packets.push(TcpPacket::LayerResponse(self.canvas.layers.clone()));
+        }
+
+        let ids_to_delete: Vec<EntryId> = before
+            .iter()
+            .filter(|entry| after.iter().all(|kept| kept.id != entry.id))
+            .map(|entry| entry.id)
+            .collect();
+        if !ids_to_delete.is_empty() {
+            packets.push(TcpPacket::ClearResponse { ids_to_delete });
+        }
+
+        for entry in after {
+            match before.iter().find(|previous| previous.id == entry.id) {
+                None => packets.push(TcpPacket::DrawResponse(entry)),
+                Some(previous) if *previous != entry => {
+                    packets.push(TcpPacket::UpdateResponse(entry.id, entry))
+                }
+                Some(_) => {}
+            }
+        }
+
+        let mut written = 0;
+        for packet in packets.iter() {
+            written += self.broadcast(packet)?;
+        }
+
+        Ok(written)
+    }
+
+    /// Sends the packet to every connected session, returning the total number
+    /// of bytes written.
+    pub fn broadcast(&mut self, packet: &TcpPacket) -> Result<usize> {
+        let packet_bytes = packet.to_bytes()?;
+        for session in self.sessions.iter_mut() {
+            session.stream.write_all(&packet_bytes)?;
+            session.stream.flush()?;
+        }
+
+        Ok(packet_bytes.len() * self.sessions.len())
+    }
+
+    /// Sends the packet to every session but the one of `username`, returning
+    /// the total number of bytes written.
+    pub fn broadcast_to_others(&mut self, packet: &TcpPacket, username: &str) -> Result<usize> {
+        let packet_bytes = packet.to_bytes()?;
+        let mut written = 0;
+        for session in self.sessions.iter_mut() {
+            if session.username != username {
+                session.stream.write_all(&packet_bytes)?;
+                session.stream.flush()?;
+                written += packet_bytes.len();
+            }
+        }
+
+        Ok(written)
+    }
+
+    pub fn get_session_mut(&mut self, stream: &TcpStream) -> Option<&mut Session> {
+        let addr = stream.peer_addr().ok()?;
+        self.sessions
+            .iter_mut()
+            .find(|session| session.stream.peer_addr().ok() == Some(addr))
+    }
+
+    /// How many entries of the canvas currently belong to `username`.
+    pub fn count_entries_by(&self, username: &str) -> usize {
+        self.canvas
+            .entries
+            .iter()
+            .filter(|entry| entry.author == username)
+            .count()
+    }
+
     pub fn get_username(&self, stream: &TcpStream) -> Option<&String> {
         stream.peer_addr().ok().and_then(|addr| {
             self.sessions.iter().find_map(|session| {
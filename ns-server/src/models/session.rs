@@ -1,12 +1,38 @@
 use std::net::TcpStream;
 
+use ns_core::models::blob::PartialBlob;
+
+use super::rate_limiter::TokenBucket;
+
 pub struct Session {
     pub stream: TcpStream,
     pub username: String,
+    /// Limits how fast this session can mutate the canvas
+    pub rate_limiter: TokenBucket,
+    /// Limits how often the points of the stroke being drawn are passed on,
+    /// the ones held back being sent along with the next
+    pub stroke_limiter: TokenBucket,
+    /// Limits how fast this session can send live strokes and blob chunks
+    pub stream_limiter: TokenBucket,
+    /// The blob this session is uploading, if any
+    pub upload: Option<PartialBlob>,
 }
 
 impl Session {
-    pub fn new(stream: TcpStream, username: String) -> Self {
-        Session { stream, username }
+    pub fn new(
+        stream: TcpStream,
+        username: String,
+        rate_limiter: TokenBucket,
+        stroke_limiter: TokenBucket,
+        stream_limiter: TokenBucket,
+    ) -> Self {
+        Session {
+            stream,
+            username,
+            rate_limiter,
+            stroke_limiter,
+            stream_limiter,
+            upload: None,
+        }
     }
 }
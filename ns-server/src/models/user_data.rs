@@ -1,20 +1,18 @@
 use std::time::Instant;
 
-use ns_core::models::canvas::CanvasEntry;
-
-#[derive(Clone)]
-pub enum Action {
-    Delete(CanvasEntry),
-    Draw(usize),
-    Update(CanvasEntry),
-    Clear(ns_core::models::canvas::Canvas),
-}
+use ns_core::models::{
+    canvas::{LayerId, BASE_LAYER},
+    history::Operation,
+};
 
 #[derive(Clone)]
 pub struct UserData {
     pub username: String,
-    pub action_history: Vec<Action>,
+    /// The operations made by the user, most recent last, undone in reverse
+    pub action_history: Vec<Operation>,
     pub last_login: Option<Instant>,
+    /// The layer new entries of the user are drawn on
+    pub layer: LayerId,
 }
 
 impl UserData {
@@ -23,6 +21,7 @@ impl UserData {
             username: username.to_string(),
             action_history: vec![],
             last_login: None,
+            layer: BASE_LAYER,
         }
     }
 }
@@ -1,5 +1,19 @@
+mod audit_query;
 mod handle_client;
+mod http;
 mod init;
+mod persistence;
+mod recovery;
+mod shutdown;
+#[cfg(test)]
+mod test_server;
+mod undo;
 
-pub use handle_client::handle_client;
+pub use audit_query::{query_audit_log, AuditFilter};
+pub use handle_client::serve_connection;
+pub use http::{serve_http, HttpContext};
 pub use init::init_server;
+pub use persistence::{check_writable, load_snapshot, save_snapshot};
+pub use recovery::lock_state;
+pub use shutdown::{shutdown_server, Connection};
+pub use undo::{undo_last_action, UndoOutcome};
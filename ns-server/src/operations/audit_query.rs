@@ -0,0 +1,180 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use ns_core::errors::{Result, ServerError};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::models::AuditRecord;
+
+/// Which records of the audit log to print.
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub since: Option<OffsetDateTime>,
+    pub until: Option<OffsetDateTime>,
+}
+
+impl AuditFilter {
+    /// Builds a filter from the command line, where times are in RFC 3339
+    /// format, e.g. `2024-04-01T09:00:00Z`.
+    pub fn parse(user: Option<String>, since: Option<&str>, until: Option<&str>) -> Result<Self> {
+        let parse_time = |name: &str, value: Option<&str>| {
+            value
+                .map(|value| {
+                    OffsetDateTime::parse(value, &Rfc3339).map_err(|e| {
+                        ServerError::InvalidConfig(format!(
+                            "--{name}: {value:?} is not RFC 3339: {e}"
+                        ))
+                    })
+                })
+                .transpose()
+        };
+
+        Ok(AuditFilter {
+            user,
+            since: parse_time("since", since)?,
+            until: parse_time("until", until)?,
+        })
+    }
+
+    fn matches(&self, record: &AuditRecord) -> bool {
+        if self
+            .user
+            .as_ref()
+            .is_some_and(|user| *user != record.username)
+        {
+            return false;
+        }
+
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+
+        match OffsetDateTime::parse(&record.timestamp, &Rfc3339) {
+            Ok(timestamp) => {
+                self.since.is_none_or(|since| timestamp >= since)
+                    && self.until.is_none_or(|until| timestamp <= until)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Writes every record of the audit log at `path` that matches the filter to
+/// `out`, keeping the original JSON lines so the output can be piped to other tools.
+pub fn query_audit_log(path: &Path, filter: &AuditFilter, out: &mut impl Write) -> Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<AuditRecord>(&line) {
+            Ok(record) if filter.matches(&record) => writeln!(out, "{line}")?,
+            Ok(_) => {}
+            Err(e) => eprintln!("Skipping line {} of {}: {e}", number + 1, path.display()),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const FIXTURE: &str = r#"{"timestamp":"2024-04-01T09:00:00Z","username":"alice","peer":"127.0.0.1:5000","room":"default","operation":"draw","entry_id":1,"before":null,"after":null}
+{"timestamp":"2024-04-01T12:30:00Z","username":"bob","peer":"127.0.0.1:5001","room":"default","operation":"delete","entry_id":{"site":2,"counter":4},"before":null,"after":null}
+
+not a record
+{"timestamp":"2024-04-01T17:00:00Z","username":"alice","peer":"127.0.0.1:5000","room":"default","operation":"clear","entry_id":2,"before":null,"after":null}
+{"timestamp":"yesterday","username":"alice","peer":"127.0.0.1:5000","room":"default","operation":"undo","entry_id":3,"before":null,"after":null}
+"#;
+
+    /// Runs the query against the fixture, returning the usernames and
+    /// operations of the records printed.
+    fn query(user: Option<&str>, since: Option<&str>, until: Option<&str>) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!(
+            "netsketch-audit-{}-{}.jsonl",
+            std::process::id(),
+            file_suffix(user, since, until)
+        ));
+        fs::write(&path, FIXTURE).unwrap();
+
+        let filter = AuditFilter::parse(user.map(str::to_string), since, until).unwrap();
+        let mut out = Vec::new();
+        let result = query_audit_log(&path, &filter, &mut out);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                // Lines are passed through as they were written
+                assert!(FIXTURE.lines().any(|fixture| fixture == line));
+                let record: AuditRecord = serde_json::from_str(line).unwrap();
+                format!("{} {:?}", record.username, record.operation)
+            })
+            .collect()
+    }
+
+    /// Keeps the fixture files of tests running in parallel apart.
+    fn file_suffix(user: Option<&str>, since: Option<&str>, until: Option<&str>) -> String {
+        format!("{user:?}{since:?}{until:?}")
+            .chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect()
+    }
+
+    #[test]
+    fn every_record_is_printed_without_filters() {
+        assert_eq!(
+            query(None, None, None),
+            ["alice Draw", "bob Delete", "alice Clear", "alice Undo"]
+        );
+    }
+
+    #[test]
+    fn records_are_filtered_by_user() {
+        assert_eq!(query(Some("bob"), None, None), ["bob Delete"]);
+        assert!(query(Some("carol"), None, None).is_empty());
+    }
+
+    #[test]
+    fn records_are_filtered_by_time_inclusively() {
+        assert_eq!(
+            query(None, Some("2024-04-01T12:30:00Z"), None),
+            ["bob Delete", "alice Clear"]
+        );
+        assert_eq!(
+            query(None, None, Some("2024-04-01T12:30:00Z")),
+            ["alice Draw", "bob Delete"]
+        );
+        assert_eq!(
+            query(
+                Some("alice"),
+                Some("2024-04-01T10:00:00+02:00"),
+                Some("2024-04-01T18:00:00Z")
+            ),
+            ["alice Draw", "alice Clear"]
+        );
+    }
+
+    #[test]
+    fn times_that_are_not_rfc_3339_are_rejected() {
+        for (since, until) in [(Some("2024-04-01"), None), (None, Some("tomorrow"))] {
+            let error = match AuditFilter::parse(None, since, until) {
+                Ok(_) => panic!("expected an error for {since:?} {until:?}"),
+                Err(e) => e.to_string(),
+            };
+            assert!(error.contains("is not RFC 3339"), "{error}");
+        }
+    }
+}
@@ -1,237 +1,930 @@
 use std::{
     io::{Read, Write},
     net::TcpStream,
-    sync::{Arc, Mutex},
-    time::Duration,
-    usize,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use ns_core::errors::{Error, Result, ServerError};
-use ns_core::models::packets::TcpPacket;
+use ns_core::models::{
+    blob::{chunk_packets, BlobId, PartialBlob},
+    canvas::{CanvasElement, EntryId, GroupChange, Layer, LayerChange, BASE_LAYER},
+    history::{History, Operation},
+    packets::TcpPacket,
+};
+
+use tracing::{debug, error, error_span, field, info, trace, warn};
+
+use super::{lock_state, undo_last_action, UndoOutcome};
+use crate::{
+    config::ServerConfig,
+    models::{AuditContext, AuditOperation, BlobStore, Metrics, ServerState, UserData},
+};
+
+/// Handles the requests of a connection one after the other until one fails,
+/// then ends its session, unless the server is shutting down.
+///
+/// A panicking handler only costs its own connection, the next lock recovers
+/// the state it may have left behind.
+pub fn serve_connection(
+    stream: TcpStream,
+    server_state: Arc<Mutex<ServerState>>,
+    config: &ServerConfig,
+    metrics: &Metrics,
+    shutdown: &AtomicBool,
+) {
+    loop {
+        let outcome = catch_unwind(AssertUnwindSafe(|| {
+            handle_client(stream.try_clone()?, server_state.clone(), config, metrics)
+        }));
 
-use tracing::{debug, error, info};
+        let failed = match outcome {
+            Ok(result) => result.is_err(),
+            Err(_) => {
+                error!("Connection handler panicked, dropping the connection");
+                true
+            }
+        };
 
-use crate::models::{Action, ServerState, UserData};
+        if failed {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let mut server_state =
+                lock_state(&server_state, config.persistence.snapshot_path.as_deref());
+            if let Err(e) = server_state.disconnect_user(stream) {
+                error!("Failed to disconnect: {e}");
+            }
+            break;
+        }
+    }
+}
 
-pub fn handle_client(mut stream: TcpStream, server_state: Arc<Mutex<ServerState>>) -> Result<()> {
-    // 10 minute timeout
-    stream.set_read_timeout(Some(Duration::from_secs(600)))?;
+pub fn handle_client(
+    mut stream: TcpStream,
+    server_state: Arc<Mutex<ServerState>>,
+    config: &ServerConfig,
+    metrics: &Metrics,
+) -> Result<()> {
+    stream.set_read_timeout(Some(config.read_timeout()))?;
+    let peer = stream.peer_addr()?;
+    let room = config.room.as_str();
 
     // Payload length
     let mut length_header = [0u8; 4];
     stream.read_exact(&mut length_header)?;
     let length = u32::from_le_bytes(length_header);
 
+    if length > config.limits.max_packet_size {
+        error!(peer = %peer, room, length, "Rejecting oversized packet");
+        return Err(ServerError::PacketTooLarge(length).into());
+    }
+
     // Payload
     let mut buffer = vec![0u8; length as usize];
     stream.read_exact(&mut buffer)?;
     stream.flush()?;
 
-    let packet = TcpPacket::try_from_bytes(&buffer)?;
+    let packet = match TcpPacket::try_from_bytes(&buffer) {
+        Ok(packet) => packet,
+        Err(e) => {
+            metrics.record_decode_error();
+            warn!(peer = %peer, room, length, "Failed to decode packet: {e}");
+            return Err(e);
+        }
+    };
+    let packet_type = packet.kind();
+    metrics.record_packet(packet_type);
+
+    // Every event logged while handling the request carries these fields. The
+    // span is at the error level so that it is kept whatever level is logged.
+    let span = error_span!(
+        "request",
+        username = field::Empty,
+        peer = %peer,
+        room,
+        packet_type
+    );
+    let _request = span.enter();
 
-    debug!("Received packet: {:?}", packet);
+    debug!(length, "Received packet");
+    trace!(?packet, "Packet contents");
 
-    let mut server_state = match server_state.lock() {
-        Ok(server_state) => server_state,
-        Err(_) => return Err(ServerError::LockError.into()),
+    // Whether a blob was uploaded may only be known from the disk, which is
+    // looked at before the state is locked for the request
+    let missing_blob = match &packet {
+        TcpPacket::DrawRequest(CanvasElement::Image { blob, .. })
+        | TcpPacket::UpdateRequest(_, CanvasElement::Image { blob, .. }) => {
+            let blobs = lock_state(&server_state, config.persistence.snapshot_path.as_deref())
+                .blobs
+                .clone();
+            (!blobs.contains(*blob)).then_some(*blob)
+        }
+        _ => None,
     };
 
-    let mut users = server_state.users.clone();
+    let mut server_state = lock_state(&server_state, config.persistence.snapshot_path.as_deref());
+    let lock_timer = metrics.time_lock_hold();
+
+    // Blobs are only read or written once the state is unlocked, so that the
+    // other clients are not kept waiting on the disk
+    let mut blob_transfer = None;
 
-    let user_data = server_state
+    let user = server_state
         .get_username(&stream)
-        .and_then(|name| users.get_mut(name));
+        .cloned()
+        .and_then(|name| server_state.users.remove(&name));
 
-    if let Some(user_data) = user_data {
-        match packet {
-            TcpPacket::DrawRequest(action) => {
-                let new_entry_id = {
-                    let new_entry = server_state
+    if let Some(mut user) = user {
+        // The user is taken out of the state while their request is handled,
+        // and put back whatever the outcome
+        let result = (|| -> Result<()> {
+            let user_data = &mut user;
+            let username = user_data.username.clone();
+            span.record("username", field::display(&username));
+
+            // The layer drawn on may be gone, e.g. after the room was reverted
+            if server_state.canvas.layer(user_data.layer).is_none() {
+                user_data.layer = BASE_LAYER;
+            }
+            let auditor = AuditContext {
+                username: &username,
+                peer,
+                room,
+            };
+
+            let is_mutation = matches!(
+                packet,
+                TcpPacket::DrawRequest(_)
+                    | TcpPacket::UpdateRequest(_, _)
+                    | TcpPacket::Delete(_)
+                    | TcpPacket::ClearRequest { .. }
+                    | TcpPacket::Undo
+                    | TcpPacket::RestackRequest(_, _)
+                    | TcpPacket::LayerRequest(_)
+                    | TcpPacket::GroupRequest(_)
+                    | TcpPacket::TransformRequest(_, _)
+                    | TcpPacket::BatchRequest(_, _)
+                    | TcpPacket::AdminUndo(_)
+                    | TcpPacket::AdminRevert(_)
+            );
+            // Drawing anything ends the stroke the user was drawing, whether it is
+            // that stroke or it gets refused
+            let ends_stroke = matches!(packet, TcpPacket::DrawRequest(_));
+
+            // Streamed packets come much faster, so they have their own limit
+            let is_streamed = matches!(
+                packet,
+                TcpPacket::LiveStrokeRequest { .. } | TcpPacket::BlobChunk { .. }
+            );
+
+            let throttled = (is_mutation || is_streamed)
+                && !server_state
+                    .get_session_mut(&stream)
+                    .is_some_and(|session| {
+                        if is_mutation {
+                            session.rate_limiter.try_take()
+                        } else {
+                            session.stream_limiter.try_take()
+                        }
+                    });
+
+            if throttled {
+                warn!("Request throttled");
+                // The upload cannot be completed without the chunk dropped
+                if matches!(packet, TcpPacket::BlobChunk { .. }) {
+                    if let Some(session) = server_state.get_session_mut(&stream) {
+                        session.upload = None;
+                    }
+                }
+                reply(
+                    &mut stream,
+                    &TcpPacket::Error("Too many requests, slow down".to_string()),
+                )?;
+                if ends_stroke {
+                    metrics.record_broadcast(server_state.end_live_stroke(&username)?);
+                }
+                return Ok(());
+            }
+
+            match packet {
+                TcpPacket::DrawRequest(_)
+                    if config
+                        .limits
+                        .max_entries_per_user
+                        .is_some_and(|max| server_state.count_entries_by(&username) >= max) =>
+                {
+                    warn!("Entry quota reached");
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error(format!(
+                            "You cannot have more than {} entries on the canvas",
+                            config.limits.max_entries_per_user.unwrap_or_default()
+                        )),
+                    )?;
+                }
+
+                TcpPacket::DrawRequest(_)
+                    if server_state
                         .canvas
-                        .add_action(user_data.username.clone(), &action);
+                        .layer(user_data.layer)
+                        .is_some_and(|layer| layer.locked) =>
+                {
+                    warn!(
+                        layer = user_data.layer,
+                        "Refusing to draw on a locked layer"
+                    );
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error(format!("Layer {} is locked", user_data.layer)),
+                    )?;
+                }
+
+                TcpPacket::UpdateRequest(id, _)
+                | TcpPacket::Delete(id)
+                | TcpPacket::RestackRequest(id, _)
+                    if server_state.canvas.is_locked(id) =>
+                {
+                    warn!(
+                        entry_id = %id,
+                        "Refusing to change an entry on a locked layer"
+                    );
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error(format!("Entry {} is on a locked layer", id)),
+                    )?;
+                }
+
+                TcpPacket::TransformRequest(_, transform) if !transform.is_valid() => {
+                    warn!(?transform, "Refusing an invalid transform");
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error(
+                            "Rotations must be finite and scale factors finite and positive"
+                                .to_string(),
+                        ),
+                    )?;
+                }
+
+                TcpPacket::TransformRequest(ids, _) | TcpPacket::BatchRequest(ids, _)
+                    if ids.iter().any(|id| server_state.canvas.is_locked(*id)) =>
+                {
+                    warn!(
+                        entry_ids = ?ids,
+                        "Refusing to change entries on a locked layer"
+                    );
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error("Some of the entries are on a locked layer".to_string()),
+                    )?;
+                }
+
+                TcpPacket::GroupRequest(change)
+                    if group_members(&server_state, &change)
+                        .iter()
+                        .any(|id| server_state.canvas.is_locked(*id)) =>
+                {
+                    warn!(
+                        group = change.group(),
+                        "Refusing to change a group with entries on a locked layer"
+                    );
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error(format!(
+                            "Group {} has entries on a locked layer",
+                            change.group()
+                        )),
+                    )?;
+                }
+
+                TcpPacket::DrawRequest(CanvasElement::Image { blob, .. })
+                | TcpPacket::UpdateRequest(_, CanvasElement::Image { blob, .. })
+                    if missing_blob == Some(blob) =>
+                {
+                    warn!(
+                        %blob,
+                        "Refusing an image whose blob was not uploaded"
+                    );
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error(format!("Blob {} was not uploaded", blob)),
+                    )?;
+                }
+
+                TcpPacket::DrawRequest(action) => {
+                    let new_entry = server_state.canvas.add_to_layer(
+                        user_data.layer,
+                        user_data.username.clone(),
+                        &action,
+                    );
+                    let new_entry_id = new_entry.id;
 
                     // Send the update to all connected clients
                     let update_packet = TcpPacket::DrawResponse(new_entry.clone());
-                    for connection in server_state.sessions.iter_mut() {
-                        let packet_bytes = update_packet.to_bytes()?;
-                        connection.stream.write_all(&packet_bytes)?;
-                        connection.stream.flush()?;
-                    }
+                    metrics.record_broadcast(server_state.broadcast(&update_packet)?);
 
-                    new_entry.id
-                };
+                    let operation = Operation::Draw(new_entry);
+                    server_state.record(&username, operation.clone(), &config.history);
 
-                info!(
-                    "User {} drew {:?} at entry {}",
-                    user_data.username, action, new_entry_id
-                );
+                    info!(
+                        entry_id = %new_entry_id,
+                        element = ?action,
+                        "Entry drawn"
+                    );
 
-                // Add action to user history
-                user_data.action_history.push(Action::Draw(new_entry_id));
-            }
+                    server_state.audit([auditor.record(
+                        AuditOperation::Draw,
+                        new_entry_id,
+                        None,
+                        Some(&action),
+                    )]);
+
+                    // Add action to user history
+                    user_data.action_history.push(operation);
+                }
 
-            TcpPacket::UpdateRequest(id, element) => {
-                let previous_entry = server_state.canvas.get_entry(id).cloned();
-
-                info!(
-                    "User {} updated entry {} with {:?}",
-                    user_data.username, id, element
-                );
-
-                match server_state.canvas.update_entry(id, &element) {
-                    Some(entry) => {
-                        let update_packet = TcpPacket::UpdateResponse(id, entry);
-                        for connection in server_state.sessions.iter_mut() {
-                            let packet_bytes = update_packet.to_bytes()?;
-                            connection.stream.write_all(&packet_bytes)?;
-                            connection.stream.flush()?;
+                TcpPacket::UpdateRequest(id, element) => {
+                    let previous_entry = server_state.canvas.get_entry(id).cloned();
+
+                    info!(
+                        entry_id = %id,
+                        element = ?element,
+                        "Entry updated"
+                    );
+
+                    match server_state.canvas.update_entry(id, &element) {
+                        Some(entry) => {
+                            let update_packet = TcpPacket::UpdateResponse(id, entry.clone());
+                            metrics.record_broadcast(server_state.broadcast(&update_packet)?);
+
+                            server_state.audit([auditor.record(
+                                AuditOperation::Update,
+                                id,
+                                previous_entry.as_ref().map(|entry| &entry.element),
+                                Some(&element),
+                            )]);
+
+                            if let Some(before) = previous_entry {
+                                let operation = Operation::Update {
+                                    before,
+                                    after: entry,
+                                };
+                                server_state.record(&username, operation.clone(), &config.history);
+                                user_data.action_history.push(operation);
+                            }
                         }
+                        None => {
+                            let notification_packet = TcpPacket::Notification(format!(
+                                "Entry with id {} does not exist",
+                                id
+                            ));
+                            let packet_bytes = notification_packet.to_bytes()?;
+                            stream.write_all(&packet_bytes)?;
+                            stream.flush()?;
+                        }
+                    }
+                }
+
+                TcpPacket::Delete(id) => {
+                    let entry = server_state.canvas.get_entry(id).cloned();
 
-                        if let Some(previous_entry) = previous_entry {
-                            user_data
-                                .action_history
-                                .push(Action::Update(previous_entry))
+                    info!(
+                        entry_id = %id,
+                        "Entry deleted"
+                    );
+
+                    if let Some(entry) = entry {
+                        server_state.audit([auditor.record(
+                            AuditOperation::Delete,
+                            id,
+                            Some(&entry.element),
+                            None,
+                        )]);
+                        server_state.canvas.delete_entry(id);
+                        let operation = Operation::Delete(entry);
+                        server_state.record(&username, operation.clone(), &config.history);
+                        user_data.action_history.push(operation);
+                        let update_packet = TcpPacket::Delete(id);
+                        metrics.record_broadcast(server_state.broadcast(&update_packet)?);
+                    }
+                }
+
+                TcpPacket::RestackRequest(id, restack) => {
+                    info!(
+                        entry_id = %id,
+                        ?restack,
+                        "Entry restacked"
+                    );
+
+                    if let Some(operation) = Operation::restack(&server_state.canvas, id, restack) {
+                        let before = server_state.canvas.entries.clone();
+                        metrics.record_broadcast(server_state.apply(
+                            &username,
+                            operation.clone(),
+                            &config.history,
+                        )?);
+                        user_data.action_history.push(operation);
+
+                        let records = auditor.diff(
+                            AuditOperation::Restack,
+                            &before,
+                            &server_state.canvas.entries,
+                        );
+                        server_state.audit(records);
+                    }
+                }
+
+                TcpPacket::GroupRequest(change) => {
+                    match Operation::group(&server_state.canvas, &change) {
+                        Some(operation) => {
+                            info!(?change, "Group changed");
+
+                            let before = server_state.canvas.entries.clone();
+                            metrics.record_broadcast(server_state.apply(
+                                &username,
+                                operation.clone(),
+                                &config.history,
+                            )?);
+                            user_data.action_history.push(operation);
+
+                            let records = auditor.diff(
+                                AuditOperation::Group,
+                                &before,
+                                &server_state.canvas.entries,
+                            );
+                            server_state.audit(records);
                         }
+                        None => reply(
+                            &mut stream,
+                            &TcpPacket::Notification(format!(
+                                "Nothing to change in group {}",
+                                change.group()
+                            )),
+                        )?,
                     }
-                    None => {
-                        let notification_packet =
-                            TcpPacket::Notification(format!("Entry with id {} does not exist", id));
-                        let packet_bytes = notification_packet.to_bytes()?;
-                        stream.write_all(&packet_bytes)?;
-                        stream.flush()?;
+                }
+
+                TcpPacket::TransformRequest(ids, transform) => {
+                    match Operation::transform(&server_state.canvas, &ids, transform) {
+                        Some(operation) => {
+                            info!(
+                                entry_ids = ?ids,
+                                ?transform,
+                                "Entries transformed"
+                            );
+
+                            // Clients transform their own copies, rather than
+                            // receiving every changed element
+                            let before = server_state.canvas.entries.clone();
+                            operation.apply(&mut server_state.canvas);
+                            server_state.record(&username, operation.clone(), &config.history);
+                            user_data.action_history.push(operation);
+
+                            let response = TcpPacket::TransformResponse(ids, transform);
+                            metrics.record_broadcast(server_state.broadcast(&response)?);
+
+                            let records = auditor.diff(
+                                AuditOperation::Transform,
+                                &before,
+                                &server_state.canvas.entries,
+                            );
+                            server_state.audit(records);
+                        }
+                        None => reply(
+                            &mut stream,
+                            &TcpPacket::Notification("Nothing to transform".to_string()),
+                        )?,
                     }
                 }
-            }
 
-            TcpPacket::Delete(id) => {
-                let entry = server_state.canvas.get_entry(id).cloned();
+                TcpPacket::BatchRequest(ids, change) => {
+                    match Operation::batch(&server_state.canvas, &ids, &change) {
+                        Some(operation) => {
+                            info!(
+                                entry_ids = ?ids,
+                                ?change,
+                                "Entries changed at once"
+                            );
 
-                info!("User {} deleted entry {}", user_data.username, id);
+                            let before = server_state.canvas.entries.clone();
+                            metrics.record_broadcast(server_state.apply(
+                                &username,
+                                operation.clone(),
+                                &config.history,
+                            )?);
+                            user_data.action_history.push(operation);
 
-                if let Some(entry) = entry {
-                    user_data.action_history.push(Action::Delete(entry.clone()));
-                    server_state.canvas.delete_entry(id);
-                    let update_packet = TcpPacket::Delete(id);
-                    for connection in server_state.sessions.iter_mut() {
-                        let packet_bytes = update_packet.to_bytes()?;
-                        connection.stream.write_all(&packet_bytes)?;
-                        connection.stream.flush()?;
+                            let records = auditor.diff(
+                                AuditOperation::Batch,
+                                &before,
+                                &server_state.canvas.entries,
+                            );
+                            server_state.audit(records);
+                        }
+                        None => reply(
+                            &mut stream,
+                            &TcpPacket::Notification("Nothing to change".to_string()),
+                        )?,
                     }
                 }
-            }
 
-            TcpPacket::Undo => {
-                let last_action = user_data.action_history.pop();
-
-                info!("User {} undid an action", user_data.username);
-
-                if let Some(last_action) = last_action {
-                    match last_action {
-                        Action::Delete(entry) => {
-                            // Recreate entry
-                            server_state.canvas.entries.push(entry.clone());
-                            let draw_packet = TcpPacket::DrawResponse(entry);
-                            for connection in server_state.sessions.iter_mut() {
-                                let bytes = draw_packet.to_bytes()?;
-                                connection.stream.write_all(&bytes)?;
-                                connection.stream.flush()?;
-                            }
+                TcpPacket::Undo => {
+                    let before = server_state.canvas.entries.clone();
+
+                    match undo_last_action(&mut server_state, user_data, &config.history)? {
+                        UndoOutcome::Empty => {}
+                        UndoOutcome::Undone(written) => {
+                            metrics.record_broadcast(written);
+                            info!("Action undone");
                         }
-                        Action::Draw(id) => {
-                            // Delete entry with that id
-                            server_state.canvas.delete_entry(id);
-                            let update_packet = TcpPacket::Delete(id);
-                            for connection in server_state.sessions.iter_mut() {
-                                let packet_bytes = update_packet.to_bytes()?;
-                                connection.stream.write_all(&packet_bytes)?;
-                                connection.stream.flush()?;
-                            }
+                        UndoOutcome::Forced(conflicts, written) => {
+                            metrics.record_broadcast(written);
+                            warn!(?conflicts, "Action undone over changes by others");
+                        }
+                        UndoOutcome::Skipped(conflicts) => {
+                            warn!(?conflicts, "Undo skipped over changes by others");
+                            reply(
+                                &mut stream,
+                                &TcpPacket::Notification(format!(
+                                    "Nothing was undone, someone else changed entries {:?} since",
+                                    conflicts
+                                )),
+                            )?;
                         }
-                        Action::Update(previous_entry) => {
-                            // Replace entry
-                            let current_entry = server_state
-                                .canvas
-                                .entries
-                                .iter_mut()
-                                .find(|entry| entry.id == previous_entry.id);
-
-                            if let Some(current_entry) = current_entry {
-                                *current_entry = previous_entry
+                        UndoOutcome::Locked(locked) => {
+                            warn!(entry_ids = ?locked, "Refusing to undo onto a locked layer");
+                            reply(
+                                &mut stream,
+                                &TcpPacket::Error(format!(
+                                    "Nothing was undone, entries {:?} are on a locked layer",
+                                    locked
+                                )),
+                            )?;
+                        }
+                    }
+
+                    let records =
+                        auditor.diff(AuditOperation::Undo, &before, &server_state.canvas.entries);
+                    server_state.audit(records);
+                }
+
+                TcpPacket::Disconnect => {
+                    info!("User disconnected");
+                    user_data.last_login = Some(std::time::Instant::now());
+                    server_state.disconnect_user(stream.try_clone()?)?;
+                    return Ok(());
+                }
+
+                TcpPacket::ClearRequest { only_owned } => {
+                    // Decide which entries to delete, leaving locked layers alone
+                    let canvas = &server_state.canvas;
+                    let ids_to_delete: Vec<EntryId> = canvas
+                        .entries
+                        .iter()
+                        .filter_map(|entry| {
+                            if only_owned && entry.author != user_data.username {
+                                return None;
+                            }
+                            if canvas.is_locked(entry.id) {
+                                return None;
                             }
+                            Some(entry.id)
+                        })
+                        .collect();
+
+                    info!(only_owned, cleared = ids_to_delete.len(), "Canvas cleared");
+
+                    let records: Vec<_> = server_state
+                        .canvas
+                        .entries
+                        .iter()
+                        .filter(|entry| ids_to_delete.contains(&entry.id))
+                        .map(|entry| {
+                            auditor.record(
+                                AuditOperation::Clear,
+                                entry.id,
+                                Some(&entry.element),
+                                None,
+                            )
+                        })
+                        .collect();
+                    server_state.audit(records);
+
+                    let cleared = server_state
+                        .canvas
+                        .entries
+                        .iter()
+                        .filter(|entry| ids_to_delete.contains(&entry.id))
+                        .cloned()
+                        .collect();
+                    let operation = Operation::Clear(cleared);
+                    server_state.record(&username, operation.clone(), &config.history);
+
+                    // Put the clear action in the user history
+                    user_data.action_history.push(operation);
+
+                    // actually delete them on server side
+                    server_state
+                        .canvas
+                        .entries
+                        .retain(|entry| !ids_to_delete.contains(&entry.id));
+
+                    // Prepare the update packet
+                    let clear_packet = TcpPacket::ClearResponse { ids_to_delete };
+
+                    // Update all the clients
+                    metrics.record_broadcast(server_state.broadcast(&clear_packet)?);
+                }
+
+                TcpPacket::LayerRequest(change) => {
+                    let layer = change.layer().map(|id| (id, server_state.canvas.layer(id)));
+
+                    match layer {
+                        Some((id, None)) => {
+                            reply(
+                                &mut stream,
+                                &TcpPacket::Error(format!("Layer {} does not exist", id)),
+                            )?;
+                        }
+                        Some((id, Some(layer))) if !can_manage(layer, &username, config) => {
+                            warn!(
+                                layer = id,
+                                "Refusing to change a layer owned by someone else"
+                            );
+                            reply(
+                                &mut stream,
+                                &TcpPacket::Error(format!(
+                                    "Only {} can change layer {}",
+                                    layer.owner.as_deref().unwrap_or_default(),
+                                    id
+                                )),
+                            )?;
                         }
-                        Action::Clear(prev_canvas_state) => {
-                            //Replace the whole canvas
-                            let actions = prev_canvas_state.entries.clone();
-                            server_state.canvas = prev_canvas_state;
-
-                            // Force all clients to full reload
-                            for connection in server_state.sessions.iter_mut() {
-                                let update_packet = TcpPacket::LoadCanvas(actions.clone());
-                                let packet_bytes = update_packet.to_bytes()?;
-                                connection.stream.write_all(&packet_bytes)?;
-                                connection.stream.flush()?;
+                        _ => {
+                            let created = matches!(change, LayerChange::Create(_));
+                            let before = server_state.canvas.layers.clone();
+                            if let Some(id) =
+                                server_state.canvas.change_layer(change.clone(), &username)
+                            {
+                                // New layers are drawn on right away by whoever created them
+                                if created {
+                                    user_data.layer = id;
+                                }
+
+                                info!(layer = id, ?change, "Layer changed");
+
+                                // Recorded so that playback and reverts bring the layers back too
+                                let operation = Operation::Layers {
+                                    before,
+                                    after: server_state.canvas.layers.clone(),
+                                };
+                                server_state.record(&username, operation, &config.history);
+
+                                let layers =
+                                    TcpPacket::LayerResponse(server_state.canvas.layers.clone());
+                                metrics.record_broadcast(server_state.broadcast(&layers)?);
                             }
                         }
                     }
                 }
-            }
 
-            TcpPacket::Disconnect => {
-                user_data.last_login = Some(std::time::Instant::now());
-                server_state.disconnect_user(stream)?;
-                server_state.users = users;
-                return Ok(());
-            }
+                TcpPacket::SelectLayer(id) => {
+                    if server_state.canvas.layer(id).is_some() {
+                        debug!(layer = id, "Layer selected");
+                        user_data.layer = id;
+                    } else {
+                        reply(
+                            &mut stream,
+                            &TcpPacket::Error(format!("Layer {} does not exist", id)),
+                        )?;
+                    }
+                }
+
+                TcpPacket::LiveStrokeRequest { points, style } => {
+                    let passed_on = server_state
+                        .get_session_mut(&stream)
+                        .is_some_and(|session| session.stroke_limiter.try_take());
+
+                    let pending = server_state
+                        .live_strokes
+                        .entry(username.clone())
+                        .or_default();
+                    pending.extend(points);
+
+                    if passed_on {
+                        let points = std::mem::take(pending);
+                        trace!(points = points.len(), "Passing on live stroke");
 
-            TcpPacket::ClearRequest { only_owned } => {
-                // Save the previous state of the canvas
-                let prev_canvas_state = server_state.canvas.clone();
-
-                // Put the clear action in the user history
-                user_data
-                    .action_history
-                    .push(Action::Clear(prev_canvas_state));
-
-                // Decide which entries to delete
-                let ids_to_delete = server_state
-                    .canvas
-                    .entries
-                    .iter_mut()
-                    .filter_map(|entry| {
-                        if only_owned && entry.author != user_data.username {
-                            return None;
+                        let packet = TcpPacket::LiveStrokeResponse {
+                            author: username.clone(),
+                            points,
+                            style,
+                        };
+                        metrics.record_broadcast(
+                            server_state.broadcast_to_others(&packet, &username)?,
+                        );
+                    }
+                }
+
+                TcpPacket::BlobChunk { id, size, .. } if size > config.limits.max_blob_size => {
+                    warn!(
+                        blob = %id,
+                        size,
+                        "Refusing an oversized blob"
+                    );
+                    if let Some(session) = server_state.get_session_mut(&stream) {
+                        session.upload = None;
+                    }
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error(format!(
+                            "Blobs cannot be larger than {} bytes",
+                            config.limits.max_blob_size
+                        )),
+                    )?;
+                }
+
+                TcpPacket::BlobChunk { id, size, .. }
+                    if config.limits.max_blob_storage.is_some_and(|max| {
+                        server_state.blobs.used().saturating_add(size) > max
+                    }) =>
+                {
+                    warn!(
+                        blob = %id,
+                        size,
+                        "Refusing a blob there is no room for"
+                    );
+                    if let Some(session) = server_state.get_session_mut(&stream) {
+                        session.upload = None;
+                    }
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error(ServerError::BlobStorageFull.to_string()),
+                    )?;
+                }
+
+                TcpPacket::BlobChunk {
+                    id,
+                    size,
+                    offset,
+                    data,
+                } => {
+                    let received = match server_state.get_session_mut(&stream) {
+                        Some(session) => {
+                            // The first chunk starts a new upload, dropping any unfinished one
+                            if offset == 0 {
+                                session.upload = Some(PartialBlob::new(id, size));
+                            }
+
+                            match session.upload.as_mut() {
+                                Some(upload) if upload.id == id && upload.size == size => {
+                                    let received = upload.push(offset, &data);
+                                    if !matches!(received, Ok(None)) {
+                                        session.upload = None;
+                                    }
+                                    received
+                                }
+                                _ => Err(ServerError::InvalidBlob(format!(
+                                    "{}: its upload was not started",
+                                    id
+                                ))
+                                .into()),
+                            }
                         }
-                        Some(entry.id)
-                    })
-                    .collect();
-
-                // actually delete them on server side
-                server_state.canvas.entries.retain(|entry| {
-                    if only_owned && entry.author != user_data.username {
-                        return true;
+                        None => Ok(None),
+                    };
+
+                    match received {
+                        Ok(None) => {}
+                        Ok(Some(data)) => blob_transfer = Some(BlobTransfer::Store(id, data)),
+                        Err(e) => {
+                            warn!(
+                                blob = %id,
+                                "Refusing blob: {e}"
+                            );
+                            reply(&mut stream, &TcpPacket::Error(e.to_string()))?;
+                        }
+                    }
+                }
+
+                TcpPacket::BlobRequest(id) => blob_transfer = Some(BlobTransfer::Send(id)),
+
+                TcpPacket::AdminUndo(_) | TcpPacket::AdminRevert(_)
+                    if !config.is_admin(&username) =>
+                {
+                    warn!("Refusing admin request");
+                    reply(
+                        &mut stream,
+                        &TcpPacket::Error(
+                            "Only admins can undo or revert the whole room".to_string(),
+                        ),
+                    )?;
+                }
+
+                TcpPacket::AdminUndo(count) => {
+                    let history = &server_state.history;
+                    let revision = history
+                        .latest()
+                        .saturating_sub(count)
+                        .max(history.base_revision);
+
+                    revert_room(&mut server_state, revision, &auditor, config, metrics)?;
+
+                    info!(count, revision, "Room undone");
+                }
+
+                TcpPacket::AdminRevert(revision) => {
+                    let history = &server_state.history;
+                    let (oldest, latest) = (history.base_revision, history.latest());
+
+                    if (oldest..=latest).contains(&revision) {
+                        revert_room(&mut server_state, revision, &auditor, config, metrics)?;
+
+                        info!(revision, "Room reverted");
+                    } else {
+                        reply(
+                            &mut stream,
+                            &TcpPacket::Error(format!(
+                                "Revision {} is not available, only {} to {} are kept",
+                                revision, oldest, latest
+                            )),
+                        )?;
                     }
-                    false
-                });
+                }
 
-                // Prepare the update packet
-                let clear_packet = TcpPacket::ClearResponse { ids_to_delete };
+                TcpPacket::HistoryRequest => {
+                    let max_size = config.limits.max_packet_size as usize;
+                    match history_packet(&server_state.history, max_size)? {
+                        Some(packet) => {
+                            if let TcpPacket::HistoryResponse(history) = &packet {
+                                debug!(
+                                    revisions = history.revisions.len(),
+                                    kept = server_state.history.revisions.len(),
+                                    "Sending history"
+                                );
+                            }
+                            reply(&mut stream, &packet)?;
+                        }
+                        None => {
+                            warn!("History too large to send");
+                            reply(
+                                &mut stream,
+                                &TcpPacket::Error(
+                                    "The canvas is too large to be played back".to_string(),
+                                ),
+                            )?;
+                        }
+                    }
+                }
 
-                // Update all the clients
-                for connection in server_state.sessions.iter_mut() {
-                    let packet_bytes = clear_packet.to_bytes()?;
-                    connection.stream.write_all(&packet_bytes)?;
-                    connection.stream.flush()?;
+                TcpPacket::CanvasAtRequest(point) => {
+                    let revision = server_state.history.resolve(point);
+                    let canvas = server_state.history.canvas_at(revision);
+                    debug!(revision, "Sending canvas at revision");
+                    reply(
+                        &mut stream,
+                        &TcpPacket::CanvasAtResponse {
+                            revision,
+                            entries: canvas.entries,
+                            layers: canvas.layers,
+                        },
+                    )?;
                 }
+
+                _ => {}
             }
 
-            _ => {}
-        }
+            if ends_stroke {
+                metrics.record_broadcast(server_state.end_live_stroke(&username)?);
+            }
+
+            if let Some(max_actions) = config.history.max_actions {
+                let excess = user_data.action_history.len().saturating_sub(max_actions);
+                user_data.action_history.drain(..excess);
+            }
+            Ok(())
+        })();
+        server_state.users.insert(user.username.clone(), user);
+        result?;
     } else if let TcpPacket::Connect(nickname) = packet {
-        if let Err(Error::ServerError(ServerError::UsernameTaken(s))) =
-            server_state.connect_user(&stream, nickname.clone())
-        {
-            error!("Username {} is already connected", s);
-            return Err(ServerError::UsernameTaken(s).into());
+        span.record("username", field::display(&nickname));
+        match server_state.connect_user(&stream, nickname.clone(), &config.limits) {
+            Err(Error::ServerError(ServerError::UsernameTaken(s))) => {
+                error!("Username already connected");
+                return Err(ServerError::UsernameTaken(s).into());
+            }
+            Err(Error::ServerError(ServerError::ServerFull)) => {
+                let notification_packet =
+                    TcpPacket::Notification(ServerError::ServerFull.to_string());
+                stream.write_all(&notification_packet.to_bytes()?)?;
+                stream.flush()?;
+                error!("Server full");
+                return Err(ServerError::ServerFull.into());
+            }
+            _ => {}
         }
 
         let update_packet = TcpPacket::LoadCanvas(server_state.canvas.entries.clone());
@@ -245,7 +938,8 @@ pub fn handle_client(mut stream: TcpStream, server_state: Arc<Mutex<ServerState>
             },
         ));
 
-        let user = users
+        let user = server_state
+            .users
             .entry(nickname.clone())
             .or_insert(UserData::new(&nickname));
 
@@ -253,9 +947,8 @@ pub fn handle_client(mut stream: TcpStream, server_state: Arc<Mutex<ServerState>
             Some(last_login) => {
                 let now = std::time::Instant::now();
 
-                // TODO: Increase the time limit to one minute
-                if now.duration_since(last_login).as_secs() > 60 {
-                    info!("Clearing {}'s action history", nickname);
+                if now.duration_since(last_login).as_secs() > config.history.expiry_secs {
+                    info!("Action history expired");
                     user.action_history.clear();
                 }
 
@@ -263,7 +956,7 @@ pub fn handle_client(mut stream: TcpStream, server_state: Arc<Mutex<ServerState>
             }
 
             None => {
-                info!("User {} connected", nickname);
+                info!("User connected");
                 user.last_login = Some(std::time::Instant::now());
             }
         }
@@ -281,11 +974,656 @@ pub fn handle_client(mut stream: TcpStream, server_state: Arc<Mutex<ServerState>
         // reply to the user trying to connect
         stream.write_all(&packet_bytes)?;
         stream.flush()?;
+        reply(
+            &mut stream,
+            &TcpPacket::LayerResponse(server_state.canvas.layers.clone()),
+        )?;
     } else {
         return Err(ServerError::UserNotFound.into());
     }
 
-    server_state.users = users;
+    if let Some(transfer) = blob_transfer {
+        let blobs = server_state.blobs.clone();
+        drop(server_state);
+        drop(lock_timer);
+        transfer_blob(&mut stream, &blobs, transfer, config)?;
+    }
 
     Ok(())
 }
+
+/// What is left to do with a blob once the state is unlocked.
+enum BlobTransfer {
+    /// Stores a blob that was received in full
+    Store(BlobId, Vec<u8>),
+    /// Sends a blob to the client that asked for it
+    Send(BlobId),
+}
+
+fn transfer_blob(
+    stream: &mut TcpStream,
+    blobs: &BlobStore,
+    transfer: BlobTransfer,
+    config: &ServerConfig,
+) -> Result<()> {
+    match transfer {
+        BlobTransfer::Store(id, data) => {
+            let size = data.len();
+            match blobs.put(id, data, config.limits.max_blob_storage) {
+                Ok(()) => {
+                    info!(blob = %id, size, "Blob stored");
+                    reply(stream, &TcpPacket::BlobStored(id))
+                }
+                // Other uploads took the room left while this one was received
+                Err(Error::ServerError(e @ ServerError::BlobStorageFull)) => {
+                    warn!(blob = %id, size, "Refusing a blob there is no room for");
+                    reply(stream, &TcpPacket::Error(e.to_string()))
+                }
+                Err(e) => {
+                    error!(blob = %id, "Failed to store blob: {e}");
+                    reply(
+                        stream,
+                        &TcpPacket::Error(format!("Blob {} could not be stored", id)),
+                    )
+                }
+            }
+        }
+
+        BlobTransfer::Send(id) => match blobs.get(id) {
+            Ok(Some(data)) => {
+                debug!(blob = %id, size = data.len(), "Sending blob");
+                for packet in chunk_packets(id, &data) {
+                    reply(stream, &packet)?;
+                }
+                Ok(())
+            }
+            Ok(None) => reply(
+                stream,
+                &TcpPacket::Error(format!("Blob {} does not exist", id)),
+            ),
+            Err(e) => {
+                error!(blob = %id, "Failed to read blob: {e}");
+                reply(
+                    stream,
+                    &TcpPacket::Error(format!("Blob {} could not be read", id)),
+                )
+            }
+        },
+    }
+}
+
+/// Brings the whole canvas back to how it was right after `revision`, as a
+/// single new revision made by the admin.
+fn revert_room(
+    server_state: &mut ServerState,
+    revision: u64,
+    auditor: &AuditContext,
+    config: &ServerConfig,
+    metrics: &Metrics,
+) -> Result<()> {
+    let before = server_state.canvas.entries.clone();
+    let target = server_state.history.canvas_at(revision);
+    let after = target.entries;
+    server_state.audit(auditor.diff(AuditOperation::Revert, &before, &after));
+
+    let mut operations = vec![Operation::Replace { before, after }];
+    if server_state.canvas.layers != target.layers {
+        operations.push(Operation::Layers {
+            before: server_state.canvas.layers.clone(),
+            after: target.layers,
+        });
+    }
+    let operation = Operation::Batch(operations);
+    metrics.record_broadcast(server_state.apply(auditor.username, operation, &config.history)?);
+
+    Ok(())
+}
+
+/// Whether the user may rename, hide, lock or move the layer.
+fn can_manage(layer: &Layer, username: &str, config: &ServerConfig) -> bool {
+    layer.owner.as_ref().is_none_or(|owner| owner == username) || config.is_admin(username)
+}
+
+/// The ids of the entries a group change would touch.
+fn group_members(server_state: &ServerState, change: &GroupChange) -> Vec<EntryId> {
+    match change {
+        GroupChange::Create(_, ids) => ids.clone(),
+        _ => server_state
+            .canvas
+            .group(change.group())
+            .iter()
+            .map(|entry| entry.id)
+            .collect(),
+    }
+}
+
+/// The history packet holding as many of the most recent revisions as fit in
+/// a packet of `max_size` bytes, the older ones being folded into its base, or
+/// [None] if not even the canvas they start from fits.
+fn history_packet(history: &History, max_size: usize) -> Result<Option<TcpPacket>> {
+    let mut kept = history.revisions.len();
+    loop {
+        let mut trimmed = history.clone();
+        trimmed.trim(kept);
+        let packet = TcpPacket::HistoryResponse(trimmed);
+
+        // The length header is not counted
+        if packet.to_bytes()?.len() - 4 <= max_size {
+            return Ok(Some(packet));
+        }
+        if kept == 0 {
+            return Ok(None);
+        }
+        kept /= 2;
+    }
+}
+
+/// Sends a packet back to the client whose request is being handled.
+fn reply(stream: &mut TcpStream, packet: &TcpPacket) -> Result<()> {
+    stream.write_all(&packet.to_bytes()?)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ns_core::models::{
+        blob::BLOB_CHUNK_SIZE,
+        canvas::{CanvasEntry, Point, Style, Transform},
+        history::HistoryPoint,
+    };
+
+    use super::*;
+    use crate::operations::test_server::{TestClient, TestServer};
+
+    fn circle() -> CanvasElement {
+        CanvasElement::Circle {
+            x: 50,
+            y: 50,
+            radius: 10,
+            style: Style::filled([0, 0, 0, 255]),
+        }
+    }
+
+    fn draw(client: &mut TestClient) -> CanvasEntry {
+        client.send(&TcpPacket::DrawRequest(circle()));
+        match client.expect(|packet| matches!(packet, TcpPacket::DrawResponse(_))) {
+            TcpPacket::DrawResponse(entry) => entry,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Sends transforms that must all be refused, checking the entry is left
+    /// alone and that the connection is still served afterwards.
+    fn assert_refused(transforms: &[Transform]) {
+        let server = TestServer::new(ServerConfig::default());
+        let mut alice = server.connect("alice");
+        let entry = draw(&mut alice);
+
+        for transform in transforms {
+            let request = TcpPacket::TransformRequest(vec![entry.id], *transform);
+            alice.expect_error(&request);
+        }
+        assert_eq!(server.state().canvas.get_entry(entry.id), Some(&entry));
+
+        alice.send(&TcpPacket::TransformRequest(
+            vec![entry.id],
+            Transform::Rotate(90.),
+        ));
+        alice.expect(|packet| matches!(packet, TcpPacket::TransformResponse(_, _)));
+    }
+
+    #[test]
+    fn rotations_that_are_not_finite_are_refused() {
+        assert_refused(&[
+            Transform::Rotate(f32::NAN),
+            Transform::Rotate(f32::INFINITY),
+            Transform::Rotate(f32::NEG_INFINITY),
+        ]);
+    }
+
+    #[test]
+    fn scale_factors_that_are_not_finite_are_refused() {
+        assert_refused(&[Transform::Scale(f32::NAN), Transform::Scale(f32::INFINITY)]);
+    }
+
+    #[test]
+    fn scale_factors_that_are_not_positive_are_refused() {
+        assert_refused(&[Transform::Scale(0.), Transform::Scale(-2.)]);
+    }
+
+    #[test]
+    fn extreme_translations_stop_at_the_edges() {
+        let server = TestServer::new(ServerConfig::default());
+        let mut alice = server.connect("alice");
+        let entry = draw(&mut alice);
+
+        for (dx, dy, x, y) in [
+            (i32::MAX, i32::MIN, u16::MAX, 0),
+            (i32::MIN, i32::MAX, 0, u16::MAX),
+        ] {
+            alice.send(&TcpPacket::TransformRequest(
+                vec![entry.id],
+                Transform::Translate(dx, dy),
+            ));
+            alice.expect(|packet| matches!(packet, TcpPacket::TransformResponse(_, _)));
+
+            let state = server.state();
+            let element = &state.canvas.get_entry(entry.id).unwrap().element;
+            assert!(
+                matches!(element, CanvasElement::Circle { x: ex, y: ey, .. } if (*ex, *ey) == (x, y)),
+                "{element:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn extreme_group_moves_stop_at_the_edges() {
+        let server = TestServer::new(ServerConfig::default());
+        let mut alice = server.connect("alice");
+        let entry = draw(&mut alice);
+        let group = "g".to_string();
+
+        for change in [
+            GroupChange::Create(group.clone(), vec![entry.id]),
+            GroupChange::Move(group.clone(), i32::MAX, 0),
+        ] {
+            alice.send(&TcpPacket::GroupRequest(change));
+        }
+        // Answered only once the group moves were handled
+        alice.send(&TcpPacket::CanvasAtRequest(HistoryPoint::Revision(0)));
+        alice.expect(|packet| matches!(packet, TcpPacket::CanvasAtResponse { .. }));
+
+        let state = server.state();
+        let element = &state.canvas.get_entry(entry.id).unwrap().element;
+        assert!(
+            matches!(
+                element,
+                CanvasElement::Circle {
+                    x: u16::MAX,
+                    y: 50,
+                    ..
+                }
+            ),
+            "{element:?}"
+        );
+    }
+
+    #[test]
+    fn the_history_sent_fits_in_a_packet() {
+        let mut config = ServerConfig::default();
+        config.limits.max_packet_size = 2000;
+        config.limits.mutation_burst = 1000;
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+
+        // A small canvas with a long history
+        let entry = draw(&mut alice);
+        for radius in 0..100 {
+            let element = CanvasElement::Circle {
+                x: 50,
+                y: 50,
+                radius,
+                style: Style::filled([0, 0, 0, 255]),
+            };
+            alice.send(&TcpPacket::UpdateRequest(entry.id, element));
+        }
+        alice.send(&TcpPacket::HistoryRequest);
+        let packet = alice.expect(|packet| matches!(packet, TcpPacket::HistoryResponse(_)));
+
+        assert!(packet.to_bytes().unwrap().len() - 4 <= 2000);
+        let TcpPacket::HistoryResponse(history) = packet else {
+            unreachable!();
+        };
+        // The most recent revisions are kept, the others folded into the base
+        assert_eq!(history.latest(), 101);
+        assert!(!history.revisions.is_empty() && history.revisions.len() < 101);
+        assert_eq!(
+            history.canvas_at(101).entries,
+            server.state().canvas.entries
+        );
+    }
+
+    #[test]
+    fn histories_of_canvases_too_large_for_a_packet_are_refused() {
+        let mut config = ServerConfig::default();
+        config.limits.max_packet_size = 2000;
+        config.limits.mutation_burst = 1000;
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+
+        for _ in 0..100 {
+            alice.send(&TcpPacket::DrawRequest(circle()));
+        }
+        alice.send(&TcpPacket::HistoryRequest);
+        let packet = alice.expect(|packet| matches!(packet, TcpPacket::Error(_)));
+        assert!(matches!(packet, TcpPacket::Error(message) if message.contains("too large")));
+    }
+
+    #[test]
+    fn users_are_kept_whatever_the_outcome_of_their_requests() {
+        let mut config = ServerConfig::default();
+        config.limits.mutations_per_sec = 0.001;
+        config.limits.mutation_burst = 3;
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+
+        draw(&mut alice);
+        let second = draw(&mut alice);
+        alice.send(&TcpPacket::Undo);
+        alice.expect(|packet| {
+            matches!(packet, TcpPacket::ClearResponse { ids_to_delete } if *ids_to_delete == [second.id])
+        });
+        // Refused before anything is changed
+        alice.expect_error(&TcpPacket::DrawRequest(circle()));
+
+        let state = server.state();
+        assert_eq!(state.users.len(), 1);
+        assert_eq!(state.users["alice"].action_history.len(), 1);
+    }
+
+    #[test]
+    fn throttled_requests_are_refused_without_disconnecting() {
+        let mut config = ServerConfig::default();
+        config.limits.mutations_per_sec = 0.001;
+        config.limits.mutation_burst = 2;
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+
+        draw(&mut alice);
+        draw(&mut alice);
+        let message = alice.expect_error(&TcpPacket::DrawRequest(circle()));
+        assert!(message.contains("Too many requests"), "{message}");
+        assert_eq!(server.state().count_entries_by("alice"), 2);
+
+        // Requests that change nothing are still served
+        alice.send(&TcpPacket::CanvasAtRequest(HistoryPoint::Revision(0)));
+        alice.expect(|packet| matches!(packet, TcpPacket::CanvasAtResponse { .. }));
+    }
+
+    #[test]
+    fn entries_beyond_the_quota_are_refused() {
+        let mut config = ServerConfig::default();
+        config.limits.max_entries_per_user = Some(1);
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        let entry = draw(&mut alice);
+        let message = alice.expect_error(&TcpPacket::DrawRequest(circle()));
+        assert!(message.contains("more than 1 entries"), "{message}");
+        assert_eq!(server.state().count_entries_by("alice"), 1);
+
+        // The quota is per user, and deleting frees it up again
+        draw(&mut bob);
+        alice.send(&TcpPacket::Delete(entry.id));
+        alice.expect(|packet| matches!(packet, TcpPacket::Delete(_)));
+        draw(&mut alice);
+    }
+
+    fn admin_config() -> ServerConfig {
+        let mut config = ServerConfig::default();
+        config.admin.users = vec!["alice".to_string()];
+        config
+    }
+
+    #[test]
+    fn only_admins_can_undo_the_room() {
+        let server = TestServer::new(admin_config());
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+        draw(&mut alice);
+        bob.expect(|packet| matches!(packet, TcpPacket::DrawResponse(_)));
+
+        let message = bob.expect_error(&TcpPacket::AdminUndo(1));
+        assert!(message.contains("Only admins"), "{message}");
+        assert_eq!(server.state().canvas.entries.len(), 1);
+        assert_eq!(server.state().history.latest(), 1);
+    }
+
+    #[test]
+    fn admin_undos_stop_at_the_oldest_revision_kept() {
+        let mut config = admin_config();
+        config.history.max_revisions = 2;
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        let entries: Vec<CanvasEntry> = (0..4).map(|_| draw(&mut alice)).collect();
+        assert_eq!(server.state().history.base_revision, 2);
+
+        alice.send(&TcpPacket::AdminUndo(100));
+
+        // Every session is sent what the undo removed
+        for client in [&mut alice, &mut bob] {
+            let packet = client.expect(|packet| matches!(packet, TcpPacket::ClearResponse { .. }));
+            let TcpPacket::ClearResponse { mut ids_to_delete } = packet else {
+                unreachable!();
+            };
+            ids_to_delete.sort();
+            assert_eq!(ids_to_delete, [entries[2].id, entries[3].id]);
+        }
+        let state = server.state();
+        let ids: Vec<EntryId> = state.canvas.entries.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [entries[0].id, entries[1].id]);
+    }
+
+    fn live_stroke(points: &[Point]) -> TcpPacket {
+        TcpPacket::LiveStrokeRequest {
+            points: points.to_vec(),
+            style: Style::solid([0, 0, 0, 255]),
+        }
+    }
+
+    fn relayed_points(client: &mut TestClient) -> Vec<Point> {
+        match client.expect(|packet| matches!(packet, TcpPacket::LiveStrokeResponse { .. })) {
+            TcpPacket::LiveStrokeResponse { author, points, .. } => {
+                assert_eq!(author, "alice");
+                points
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn live_strokes_are_coalesced_and_relayed_to_the_others() {
+        let mut config = ServerConfig::default();
+        config.limits.live_strokes_per_sec = 5.;
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        alice.send(&live_stroke(&[(1, 1)]));
+        assert_eq!(relayed_points(&mut bob), [(1, 1)]);
+
+        // Held back until the next one is let through
+        alice.send(&live_stroke(&[(2, 2)]));
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        alice.send(&live_stroke(&[(3, 3)]));
+        assert_eq!(relayed_points(&mut bob), [(2, 2), (3, 3)]);
+
+        // Never sent back to the one drawing
+        alice.send(&TcpPacket::DrawRequest(circle()));
+        let packet = alice.expect(|packet| !matches!(packet, TcpPacket::Notification(_)));
+        assert!(matches!(packet, TcpPacket::DrawResponse(_)), "{packet:?}");
+    }
+
+    #[test]
+    fn live_strokes_end_when_the_stroke_is_drawn() {
+        let server = TestServer::new(ServerConfig::default());
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        alice.send(&live_stroke(&[(1, 1), (2, 2)]));
+        relayed_points(&mut bob);
+        alice.send(&TcpPacket::DrawRequest(circle()));
+
+        bob.expect(|packet| matches!(packet, TcpPacket::DrawResponse(_)));
+        let packet = bob.receive();
+        assert!(
+            matches!(&packet, Some(TcpPacket::LiveStrokeEnd(author)) if author == "alice"),
+            "{packet:?}"
+        );
+        assert!(server.state().live_strokes.is_empty());
+    }
+
+    #[test]
+    fn live_strokes_are_discarded_when_their_author_leaves() {
+        let server = TestServer::new(ServerConfig::default());
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        alice.send(&live_stroke(&[(1, 1), (2, 2)]));
+        relayed_points(&mut bob);
+        alice.send(&TcpPacket::Disconnect);
+
+        bob.expect(
+            |packet| matches!(packet, TcpPacket::LiveStrokeEnd(author) if author == "alice"),
+        );
+        assert!(server.state().live_strokes.is_empty());
+    }
+
+    #[test]
+    fn streamed_packets_are_throttled_separately() {
+        let mut config = ServerConfig::default();
+        config.limits.mutations_per_sec = 0.001;
+        config.limits.mutation_burst = 1;
+        config.limits.streamed_per_sec = 0.001;
+        config.limits.streamed_burst = 2;
+        config.limits.max_blob_size = 2 * BLOB_CHUNK_SIZE as u64;
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+
+        // Neither takes from the other's tokens
+        draw(&mut alice);
+        alice.send(&live_stroke(&[(1, 1)]));
+
+        let blob = vec![1; BLOB_CHUNK_SIZE + 10];
+        let chunks = chunk_packets(BlobId::of(&blob), &blob);
+        alice.send(&chunks[0]);
+        let message = alice.expect_error(&chunks[1]);
+        assert!(message.contains("Too many requests"), "{message}");
+
+        // The rest of the blob could never arrive
+        let state = server.state();
+        let session = state.sessions.iter().find(|s| s.username == "alice");
+        assert!(session.unwrap().upload.is_none());
+    }
+
+    #[test]
+    fn layers_are_brought_back_by_playback_and_reverts() {
+        let server = TestServer::new(admin_config());
+        let mut alice = server.connect("alice");
+
+        draw(&mut alice);
+        let before_layers = server.state().history.latest();
+        alice.send(&TcpPacket::LayerRequest(LayerChange::Create(
+            "sketch".to_string(),
+        )));
+        alice.expect(|packet| matches!(packet, TcpPacket::LayerResponse(_)));
+        let sketch = server.state().canvas.layers[1].clone();
+        alice.send(&TcpPacket::LayerRequest(LayerChange::SetHidden(
+            sketch.id, true,
+        )));
+        alice.expect(|packet| matches!(packet, TcpPacket::LayerResponse(_)));
+
+        // The layer as it was right after it was created
+        alice.send(&TcpPacket::CanvasAtRequest(HistoryPoint::Revision(
+            before_layers + 1,
+        )));
+        let layers =
+            match alice.expect(|packet| matches!(packet, TcpPacket::CanvasAtResponse { .. })) {
+                TcpPacket::CanvasAtResponse { layers, .. } => layers,
+                _ => unreachable!(),
+            };
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[1], sketch);
+
+        alice.send(&TcpPacket::AdminRevert(before_layers));
+        let layers = match alice.expect(|packet| matches!(packet, TcpPacket::LayerResponse(_))) {
+            TcpPacket::LayerResponse(layers) => layers,
+            _ => unreachable!(),
+        };
+        assert_eq!(layers.len(), 1);
+
+        // Drawing goes back to the base layer once the one drawn on is gone
+        let entry = draw(&mut alice);
+        assert_eq!(entry.layer, BASE_LAYER);
+        assert!(server.state().validate().is_ok());
+    }
+
+    #[test]
+    fn uploaded_blobs_are_sent_back() {
+        let server = TestServer::new(ServerConfig::default());
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        let blob: Vec<u8> = (0..BLOB_CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let id = BlobId::of(&blob);
+        for packet in chunk_packets(id, &blob) {
+            alice.send(&packet);
+        }
+        alice.expect(|packet| matches!(packet, TcpPacket::BlobStored(stored) if *stored == id));
+
+        bob.send(&TcpPacket::BlobRequest(id));
+        let mut received = PartialBlob::new(id, blob.len() as u64);
+        loop {
+            let TcpPacket::BlobChunk { offset, data, .. } =
+                bob.expect(|packet| matches!(packet, TcpPacket::BlobChunk { .. }))
+            else {
+                unreachable!();
+            };
+            if let Some(data) = received.push(offset, &data).unwrap() {
+                assert_eq!(data, blob);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn images_are_only_drawn_once_their_blob_is_uploaded() {
+        let server = TestServer::new(ServerConfig::default());
+        let mut alice = server.connect("alice");
+
+        let blob = b"not really an image".to_vec();
+        let id = BlobId::of(&blob);
+        let image = CanvasElement::Image {
+            x: 10,
+            y: 10,
+            width: 20,
+            height: 20,
+            blob: id,
+            style: Style::solid([0, 0, 0, 255]),
+        };
+        let message = alice.expect_error(&TcpPacket::DrawRequest(image.clone()));
+        assert!(message.contains("was not uploaded"), "{message}");
+
+        for packet in chunk_packets(id, &blob) {
+            alice.send(&packet);
+        }
+        alice.expect(|packet| matches!(packet, TcpPacket::BlobStored(_)));
+        alice.send(&TcpPacket::DrawRequest(image));
+        alice.expect(|packet| matches!(packet, TcpPacket::DrawResponse(_)));
+    }
+
+    #[test]
+    fn blobs_are_refused_once_the_storage_is_full() {
+        let mut config = ServerConfig::default();
+        config.limits.max_blob_storage = Some(BLOB_CHUNK_SIZE as u64 + 20);
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+
+        let first: Vec<u8> = (0..BLOB_CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        for packet in chunk_packets(BlobId::of(&first), &first) {
+            alice.send(&packet);
+        }
+        alice.expect(|packet| matches!(packet, TcpPacket::BlobStored(_)));
+
+        let second = vec![1; 20];
+        let chunks = chunk_packets(BlobId::of(&second), &second);
+        let message = alice.expect_error(&chunks[0]);
+        assert!(message.contains("no room left"), "{message}");
+        assert_eq!(server.state().blobs.used(), first.len() as u64);
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use ns_core::errors::Result;
+use tracing::{debug, error, info};
+
+use super::check_writable;
+use crate::models::{Gauges, Metrics, ServerState};
+
+/// Everything the monitoring endpoints report on.
+pub struct HttpContext {
+    pub server_state: Arc<Mutex<ServerState>>,
+    pub metrics: Arc<Metrics>,
+    /// Whether the main listener is accepting connections
+    pub listener_bound: Arc<AtomicBool>,
+    pub snapshot_path: Option<PathBuf>,
+}
+
+/// Serves the monitoring endpoints over plain HTTP, one request at a time.
+/// This blocks forever, so it is meant to run on its own thread.
+///
+/// - `/metrics` exposes the [Metrics] in the Prometheus text format.
+/// - `/healthz` fails only if the server state lock is poisoned.
+/// - `/readyz` also fails while the listener is not bound or when the
+///   snapshot cannot be written.
+pub fn serve_http(listener: TcpListener, context: HttpContext) {
+    if let Ok(address) = listener.local_addr() {
+        info!(%address, "Serving monitoring endpoints");
+    }
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle_request(stream, &context) {
+                    debug!("Failed to answer HTTP request: {e}");
+                }
+            }
+            Err(e) => {
+                error!("{e}", e = e.kind());
+            }
+        }
+    }
+}
+
+fn handle_request(mut stream: TcpStream, context: &HttpContext) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => {
+            let gauges = {
+                // A poisoned lock still holds readable numbers
+                let server_state = context
+                    .server_state
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                Gauges {
+                    sessions: server_state.sessions.len(),
+                    canvas_entries: server_state.canvas.entries.len(),
+                }
+            };
+            ("200 OK", context.metrics.render(&gauges))
+        }
+        (Some("GET"), Some("/healthz")) => report(&[state_check(context)]),
+        (Some("GET"), Some("/readyz")) => report(&[
+            state_check(context),
+            listener_check(context),
+            persistence_check(context),
+        ]),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// The outcome of a single check: its name and the reason it failed, if it did.
+type Check = (&'static str, std::result::Result<(), String>);
+
+/// Answers with one line per check, failing if any of them did.
+fn report(checks: &[Check]) -> (&'static str, String) {
+    let body = checks
+        .iter()
+        .map(|(name, outcome)| match outcome {
+            Ok(()) => format!("{name}: ok\n"),
+            Err(reason) => format!("{name}: {reason}\n"),
+        })
+        .collect();
+
+    if checks.iter().all(|(_, outcome)| outcome.is_ok()) {
+        ("200 OK", body)
+    } else {
+        ("503 Service Unavailable", body)
+    }
+}
+
+fn state_check(context: &HttpContext) -> Check {
+    if context.server_state.is_poisoned() {
+        ("state", Err("lock poisoned".to_string()))
+    } else {
+        ("state", Ok(()))
+    }
+}
+
+fn listener_check(context: &HttpContext) -> Check {
+    if context.listener_bound.load(Ordering::SeqCst) {
+        ("listener", Ok(()))
+    } else {
+        ("listener", Err("not bound".to_string()))
+    }
+}
+
+fn persistence_check(context: &HttpContext) -> Check {
+    match &context.snapshot_path {
+        Some(path) => match check_writable(path) {
+            Ok(()) => ("persistence", Ok(())),
+            Err(e) => ("persistence", Err(format!("not writable: {e}"))),
+        },
+        None => ("persistence", Ok(())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, thread::spawn};
+
+    use super::*;
+
+    fn context() -> HttpContext {
+        HttpContext {
+            server_state: Arc::new(Mutex::new(ServerState::new())),
+            metrics: Arc::new(Metrics::new()),
+            listener_bound: Arc::new(AtomicBool::new(true)),
+            snapshot_path: None,
+        }
+    }
+
+    /// Sends a raw request line, returning the status line and the body.
+    fn request(context: HttpContext, request_line: &str) -> (String, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = spawn(move || handle_request(stream, &context));
+
+        write!(client, "{request_line}\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap().unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let content_length = format!("Content-Length: {}", body.len());
+        assert!(head.contains(&content_length), "{head}");
+        let status = head.lines().next().unwrap().to_string();
+        (status, body.to_string())
+    }
+
+    #[test]
+    fn metrics_are_served() {
+        let context = context();
+        context.metrics.record_decode_error();
+
+        let (status, body) = request(context, "GET /metrics HTTP/1.1");
+
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains("netsketch_sessions 0\n"), "{body}");
+        assert!(body.contains("netsketch_decode_errors_total 1\n"), "{body}");
+    }
+
+    #[test]
+    fn health_fails_once_the_state_is_poisoned() {
+        let (status, body) = request(context(), "GET /healthz HTTP/1.1");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, "state: ok\n");
+
+        let context = context();
+        let server_state = context.server_state.clone();
+        let _ = spawn(move || {
+            let _guard = server_state.lock().unwrap();
+            panic!("poisoning the state");
+        })
+        .join();
+
+        let (status, body) = request(context, "GET /healthz HTTP/1.1");
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(body, "state: lock poisoned\n");
+    }
+
+    #[test]
+    fn readiness_checks_the_listener_and_the_snapshot() {
+        let (status, body) = request(context(), "GET /readyz HTTP/1.1");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, "state: ok\nlistener: ok\npersistence: ok\n");
+
+        let context = HttpContext {
+            listener_bound: Arc::new(AtomicBool::new(false)),
+            snapshot_path: Some(PathBuf::from("/netsketch/missing/canvas.snapshot")),
+            ..self::context()
+        };
+        let (status, body) = request(context, "GET /readyz HTTP/1.1");
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert!(body.contains("listener: not bound\n"), "{body}");
+        assert!(body.contains("persistence: not writable"), "{body}");
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        for (request_line, expected) in [
+            ("GET /missing HTTP/1.1", "HTTP/1.1 404 Not Found"),
+            ("GET", "HTTP/1.1 404 Not Found"),
+            ("POST /metrics HTTP/1.1", "HTTP/1.1 405 Method Not Allowed"),
+            ("not http at all", "HTTP/1.1 405 Method Not Allowed"),
+            ("", "HTTP/1.1 405 Method Not Allowed"),
+        ] {
+            let (status, _) = request(context(), request_line);
+            assert_eq!(status, expected, "{request_line:?}");
+        }
+    }
+}
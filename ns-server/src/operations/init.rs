@@ -1,32 +1,65 @@
 use std::{net::TcpListener, process::exit};
 
-use crate::Args;
+use crate::config::{LogFormat, LogRotation, ServerConfig};
 use ns_core::errors::Result;
-use tracing::{error, info, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing::{error, info};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
 
-pub fn init_server(args: Args) -> Result<TcpListener> {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::TRACE)
-        .finish();
+pub fn init_server(config: &ServerConfig) -> Result<TcpListener> {
+    init_logging(config);
 
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    let address = &config.network.address;
+    let port = config.network.port;
 
-    let server = match TcpListener::bind(format!("{}:{}", args.address, args.port)) {
+    let server = match TcpListener::bind(format!("{}:{}", address, port)) {
         Ok(server) => server,
         Err(e) => {
-            error!(
-                "Failed to bind the server to {}:{}\nReason: {}",
-                args.address, args.port, e
-            );
+            error!(address = %address, port, reason = %e, "Failed to bind the server");
             exit(1);
         }
     };
 
-    info!(
-        "Listening for connections on {}:{}...",
-        args.address, args.port
-    );
+    info!(address = %address, port, room = %config.room, "Listening for connections");
 
     Ok(server)
 }
+
+/// Logs to stdout and, if a directory is configured, to rolling log files.
+/// `RUST_LOG` takes precedence over the level in the configuration.
+fn init_logging(config: &ServerConfig) {
+    let logging = &config.logging;
+
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&logging.level));
+
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = Vec::new();
+
+    layers.push(match logging.format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    });
+
+    if let Some(directory) = &logging.directory {
+        let rotation = match logging.rotation {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        };
+        let appender = RollingFileAppender::new(rotation, directory, &logging.file_prefix);
+
+        layers.push(match logging.format {
+            LogFormat::Text => fmt::layer().with_ansi(false).with_writer(appender).boxed(),
+            LogFormat::Json => fmt::layer().json().with_writer(appender).boxed(),
+        });
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .try_init()
+        .expect("setting default subscriber failed");
+}
@@ -0,0 +1,119 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use bincode::{config, Decode, Encode};
+use ns_core::errors::{Result, ServerError};
+use ns_core::models::canvas::{Canvas, CanvasEntry, Layer, LayerId, SiteId};
+
+mod legacy;
+
+/// Bumped whenever the layout of [Snapshot] changes, so that older snapshots
+/// can still be told apart and migrated when loading.
+///
+/// - 1: entry ids were plain numbers handed out by the server, and elements
+///   only had a colour
+/// - 7: entries have [EntryId](ns_core::models::canvas::EntryId)s, a z-order,
+///   a layer, a group and a rotation, and elements have a
+///   [Style](ns_core::models::canvas::Style)
+///
+/// Versions 2 to 6 were never released and are refused.
+const SNAPSHOT_VERSION: u32 = 7;
+
+/// The on-disk representation of the canvas.
+/// ```plaintext
+/// | 4 bytes | n bytes  |
+/// | version | snapshot |
+/// ```
+/// where `version` is a little-endian u32 and `snapshot` is the encoded [Snapshot].
+#[derive(Encode, Decode)]
+struct Snapshot {
+    site: SiteId,
+    next_counter: u64,
+    entries: Vec<CanvasEntry>,
+    layers: Vec<Layer>,
+    next_layer: LayerId,
+}
+
+/// Writes the canvas to `path`, going through a temporary file so that a
+/// crash halfway through never leaves a truncated snapshot behind.
+pub fn save_snapshot(canvas: &Canvas, path: &Path) -> Result<()> {
+    let snapshot = Snapshot {
+        site: canvas.site,
+        next_counter: canvas.next_counter,
+        entries: canvas.entries.clone(),
+        layers: canvas.layers.clone(),
+        next_layer: canvas.next_layer,
+    };
+
+    let payload = bincode::encode_to_vec(&snapshot, config::standard())?;
+    let bytes = [SNAPSHOT_VERSION.to_le_bytes().to_vec(), payload].concat();
+
+    let temporary_path = temporary_path(path);
+    fs::write(&temporary_path, bytes)?;
+    fs::rename(&temporary_path, path)?;
+
+    Ok(())
+}
+
+/// Reads the canvas back from `path`, or returns [None] if no snapshot was
+/// saved there yet.
+pub fn load_snapshot(path: &Path) -> Result<Option<Canvas>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if bytes.len() < 4 {
+        return Err(ServerError::SnapshotError(format!("{} is truncated", path.display())).into());
+    }
+
+    let (version, payload) = bytes.split_at(4);
+    let version = u32::from_le_bytes(version.try_into().unwrap());
+
+    let snapshot: Snapshot = match version {
+        SNAPSHOT_VERSION => bincode::decode_from_slice(payload, config::standard())?.0,
+        _ => match legacy::migrate(version, payload)? {
+            Some(snapshot) => snapshot,
+            None => {
+                return Err(ServerError::SnapshotError(format!(
+                    "{} has unsupported version {}",
+                    path.display(),
+                    version
+                ))
+                .into())
+            }
+        },
+    };
+
+    Ok(Some(Canvas {
+        entries: snapshot.entries,
+        site: snapshot.site,
+        next_counter: snapshot.next_counter,
+        layers: snapshot.layers,
+        next_layer: snapshot.next_layer,
+    }))
+}
+
+/// Checks that a snapshot could be saved at `path` by writing and removing a
+/// probe file next to it.
+pub fn check_writable(path: &Path) -> Result<()> {
+    let probe_path = sibling_path(path, ".probe");
+    fs::write(&probe_path, [])?;
+    fs::remove_file(&probe_path)?;
+
+    Ok(())
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    sibling_path(path, ".tmp")
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
@@ -0,0 +1,289 @@
+//! The layout of snapshots from before entries had ids of their own, and how
+//! they are migrated to the current one.
+
+use bincode::{config, Decode, Encode};
+use ns_core::errors::Result;
+use ns_core::models::canvas::{
+    Canvas, CanvasElement, CanvasEntry, EntryId, Style, BASE_LAYER, SERVER_SITE,
+};
+
+use super::Snapshot;
+
+/// Decodes a snapshot of an older `version` and migrates it to the current
+/// layout, or returns [None] if there never was such a version.
+///
+/// Versions 2 to 6 were never released, so only version 1 is migrated.
+pub(super) fn migrate(version: u32, payload: &[u8]) -> Result<Option<Snapshot>> {
+    match version {
+        1 => {
+            let snapshot: SnapshotV1 = bincode::decode_from_slice(payload, config::standard())?.0;
+            Ok(Some(snapshot.into()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The elements of version 1 snapshots, which only had a colour.
+#[derive(Encode, Decode)]
+#[cfg_attr(test, derive(serde::Serialize))]
+enum ElementV1 {
+    Line {
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        colour: [u8; 4],
+    },
+    Circle {
+        x: u16,
+        y: u16,
+        radius: u16,
+        colour: [u8; 4],
+    },
+    Rect {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        colour: [u8; 4],
+    },
+    Text {
+        x: u16,
+        y: u16,
+        text: String,
+        colour: [u8; 4],
+    },
+}
+
+impl From<ElementV1> for CanvasElement {
+    /// Lines and text keep being drawn 5 units wide and 50 units high, and
+    /// shapes filled without an outline.
+    fn from(element: ElementV1) -> Self {
+        match element {
+            ElementV1::Line {
+                x1,
+                y1,
+                x2,
+                y2,
+                colour,
+            } => CanvasElement::Line {
+                x1,
+                y1,
+                x2,
+                y2,
+                style: Style::solid(colour),
+            },
+            ElementV1::Circle {
+                x,
+                y,
+                radius,
+                colour,
+            } => CanvasElement::Circle {
+                x,
+                y,
+                radius,
+                style: Style::filled(colour),
+            },
+            ElementV1::Rect {
+                x,
+                y,
+                width,
+                height,
+                colour,
+            } => CanvasElement::Rect {
+                x,
+                y,
+                width,
+                height,
+                style: Style::filled(colour),
+            },
+            ElementV1::Text { x, y, text, colour } => CanvasElement::Text {
+                x,
+                y,
+                text,
+                style: Style::solid(colour),
+            },
+        }
+    }
+}
+
+/// The layout of version 1 snapshots.
+#[derive(Encode, Decode)]
+struct SnapshotV1 {
+    current_action_id: usize,
+    entries: Vec<EntryV1>,
+}
+
+#[derive(Encode, Decode)]
+struct EntryV1 {
+    id: usize,
+    shown: bool,
+    element: ElementV1,
+    author: String,
+}
+
+impl From<SnapshotV1> for Snapshot {
+    /// Every entry was created by the server, so the old ids become the
+    /// counters of server entries, which keeps them the same on the prompt.
+    /// Entries were drawn in the order they were stored, which becomes their
+    /// z-order, and all go on the base layer of a new canvas.
+    fn from(snapshot: SnapshotV1) -> Self {
+        let canvas = Canvas::new();
+
+        Snapshot {
+            site: SERVER_SITE,
+            next_counter: snapshot.current_action_id as u64,
+            entries: snapshot
+                .entries
+                .into_iter()
+                .zip(0..)
+                .map(|(entry, z)| CanvasEntry {
+                    id: EntryId::new(SERVER_SITE, entry.id as u64),
+                    shown: entry.shown,
+                    element: entry.element.into(),
+                    author: entry.author,
+                    z,
+                    layer: BASE_LAYER,
+                    group: None,
+                    rotation: 0.,
+                })
+                .collect(),
+            layers: canvas.layers,
+            next_layer: canvas.next_layer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::{load_snapshot, save_snapshot, SNAPSHOT_VERSION};
+    use super::*;
+
+    #[test]
+    fn version_1_snapshots_are_migrated() {
+        let path = std::env::temp_dir().join(format!(
+            "netsketch-migration-{}.snapshot",
+            std::process::id()
+        ));
+
+        let snapshot = SnapshotV1 {
+            current_action_id: 8,
+            entries: vec![
+                EntryV1 {
+                    id: 7,
+                    shown: true,
+                    element: ElementV1::Circle {
+                        x: 10,
+                        y: 10,
+                        radius: 5,
+                        colour: [0, 0, 0, 255],
+                    },
+                    author: "alice".to_string(),
+                },
+                EntryV1 {
+                    id: 3,
+                    shown: true,
+                    element: ElementV1::Line {
+                        x1: 20,
+                        y1: 20,
+                        x2: 40,
+                        y2: 40,
+                        colour: [255, 0, 0, 255],
+                    },
+                    author: "bob".to_string(),
+                },
+            ],
+        };
+        let payload = bincode::encode_to_vec(&snapshot, config::standard()).unwrap();
+        fs::write(&path, [1u32.to_le_bytes().to_vec(), payload].concat()).unwrap();
+
+        let canvas = load_snapshot(&path).unwrap().unwrap();
+        assert_eq!(canvas.entries[0].id, EntryId::new(SERVER_SITE, 7));
+        assert_eq!(canvas.entries[0].author, "alice");
+        assert_eq!(canvas.site, SERVER_SITE);
+        assert_eq!(canvas.next_counter, 8);
+        // The entry stored last was drawn on top
+        assert!(canvas.entries[1].z > canvas.entries[0].z);
+        assert!(canvas.entries.iter().all(|entry| entry.layer == BASE_LAYER));
+        assert_eq!(canvas.layers.len(), 1);
+        // Shapes stay filled and lines keep their width
+        assert_eq!(
+            *canvas.entries[0].element.style(),
+            Style::filled([0, 0, 0, 255])
+        );
+        assert_eq!(canvas.entries[1].element.style().stroke_width, 5);
+
+        // Saving it again writes the current version
+        save_snapshot(&canvas, &path).unwrap();
+        assert_eq!(
+            fs::read(&path).unwrap()[..4],
+            SNAPSHOT_VERSION.to_le_bytes()
+        );
+        assert_eq!(
+            load_snapshot(&path).unwrap().unwrap().entries,
+            canvas.entries
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unreleased_versions_are_refused() {
+        let path = std::env::temp_dir().join(format!(
+            "netsketch-unreleased-{}.snapshot",
+            std::process::id()
+        ));
+
+        for version in 2..SNAPSHOT_VERSION {
+            fs::write(&path, version.to_le_bytes()).unwrap();
+            match load_snapshot(&path) {
+                Err(e) => assert!(e.to_string().contains("unsupported version"), "{e}"),
+                Ok(_) => panic!("version {version} was loaded"),
+            }
+        }
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn audit_logs_read_colours_the_way_snapshots_are_migrated() {
+        let colour = [255, 0, 0, 255];
+        let elements = [
+            ElementV1::Line {
+                x1: 20,
+                y1: 20,
+                x2: 40,
+                y2: 40,
+                colour,
+            },
+            ElementV1::Circle {
+                x: 10,
+                y: 10,
+                radius: 5,
+                colour,
+            },
+            ElementV1::Rect {
+                x: 10,
+                y: 10,
+                width: 20,
+                height: 30,
+                colour,
+            },
+            ElementV1::Text {
+                x: 10,
+                y: 10,
+                text: "hello".to_string(),
+                colour,
+            },
+        ];
+
+        for element in elements {
+            // As an element with only a colour was written to the audit log
+            let json = serde_json::to_string(&element).unwrap();
+            let read: CanvasElement = serde_json::from_str(&json).unwrap();
+            assert_eq!(read, CanvasElement::from(element), "{json}");
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use ns_core::models::packets::TcpPacket;
+use tracing::{error, info, warn};
+
+use super::load_snapshot;
+use crate::models::ServerState;
+
+/// Locks the server state, recovering it if a handler panicked while holding
+/// the lock instead of failing every request from then on.
+///
+/// A recovered state is validated first. If it turns out to be inconsistent,
+/// the canvas is restored from the snapshot at `snapshot_path` when there is
+/// one, and repaired in place otherwise. Every client then reloads the canvas.
+pub fn lock_state<'a>(
+    server_state: &'a Mutex<ServerState>,
+    snapshot_path: Option<&Path>,
+) -> MutexGuard<'a, ServerState> {
+    match server_state.lock() {
+        Ok(server_state) => server_state,
+        Err(poisoned) => {
+            warn!("Server state lock was poisoned, recovering");
+
+            let mut guard = poisoned.into_inner();
+            recover(&mut guard, snapshot_path);
+            server_state.clear_poison();
+
+            guard
+        }
+    }
+}
+
+fn recover(server_state: &mut ServerState, snapshot_path: Option<&Path>) {
+    let reason = match server_state.validate() {
+        Ok(()) => {
+            info!("Server state is consistent, carrying on");
+            return;
+        }
+        Err(reason) => reason,
+    };
+
+    warn!("Server state is inconsistent: {}", reason);
+
+    let restored = snapshot_path.and_then(|path| match load_snapshot(path) {
+        Ok(Some(canvas)) => Some((path, canvas)),
+        Ok(None) => None,
+        Err(e) => {
+            error!("Failed to load {}: {e}", path.display());
+            None
+        }
+    });
+
+    if let Some((path, canvas)) = restored {
+        info!("Restoring the canvas from {}", path.display());
+        server_state.canvas = canvas;
+    }
+
+    if server_state.validate().is_err() {
+        info!("Repairing the server state in place");
+        server_state.repair();
+    }
+
+    // The recorded revisions no longer lead up to the recovered canvas
+    server_state.history.restart(&server_state.canvas);
+
+    // Whatever the clients have may have diverged from the recovered canvas,
+    // which is sent again the way it is on connecting
+    let reload_packets = [
+        TcpPacket::LoadCanvas(server_state.canvas.entries.clone()),
+        TcpPacket::LayerResponse(server_state.canvas.layers.clone()),
+    ];
+    for packet in reload_packets.iter() {
+        if let Err(e) = server_state.broadcast(packet) {
+            error!("Failed to resynchronise clients: {e}");
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        sync::Arc,
+        thread::spawn,
+    };
+
+    use ns_core::models::canvas::{Canvas, CanvasElement, LayerChange, Style, BASE_LAYER};
+
+    use super::*;
+    use crate::{
+        config::ServerConfig,
+        operations::{save_snapshot, test_server::TestServer},
+    };
+
+    fn circle() -> CanvasElement {
+        CanvasElement::Circle {
+            x: 10,
+            y: 10,
+            radius: 5,
+            style: Style::filled([0, 0, 0, 255]),
+        }
+    }
+
+    fn state_with_entries(count: usize) -> ServerState {
+        let mut canvas = Canvas::new();
+        for _ in 0..count {
+            canvas.add_action("alice".to_string(), &circle());
+        }
+        ServerState::from_canvas(canvas)
+    }
+
+    /// Runs `handler` on its own thread with the lock held, the way the
+    /// connection threads do, and expects it to panic.
+    fn panic_in_handler(
+        server_state: &Arc<Mutex<ServerState>>,
+        handler: impl FnOnce(&mut ServerState) + Send + 'static,
+    ) {
+        let server_state = server_state.clone();
+        let outcome = spawn(move || {
+            let mut guard = lock_state(&server_state, None);
+            handler(&mut guard);
+        })
+        .join();
+
+        assert!(outcome.is_err(), "the handler was expected to panic");
+    }
+
+    #[test]
+    fn consistent_state_survives_a_panicking_handler() {
+        let server_state = Arc::new(Mutex::new(state_with_entries(3)));
+
+        panic_in_handler(&server_state, |_| panic!("handler bug"));
+        assert!(server_state.is_poisoned());
+
+        let guard = lock_state(&server_state, None);
+        assert_eq!(guard.canvas.entries.len(), 3);
+        drop(guard);
+
+        assert!(!server_state.is_poisoned());
+        assert!(server_state.lock().is_ok());
+    }
+
+    #[test]
+    fn inconsistent_state_is_repaired_in_place() {
+        let server_state = Arc::new(Mutex::new(state_with_entries(3)));
+
+        panic_in_handler(&server_state, |server_state| {
+            // Half of an undo: the entry is pushed back but the handler dies
+            // before it gets a chance to notice it was already there
+            let entry = server_state.canvas.entries[0].clone();
+            server_state.canvas.entries.push(entry);
+            server_state.canvas.next_counter = 0;
+            panic!("handler bug");
+        });
+
+        let guard = lock_state(&server_state, None);
+        assert!(guard.validate().is_ok());
+        assert_eq!(guard.canvas.entries.len(), 3);
+        assert_eq!(guard.canvas.next_counter, 3);
+        drop(guard);
+
+        assert!(!server_state.is_poisoned());
+    }
+
+    #[test]
+    fn entries_on_a_missing_layer_are_moved_to_the_base_one() {
+        let server_state = Arc::new(Mutex::new(state_with_entries(2)));
+
+        panic_in_handler(&server_state, |server_state| {
+            server_state.canvas.layers.clear();
+            server_state.canvas.entries[1].layer = 7;
+            panic!("handler bug");
+        });
+
+        let guard = lock_state(&server_state, None);
+        assert!(guard.validate().is_ok());
+        assert!(guard.canvas.layer(BASE_LAYER).is_some());
+        assert!(guard
+            .canvas
+            .entries
+            .iter()
+            .all(|entry| entry.layer == BASE_LAYER));
+    }
+
+    #[test]
+    fn inconsistent_state_is_restored_from_the_snapshot() {
+        let snapshot_path = std::env::temp_dir().join(format!(
+            "netsketch-recovery-{}.snapshot",
+            std::process::id()
+        ));
+        save_snapshot(&state_with_entries(2).canvas, &snapshot_path).unwrap();
+
+        let server_state = Arc::new(Mutex::new(state_with_entries(2)));
+
+        let outcome = catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = lock_state(&server_state, Some(&snapshot_path));
+            let entry = guard.canvas.entries[1].clone();
+            guard.canvas.add_action("bob".to_string(), &circle());
+            guard.canvas.entries.push(entry);
+            panic!("handler bug");
+        }));
+        assert!(outcome.is_err());
+
+        let guard = lock_state(&server_state, Some(&snapshot_path));
+        let ids: Vec<u64> = guard
+            .canvas
+            .entries
+            .iter()
+            .map(|entry| entry.id.counter)
+            .collect();
+        assert_eq!(ids, vec![0, 1]);
+        assert_eq!(guard.canvas.next_counter, 2);
+
+        let _ = std::fs::remove_file(&snapshot_path);
+    }
+
+    #[test]
+    fn clients_get_the_repaired_layers_back() {
+        let server = TestServer::new(ServerConfig::default());
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        alice.send(&TcpPacket::DrawRequest(circle()));
+        bob.expect(|packet| matches!(packet, TcpPacket::DrawResponse(_)));
+        alice.send(&TcpPacket::LayerRequest(LayerChange::Create(
+            "sketch".to_string(),
+        )));
+        bob.expect(
+            |packet| matches!(packet, TcpPacket::LayerResponse(layers) if layers.len() == 2),
+        );
+
+        // The base layer is lost along the way, with the entry drawn on it
+        server.state().fault = Some(Box::new(|server_state| {
+            server_state
+                .canvas
+                .layers
+                .retain(|layer| layer.id != BASE_LAYER);
+            panic!("handler bug");
+        }));
+        alice.send(&TcpPacket::DrawRequest(circle()));
+        bob.expect(|packet| matches!(packet, TcpPacket::LoadCanvas(_)));
+
+        let TcpPacket::LayerResponse(layers) =
+            bob.expect(|packet| matches!(packet, TcpPacket::LayerResponse(_)))
+        else {
+            unreachable!();
+        };
+        assert_eq!(layers, server.state().canvas.layers);
+        assert!(layers.iter().any(|layer| layer.id == BASE_LAYER));
+    }
+
+    #[test]
+    fn requests_are_served_after_a_handler_panics() {
+        let server = TestServer::new(ServerConfig::default());
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        alice.send(&TcpPacket::DrawRequest(circle()));
+        alice.expect(|packet| matches!(packet, TcpPacket::DrawResponse(_)));
+
+        // Alice's next drawing goes through a real handler, which dies having
+        // added an entry a second time
+        server.state().fault = Some(Box::new(|server_state| {
+            let entry = server_state.canvas.entries[0].clone();
+            server_state.canvas.entries.push(entry);
+            panic!("handler bug");
+        }));
+        alice.send(&TcpPacket::DrawRequest(circle()));
+        bob.expect(|packet| matches!(packet, TcpPacket::LoadCanvas(_)));
+        let packet = bob.receive();
+        assert!(
+            matches!(&packet, Some(TcpPacket::LayerResponse(layers)) if layers.len() == 1),
+            "{packet:?}"
+        );
+
+        bob.send(&TcpPacket::DrawRequest(circle()));
+        bob.expect(
+            |packet| matches!(packet, TcpPacket::DrawResponse(entry) if entry.author == "bob"),
+        );
+
+        let guard = server.state();
+        assert!(guard.validate().is_ok());
+        assert_eq!(guard.canvas.entries.len(), 3);
+        assert_eq!(guard.count_entries_by("bob"), 1);
+        assert!(guard
+            .sessions
+            .iter()
+            .all(|session| session.username != "alice"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    io::Write,
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+    thread::{sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use ns_core::models::packets::TcpPacket;
+use tracing::{error, info, warn};
+
+use super::{lock_state, save_snapshot};
+use crate::{config::ServerConfig, models::ServerState};
+
+/// A client connection accepted by the listener, along with the thread handling it.
+pub struct Connection {
+    pub stream: TcpStream,
+    pub handle: JoinHandle<()>,
+}
+
+/// Tells every connected session that the server is going away, saves the
+/// canvas, closes all the client sockets and waits a bounded amount of time
+/// for the handler threads to finish.
+pub fn shutdown_server(
+    server_state: &Arc<Mutex<ServerState>>,
+    connections: Vec<Connection>,
+    reason: &str,
+    config: &ServerConfig,
+) {
+    info!("Shutting down: {}", reason);
+
+    {
+        let mut server_state =
+            lock_state(server_state, config.persistence.snapshot_path.as_deref());
+
+        let packet = TcpPacket::ServerShutdown(reason.to_string());
+        match packet.to_bytes() {
+            Ok(packet_bytes) => {
+                for session in server_state.sessions.iter_mut() {
+                    if let Err(e) = session
+                        .stream
+                        .write_all(&packet_bytes)
+                        .and_then(|_| session.stream.flush())
+                    {
+                        warn!(
+                            "Failed to notify {} of the shutdown: {}",
+                            session.username, e
+                        );
+                    }
+                }
+            }
+            Err(e) => error!("Failed to encode the shutdown packet: {e}"),
+        }
+
+        server_state.sessions.clear();
+
+        if let Some(path) = &config.persistence.snapshot_path {
+            match save_snapshot(&server_state.canvas, path) {
+                Ok(()) => info!("Saved the canvas to {}", path.display()),
+                Err(e) => error!("Failed to save {}: {e}", path.display()),
+            }
+        }
+    }
+
+    // Closing the sockets unblocks the handler threads waiting on a read
+    for connection in connections.iter() {
+        let _ = connection.stream.shutdown(Shutdown::Both);
+    }
+
+    let deadline = Instant::now() + config.shutdown_grace();
+    while connections.iter().any(|c| !c.handle.is_finished()) {
+        if Instant::now() >= deadline {
+            let remaining = connections
+                .iter()
+                .filter(|c| !c.handle.is_finished())
+                .count();
+            warn!("Gave up waiting for {} connection thread(s)", remaining);
+            return;
+        }
+        sleep(Duration::from_millis(50));
+    }
+
+    info!("All connections closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use ns_core::models::canvas::{CanvasElement, Style};
+
+    use super::*;
+    use crate::operations::{load_snapshot, test_server::TestServer};
+
+    #[test]
+    fn sessions_are_told_and_the_canvas_is_saved() {
+        let path =
+            std::env::temp_dir().join(format!("netsketch-shutdown-{}.bin", std::process::id()));
+        let mut config = ServerConfig::default();
+        config.persistence.snapshot_path = Some(path.clone());
+        let server = TestServer::new(config);
+        let mut alice = server.connect("alice");
+        let mut bob = server.connect("bob");
+
+        alice.send(&TcpPacket::DrawRequest(CanvasElement::Circle {
+            x: 50,
+            y: 50,
+            radius: 10,
+            style: Style::filled([0, 0, 0, 255]),
+        }));
+        bob.expect(|packet| matches!(packet, TcpPacket::DrawResponse(_)));
+        let canvas = server.state().canvas.clone();
+
+        server.shut_down("maintenance");
+
+        for client in [&mut alice, &mut bob] {
+            client.expect(|packet| {
+                matches!(packet, TcpPacket::ServerShutdown(reason) if reason == "maintenance")
+            });
+            assert!(client.receive().is_none());
+        }
+        let saved = load_snapshot(&path).unwrap().unwrap();
+        assert_eq!(saved.entries, canvas.entries);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::spawn,
+    time::Duration,
+};
+
+use ns_core::models::packets::TcpPacket;
+
+use super::{lock_state, serve_connection, shutdown_server, Connection};
+use crate::{
+    config::ServerConfig,
+    models::{Metrics, ServerState},
+};
+
+/// How long a client waits for a packet before deciding none is coming.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A server accepting real connections on a local port, each served on its
+/// own thread the way the server does.
+pub struct TestServer {
+    listener: TcpListener,
+    server_state: Arc<Mutex<ServerState>>,
+    config: Arc<ServerConfig>,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<AtomicBool>,
+    connections: Mutex<Vec<Connection>>,
+}
+
+impl TestServer {
+    pub fn new(config: ServerConfig) -> Self {
+        TestServer {
+            listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+            server_state: Arc::new(Mutex::new(ServerState::new())),
+            config: Arc::new(config),
+            metrics: Arc::new(Metrics::new()),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            connections: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Locks the state, recovering it the way a handler would.
+    pub fn state(&self) -> MutexGuard<'_, ServerState> {
+        lock_state(&self.server_state, None)
+    }
+
+    /// Connects a new client as `username`, waiting until it joined.
+    pub fn connect(&self, username: &str) -> TestClient {
+        let stream = TcpStream::connect(self.listener.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(REPLY_TIMEOUT)).unwrap();
+        let (connection, _) = self.listener.accept().unwrap();
+
+        let server_state = self.server_state.clone();
+        let config = self.config.clone();
+        let metrics = self.metrics.clone();
+        let shutdown = self.shutdown.clone();
+        let stream_to_close = connection.try_clone().unwrap();
+        let handle =
+            spawn(move || serve_connection(connection, server_state, &config, &metrics, &shutdown));
+        self.connections.lock().unwrap().push(Connection {
+            stream: stream_to_close,
+            handle,
+        });
+
+        let mut client = TestClient { stream };
+        client.send(&TcpPacket::Connect(username.to_string()));
+        client.expect(|packet| matches!(packet, TcpPacket::LayerResponse(_)));
+
+        client
+    }
+
+    /// Shuts the server down the way it is when interrupted.
+    pub fn shut_down(self, reason: &str) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        let connections = self.connections.into_inner().unwrap();
+        shutdown_server(&self.server_state, connections, reason, &self.config);
+    }
+}
+
+pub struct TestClient {
+    stream: TcpStream,
+}
+
+impl TestClient {
+    pub fn send(&mut self, packet: &TcpPacket) {
+        self.stream.write_all(&packet.to_bytes().unwrap()).unwrap();
+    }
+
+    /// The next packet from the server, or `None` once the connection is
+    /// closed or nothing came in time.
+    pub fn receive(&mut self) -> Option<TcpPacket> {
+        let mut length_header = [0u8; 4];
+        if self.stream.read_exact(&mut length_header).is_err() {
+            return None;
+        }
+
+        let mut buffer = vec![0u8; u32::from_le_bytes(length_header) as usize];
+        self.stream.read_exact(&mut buffer).unwrap();
+        Some(TcpPacket::try_from_bytes(&buffer).unwrap())
+    }
+
+    /// Skips packets until one matching `predicate` arrives, failing if the
+    /// connection closes or none does in time.
+    pub fn expect(&mut self, predicate: impl Fn(&TcpPacket) -> bool) -> TcpPacket {
+        loop {
+            match self.receive() {
+                Some(packet) if predicate(&packet) => return packet,
+                Some(_) => {}
+                None => panic!("the expected packet never arrived"),
+            }
+        }
+    }
+
+    /// Sends a request and returns the error the server replied with, failing
+    /// if it replied with anything else first.
+    pub fn expect_error(&mut self, packet: &TcpPacket) -> String {
+        self.send(packet);
+        match self.receive() {
+            Some(TcpPacket::Error(message)) => message,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+}
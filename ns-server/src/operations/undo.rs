@@ -0,0 +1,543 @@
+use ns_core::errors::Result;
+use ns_core::models::canvas::EntryId;
+
+use crate::{
+    config::{HistoryConfig, UndoConflict},
+    models::{ServerState, UserData},
+};
+
+/// What happened when a user asked to undo their last action.
+#[derive(Debug, PartialEq)]
+pub enum UndoOutcome {
+    /// The user had nothing left to undo
+    Empty,
+    /// The last action was undone, writing that many bytes to the sessions
+    Undone(usize),
+    /// The last action was undone over the changes someone else made to the
+    /// entries with these ids, writing that many bytes to the sessions
+    Forced(Vec<EntryId>, usize),
+    /// The last action was dropped because someone else changed the entries
+    /// with these ids since
+    Skipped(Vec<EntryId>),
+    /// The last action was kept because undoing it would change the entries
+    /// with these ids, which are on a locked layer
+    Locked(Vec<EntryId>),
+}
+
+/// Undoes the most recent action of the user, applying the configured
+/// [UndoConflict] policy if the entries it touched were changed since by
+/// someone else.
+///
+/// Nothing on a locked layer is ever changed, the action stays on the stack
+/// instead so that it can be undone once the layer is unlocked.
+pub fn undo_last_action(
+    server_state: &mut ServerState,
+    user_data: &mut UserData,
+    config: &HistoryConfig,
+) -> Result<UndoOutcome> {
+    let Some(action) = user_data.action_history.pop() else {
+        return Ok(UndoOutcome::Empty);
+    };
+
+    let undo = action.inverse();
+    let conflicts = undo.conflicts(&server_state.canvas);
+
+    let undo = match (conflicts.is_empty(), config.undo_conflict) {
+        (true, _) => Some(undo),
+        (false, UndoConflict::Skip) => return Ok(UndoOutcome::Skipped(conflicts)),
+        (false, UndoConflict::Force) => undo.rebase(&server_state.canvas),
+    };
+
+    if let Some(undo) = &undo {
+        let locked = undo.locked(&server_state.canvas);
+        if !locked.is_empty() {
+            user_data.action_history.push(action);
+            return Ok(UndoOutcome::Locked(locked));
+        }
+    }
+
+    let written = match undo {
+        Some(undo) => server_state.apply(&user_data.username, undo, config)?,
+        None => 0,
+    };
+    Ok(match conflicts.is_empty() {
+        true => UndoOutcome::Undone(written),
+        false => UndoOutcome::Forced(conflicts, written),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ns_core::models::{
+        canvas::{BatchChange, CanvasElement, CanvasEntry, GroupChange, Restack, Style, Transform},
+        history::Operation,
+    };
+
+    use super::*;
+
+    fn circle(radius: u16) -> CanvasElement {
+        CanvasElement::Circle {
+            x: 10,
+            y: 10,
+            radius,
+            style: Style::filled([0, 0, 0, 255]),
+        }
+    }
+
+    fn config(undo_conflict: UndoConflict) -> HistoryConfig {
+        HistoryConfig {
+            undo_conflict,
+            ..HistoryConfig::default()
+        }
+    }
+
+    // The helpers below mutate the canvas the same way the client handler
+    // does, pushing the operation onto the user's undo stack.
+
+    fn draw(server_state: &mut ServerState, user: &mut UserData, radius: u16) -> EntryId {
+        let entry = server_state
+            .canvas
+            .add_action(user.username.clone(), &circle(radius));
+        user.action_history.push(Operation::Draw(entry.clone()));
+        entry.id
+    }
+
+    fn update(server_state: &mut ServerState, user: &mut UserData, id: EntryId, radius: u16) {
+        let before = server_state.canvas.get_entry(id).cloned().unwrap();
+        let after = server_state
+            .canvas
+            .update_entry(id, &circle(radius))
+            .unwrap();
+        user.action_history
+            .push(Operation::Update { before, after });
+    }
+
+    fn delete(server_state: &mut ServerState, user: &mut UserData, id: EntryId) {
+        let entry = server_state.canvas.get_entry(id).cloned().unwrap();
+        server_state.canvas.delete_entry(id);
+        user.action_history.push(Operation::Delete(entry));
+    }
+
+    fn clear(server_state: &mut ServerState, user: &mut UserData) {
+        let cleared = std::mem::take(&mut server_state.canvas.entries);
+        user.action_history.push(Operation::Clear(cleared));
+    }
+
+    fn restack(server_state: &mut ServerState, user: &mut UserData, id: EntryId, restack: Restack) {
+        let operation = Operation::restack(&server_state.canvas, id, restack).unwrap();
+        operation.apply(&mut server_state.canvas);
+        user.action_history.push(operation);
+    }
+
+    fn group(server_state: &mut ServerState, user: &mut UserData, change: GroupChange) {
+        let operation = Operation::group(&server_state.canvas, &change).unwrap();
+        operation.apply(&mut server_state.canvas);
+        user.action_history.push(operation);
+    }
+
+    fn transform(
+        server_state: &mut ServerState,
+        user: &mut UserData,
+        ids: &[EntryId],
+        transform: Transform,
+    ) {
+        let operation = Operation::transform(&server_state.canvas, ids, transform).unwrap();
+        operation.apply(&mut server_state.canvas);
+        user.action_history.push(operation);
+    }
+
+    fn batch(
+        server_state: &mut ServerState,
+        user: &mut UserData,
+        ids: &[EntryId],
+        change: BatchChange,
+    ) {
+        let operation = Operation::batch(&server_state.canvas, ids, &change).unwrap();
+        operation.apply(&mut server_state.canvas);
+        user.action_history.push(operation);
+    }
+
+    fn stacking(server_state: &ServerState) -> Vec<EntryId> {
+        server_state
+            .canvas
+            .stacked()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect()
+    }
+
+    fn radius_of(server_state: &ServerState, id: EntryId) -> Option<u16> {
+        match server_state.canvas.get_entry(id) {
+            Some(CanvasEntry {
+                element: CanvasElement::Circle { radius, .. },
+                ..
+            }) => Some(*radius),
+            _ => None,
+        }
+    }
+
+    fn setup() -> (ServerState, UserData, UserData) {
+        (
+            ServerState::new(),
+            UserData::new("alice"),
+            UserData::new("bob"),
+        )
+    }
+
+    #[test]
+    fn undoing_an_untouched_action_reverts_it() {
+        let (mut state, mut alice, _) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let id = draw(&mut state, &mut alice, 5);
+        update(&mut state, &mut alice, id, 10);
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert_eq!(radius_of(&state, id), Some(5));
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert_eq!(radius_of(&state, id), None);
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert_eq!(outcome, UndoOutcome::Empty);
+        assert_eq!(state.history.revisions.len(), 2);
+    }
+
+    #[test]
+    fn nothing_on_a_locked_layer_is_undone() {
+        let (mut state, mut alice, _) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let kept = draw(&mut state, &mut alice, 5);
+        let deleted = draw(&mut state, &mut alice, 6);
+        delete(&mut state, &mut alice, deleted);
+        state.canvas.layers[0].locked = true;
+
+        // Undoing the delete would put the entry back on the locked layer
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert_eq!(outcome, UndoOutcome::Locked(vec![deleted]));
+        assert_eq!(radius_of(&state, deleted), None);
+        assert_eq!(alice.action_history.len(), 3);
+
+        state.canvas.layers[0].locked = false;
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        state.canvas.layers[0].locked = true;
+
+        // Undoing the draw would remove the entry from it
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert_eq!(outcome, UndoOutcome::Locked(vec![deleted]));
+        assert_eq!(radius_of(&state, deleted), Some(6));
+        assert_eq!(radius_of(&state, kept), Some(5));
+    }
+
+    #[test]
+    fn undoing_a_draw_someone_updated_is_skipped() {
+        let (mut state, mut alice, mut bob) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let id = draw(&mut state, &mut alice, 5);
+        update(&mut state, &mut bob, id, 10);
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert_eq!(outcome, UndoOutcome::Skipped(vec![id]));
+        assert_eq!(radius_of(&state, id), Some(10));
+        assert!(alice.action_history.is_empty());
+
+        // Bob's own undo is unaffected
+        let outcome = undo_last_action(&mut state, &mut bob, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert_eq!(radius_of(&state, id), Some(5));
+    }
+
+    #[test]
+    fn undoing_a_draw_someone_updated_can_be_forced() {
+        let (mut state, mut alice, mut bob) = setup();
+        let force = config(UndoConflict::Force);
+
+        let id = draw(&mut state, &mut alice, 5);
+        update(&mut state, &mut bob, id, 10);
+
+        let outcome = undo_last_action(&mut state, &mut alice, &force).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Forced(ref ids, _) if *ids == vec![id]));
+        assert_eq!(radius_of(&state, id), None);
+
+        // The entry Bob updated is gone, so forcing his undo brings it back
+        let outcome = undo_last_action(&mut state, &mut bob, &force).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Forced(_, _)));
+        assert_eq!(radius_of(&state, id), Some(5));
+    }
+
+    #[test]
+    fn undoing_an_update_of_a_deleted_entry_is_skipped() {
+        let (mut state, mut alice, mut bob) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let id = draw(&mut state, &mut alice, 5);
+        update(&mut state, &mut bob, id, 10);
+        delete(&mut state, &mut alice, id);
+
+        let outcome = undo_last_action(&mut state, &mut bob, &skip).unwrap();
+        assert_eq!(outcome, UndoOutcome::Skipped(vec![id]));
+        assert_eq!(radius_of(&state, id), None);
+
+        // Alice can still bring back the entry as she deleted it
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert_eq!(radius_of(&state, id), Some(10));
+    }
+
+    #[test]
+    fn interleaved_updates_are_undone_in_order() {
+        let (mut state, mut alice, mut bob) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let id = draw(&mut state, &mut alice, 5);
+        update(&mut state, &mut alice, id, 10);
+        update(&mut state, &mut bob, id, 15);
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert_eq!(outcome, UndoOutcome::Skipped(vec![id]));
+        assert_eq!(radius_of(&state, id), Some(15));
+
+        let outcome = undo_last_action(&mut state, &mut bob, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert_eq!(radius_of(&state, id), Some(10));
+
+        // Alice drew the entry with its original radius, which is gone either way
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert_eq!(outcome, UndoOutcome::Skipped(vec![id]));
+    }
+
+    #[test]
+    fn undoing_a_clear_keeps_entries_drawn_since() {
+        let (mut state, mut alice, mut bob) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let first = draw(&mut state, &mut alice, 5);
+        let second = draw(&mut state, &mut bob, 5);
+        clear(&mut state, &mut alice);
+        let third = draw(&mut state, &mut bob, 7);
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+
+        let mut ids: Vec<EntryId> = state.canvas.entries.iter().map(|entry| entry.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![first, second, third]);
+    }
+
+    #[test]
+    fn undoing_a_draw_someone_cleared_has_nothing_to_force() {
+        let (mut state, mut alice, mut bob) = setup();
+        let force = config(UndoConflict::Force);
+
+        let id = draw(&mut state, &mut bob, 5);
+        clear(&mut state, &mut alice);
+
+        // Forcing it through has nothing left to delete
+        let outcome = undo_last_action(&mut state, &mut bob, &force).unwrap();
+        assert_eq!(outcome, UndoOutcome::Forced(vec![id], 0));
+        assert!(state.history.revisions.is_empty());
+
+        // Restoring the clear is not blocked by Bob's undo
+        let outcome = undo_last_action(&mut state, &mut alice, &force).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert_eq!(radius_of(&state, id), Some(5));
+    }
+
+    #[test]
+    fn undoing_a_clear_over_a_restored_entry_is_skipped() {
+        let (mut state, mut alice, mut bob) = setup();
+
+        let id = draw(&mut state, &mut bob, 5);
+        update(&mut state, &mut bob, id, 10);
+        clear(&mut state, &mut alice);
+
+        // Bob forces his update back, recreating the cleared entry
+        let force = config(UndoConflict::Force);
+        undo_last_action(&mut state, &mut bob, &force).unwrap();
+        assert_eq!(radius_of(&state, id), Some(5));
+
+        let skip = config(UndoConflict::Skip);
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert_eq!(outcome, UndoOutcome::Skipped(vec![id]));
+        assert_eq!(radius_of(&state, id), Some(5));
+    }
+
+    #[test]
+    fn undoing_restacks_and_deletes_keeps_the_stacking() {
+        let (mut state, mut alice, mut bob) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let first = draw(&mut state, &mut alice, 5);
+        let second = draw(&mut state, &mut alice, 5);
+        let third = draw(&mut state, &mut alice, 5);
+
+        restack(&mut state, &mut bob, first, Restack::ToFront);
+        restack(&mut state, &mut bob, third, Restack::Lower);
+        assert_eq!(stacking(&state), vec![third, second, first]);
+
+        delete(&mut state, &mut bob, third);
+        assert_eq!(stacking(&state), vec![second, first]);
+
+        for _ in 0..3 {
+            let outcome = undo_last_action(&mut state, &mut bob, &skip).unwrap();
+            assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        }
+        assert_eq!(stacking(&state), vec![first, second, third]);
+    }
+
+    #[test]
+    fn group_changes_are_undone_as_one() {
+        let (mut state, mut alice, mut bob) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let first = draw(&mut state, &mut alice, 5);
+        let second = draw(&mut state, &mut bob, 5);
+        let alone = draw(&mut state, &mut bob, 5);
+
+        let name = "pair".to_string();
+        group(
+            &mut state,
+            &mut alice,
+            GroupChange::Create(name.clone(), vec![first, second]),
+        );
+        group(
+            &mut state,
+            &mut alice,
+            GroupChange::Move(name.clone(), 5, -20),
+        );
+        let moved = |state: &ServerState, id| {
+            !matches!(
+                state.canvas.get_entry(id).unwrap().element,
+                CanvasElement::Circle { x: 10, y: 10, .. }
+            )
+        };
+        assert!(moved(&state, first) && moved(&state, second));
+        assert!(!moved(&state, alone));
+
+        group(&mut state, &mut alice, GroupChange::Delete(name.clone()));
+        assert_eq!(state.canvas.entries.len(), 1);
+
+        // Deleting, moving and grouping are each undone at once
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert_eq!(state.canvas.group(&name).len(), 2);
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert!(!moved(&state, first) && !moved(&state, second));
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert!(state.canvas.group(&name).is_empty());
+    }
+
+    #[test]
+    fn transforms_are_undone_as_one() {
+        let (mut state, mut alice, _) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let first = draw(&mut state, &mut alice, 5);
+        let second = draw(&mut state, &mut alice, 8);
+
+        transform(
+            &mut state,
+            &mut alice,
+            &[first, second],
+            Transform::Scale(2.),
+        );
+        assert_eq!(radius_of(&state, first), Some(10));
+        assert_eq!(radius_of(&state, second), Some(16));
+
+        transform(
+            &mut state,
+            &mut alice,
+            &[first, second],
+            Transform::Rotate(-90.),
+        );
+        assert!(state
+            .canvas
+            .entries
+            .iter()
+            .all(|entry| entry.rotation == 270.));
+
+        // Circles turned all the way round are left as they are
+        assert!(Operation::transform(&state.canvas, &[first], Transform::Rotate(360.)).is_none());
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert!(state
+            .canvas
+            .entries
+            .iter()
+            .all(|entry| entry.rotation == 0.));
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert_eq!(radius_of(&state, first), Some(5));
+        assert_eq!(radius_of(&state, second), Some(8));
+    }
+
+    #[test]
+    fn batched_changes_are_undone_as_one() {
+        let (mut state, mut alice, mut bob) = setup();
+        let skip = config(UndoConflict::Skip);
+
+        let first = draw(&mut state, &mut bob, 5);
+        let second = draw(&mut state, &mut bob, 5);
+        let third = draw(&mut state, &mut bob, 5);
+        let fourth = draw(&mut state, &mut bob, 5);
+
+        // The selected entries keep their order among themselves
+        batch(
+            &mut state,
+            &mut alice,
+            &[first, third],
+            BatchChange::Restack(Restack::ToFront),
+        );
+        assert_eq!(stacking(&state), vec![second, fourth, first, third]);
+        batch(
+            &mut state,
+            &mut alice,
+            &[first, third],
+            BatchChange::Restack(Restack::Lower),
+        );
+        assert_eq!(stacking(&state), vec![second, first, third, fourth]);
+
+        batch(
+            &mut state,
+            &mut alice,
+            &[second, third],
+            BatchChange::Recolour([255, 0, 0, 255]),
+        );
+        batch(
+            &mut state,
+            &mut alice,
+            &[first, fourth],
+            BatchChange::Delete,
+        );
+        assert_eq!(stacking(&state), vec![second, third]);
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert_eq!(stacking(&state), vec![second, first, third, fourth]);
+
+        let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+        assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        assert!(state
+            .canvas
+            .entries
+            .iter()
+            .all(|entry| *entry.element.style() == Style::filled([0, 0, 0, 255])));
+
+        for _ in 0..2 {
+            let outcome = undo_last_action(&mut state, &mut alice, &skip).unwrap();
+            assert!(matches!(outcome, UndoOutcome::Undone(_)));
+        }
+        assert_eq!(stacking(&state), vec![first, second, third, fourth]);
+    }
+}